@@ -0,0 +1,360 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::crypto::PublicKey;
+use crate::mempool::{fee_rate, Mempool};
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, Blockchain, Transaction, TransactionOutput};
+use crate::util::MerkleRoot;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+
+// building the block a miner works on from the mempool.
+//
+// a transaction can only be mined together with its unconfirmed ancestors, so what gets scored is a package:
+// the transaction plus the ancestors not in the block yet, by their fees over their size. the best package
+// that still fits goes in, parents first, and the packages of the descendants of what went in are scored
+// again. a parent paying too little gets mined along with a child paying enough for both
+
+
+// a block ready to be mined, and what its transactions pay
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockTemplate {
+
+    pub block: Block,
+
+    // everything the coinbase collects beside the reward
+    pub fees: u64,
+
+    // the fee of every transaction after the coinbase, in block order
+    pub transaction_fees: Vec<u64>,
+}
+
+
+struct Package {
+
+    // parents before children
+    txids: Vec<Hash>,
+
+    fee: u64,
+
+    size: usize,
+}
+
+
+impl Package {
+
+    fn fee_rate(&self) -> u64 {
+
+        fee_rate(self.fee, self.size)
+    }
+}
+
+
+// the mempool ancestors of every mempool transaction, worked out once per block
+
+type Ancestors = HashMap<Hash, HashSet<Hash>>;
+
+
+fn all_ancestors(mempool: &Mempool) -> Ancestors {
+
+    mempool.iter()
+        .map(|entry| (entry.txid, mempool.ancestors(&entry.transaction)))
+        .collect()
+}
+
+
+// a mempool transaction along with its ancestors that are not in the block yet
+
+fn package_of(mempool: &Mempool, ancestors: &Ancestors, txid: &Hash, included: &HashSet<Hash>) -> Package {
+
+    let mut txids = ancestors[txid]
+        .iter()
+        .filter(|txid| !included.contains(txid))
+        .chain([txid])
+        .map(|txid| (ancestors[txid].len(), *txid))
+        .collect::<Vec<_>>();
+
+    // a transaction always has more ancestors than any of its parents
+
+    txids.sort();
+
+    let txids = txids.into_iter().map(|(_, txid)| txid).collect::<Vec<_>>();
+
+    let fee = txids.iter().fold(0u64, |sum, txid| sum.saturating_add(mempool.get(txid).unwrap().fee));
+
+    let size = txids.iter().map(|txid| mempool.get(txid).unwrap().size).sum();
+
+    Package { txids, fee, size }
+}
+
+
+// a block on top of the tip paying the reward and the fees to pubkey, as full as the size limit allows
+
+pub fn assemble_block(blockchain: &Blockchain, pubkey: PublicKey) -> BlockTemplate {
+
+    let mempool = blockchain.mempool();
+
+    let mut coinbase = Transaction::new(vec![], vec![TransactionOutput {
+
+        pubkey,
+
+        unique_id: Uuid::new_v4(),
+
+        value: 0,
+    }]);
+
+    // what is left for the transactions after the header, the transaction count and the coinbase
+
+    let mut space = blockchain.params().max_block_size.saturating_sub(BlockHeader::SIZE + 4 + coinbase.size());
+
+    let mut included = HashSet::new();
+
+    let mut transactions = vec![];
+
+    let mut transaction_fees = vec![];
+
+    // scores go stale as packages go in, a popped score that no longer matches is pushed again with the current one
+
+    let ancestors = all_ancestors(mempool);
+
+    let mut candidates = mempool.iter()
+        .map(|entry| (package_of(mempool, &ancestors, &entry.txid, &included).fee_rate(), entry.txid))
+        .collect::<BinaryHeap<_>>();
+
+    while let Some((score, txid)) = candidates.pop() {
+
+        if included.contains(&txid) {
+
+            continue;
+        }
+
+        let package = package_of(mempool, &ancestors, &txid, &included);
+
+        if package.fee_rate() != score {
+
+            candidates.push((package.fee_rate(), txid));
+
+            continue;
+        }
+
+        // it may still fit once some of its ancestors went in with another package
+
+        if package.size > space {
+
+            continue;
+        }
+
+        space -= package.size;
+
+        for txid in &package.txids {
+
+            let entry = mempool.get(txid).unwrap();
+
+            included.insert(*txid);
+
+            transactions.push(entry.transaction.clone());
+
+            transaction_fees.push(entry.fee);
+        }
+
+        // the descendants of what went in have smaller packages now
+
+        let descendants = package.txids.iter()
+            .flat_map(|txid| mempool.descendants(txid))
+            .filter(|txid| !included.contains(txid))
+            .collect::<HashSet<_>>();
+
+        for descendant in descendants {
+
+            candidates.push((package_of(mempool, &ancestors, &descendant, &included).fee_rate(), descendant));
+        }
+    }
+
+    let fees = transaction_fees.iter().fold(0u64, |sum, fee| sum.saturating_add(*fee));
+
+    coinbase.outputs[0].value = blockchain.calculate_block_reward() + fees;
+
+    transactions.insert(0, coinbase);
+
+    // the network-adjusted time, unless the blocks before are later than that
+
+    let earliest = blockchain.median_time_past(&blockchain.tip_hash())
+        .map_or(DateTime::<Utc>::MIN_UTC, |median| median + Duration::seconds(1));
+
+    let header = BlockHeader {
+
+        timestamp: blockchain.adjusted_time().max(earliest),
+
+        prev_block_hash: blockchain.tip_hash(),
+
+        nonce: 0,
+
+        target: blockchain.target(),
+
+        merkle_root: MerkleRoot::calculate(&transactions),
+    };
+
+    BlockTemplate {
+
+        block: Block::new(header, transactions),
+
+        fees,
+
+        transaction_fees,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::mempool::tests::{chain_of_three, coins, confirmed_coins, funded_chain, funded_chain_with, spend};
+    use crate::params::ChainParams;
+
+
+    fn block_txids(template: &BlockTemplate) -> Vec<Hash> {
+
+        template.block.transactions[1..].iter().map(Transaction::hash).collect()
+    }
+
+
+    #[test]
+    fn package_orders_parents_first() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let chain = chain_of_three(&key, coin, [1000, 2000, 3000]);
+
+        for transaction in &chain {
+
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        let [parent, child, grandchild] = chain.map(|transaction| transaction.hash());
+
+        let mempool = blockchain.mempool();
+
+        let ancestors = all_ancestors(mempool);
+
+        let package = package_of(mempool, &ancestors, &grandchild, &HashSet::new());
+
+        assert_eq!(package.txids, vec![parent, child, grandchild]);
+
+        assert_eq!(package.fee, 6000);
+
+        assert_eq!(package.size, [parent, child, grandchild].iter().map(|txid| mempool.get(txid).unwrap().size).sum::<usize>());
+
+        // what is in the block already is left out
+
+        let package = package_of(mempool, &ancestors, &grandchild, &HashSet::from([parent]));
+
+        assert_eq!(package.txids, vec![child, grandchild]);
+
+        assert_eq!(package.fee, 5000);
+    }
+
+
+    #[test]
+    fn child_pays_for_parent() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let [first, second] = confirmed_coins(&mut blockchain, &key, coin, 2).try_into().unwrap();
+
+        // the parent alone pays less than the unrelated transaction, the parent and child together more
+
+        let parent = spend(&key, &[first], 1, 300);
+
+        let child = spend(&key, &coins(&parent), 1, 6000);
+
+        let unrelated = spend(&key, &[second], 1, 2000);
+
+        for transaction in [&unrelated, &parent, &child] {
+
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        let template = assemble_block(&blockchain, key.public_key());
+
+        assert_eq!(block_txids(&template), vec![parent.hash(), child.hash(), unrelated.hash()]);
+
+        assert_eq!(template.transaction_fees, vec![300, 6000, 2000]);
+
+        assert_eq!(template.fees, 8300);
+    }
+
+
+    #[test]
+    fn stale_score_is_pushed_again() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let [first, second] = confirmed_coins(&mut blockchain, &key, coin, 2).try_into().unwrap();
+
+        // a parent with two children, one paying a lot and one paying little
+
+        let parent = spend(&key, &[first], 2, 2300);
+
+        let [left, right] = coins(&parent).try_into().unwrap();
+
+        let rich = spend(&key, &[left], 1, 4000);
+
+        let poor = spend(&key, &[right], 1, 400);
+
+        let unrelated = spend(&key, &[second], 1, 800);
+
+        for transaction in [&parent, &rich, &poor, &unrelated] {
+
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        // the poor child was first scored along with its parent, above the unrelated transaction.
+        // once the parent went in with the rich child that score is stale and the poor child alone scores below
+
+        let template = assemble_block(&blockchain, key.public_key());
+
+        assert_eq!(block_txids(&template), vec![parent.hash(), rich.hash(), unrelated.hash(), poor.hash()]);
+    }
+
+
+    #[test]
+    fn block_size_limit() {
+
+        let key = PrivateKey::new_key();
+
+        let params = ChainParams { max_block_size: 1000, ..ChainParams::regtest() };
+
+        let (mut blockchain, coin) = funded_chain_with(params, &key);
+
+        let [first, second] = confirmed_coins(&mut blockchain, &key, coin, 2).try_into().unwrap();
+
+        // the better paying transaction does not fit, the smaller one behind it still does
+
+        let big = spend(&key, &[first], 25, 50_000);
+
+        let small = spend(&key, &[second], 1, 1000);
+
+        assert!(big.size() > 1000);
+
+        for transaction in [&big, &small] {
+
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        let template = assemble_block(&blockchain, key.public_key());
+
+        assert_eq!(block_txids(&template), vec![small.hash()]);
+
+        assert!(template.block.size() <= 1000);
+    }
+}
@@ -1,4 +1,5 @@
 use btc_lib::crypto::PrivateKey;
+use btc_lib::params::{ChainParams, Network};
 use btc_lib::sha256::Hash;
 use btc_lib::types::{
 Block, BlockHeader, Transaction, TransactionOutput,
@@ -28,12 +29,23 @@ fn main() {
     } else {
 
 
-        eprintln!("usage: block_gen < block_file >");
+        eprintln!("usage: block_gen < block_file > [ network ]");
 
         exit(1);
         
     };
 
+    let network: Network = env::args().nth(2)
+        .map(|arg| arg.parse().unwrap_or_else(|e| {
+
+            eprintln!("{e}");
+
+            exit(1);
+        }))
+        .unwrap_or(Network::Testnet);
+
+    let params = ChainParams::for_network(network);
+
     let private_key = PrivateKey::new_key();
 
     let transactions = vec![Transaction::new(
@@ -42,7 +54,7 @@ fn main() {
         vec![TransactionOutput {
 
             unique_id: Uuid::new_v4(),
-            value: btc_lib::INITIAL_REWARD * 10u64.pow(8),
+            value: params.block_reward(0),
             pubkey: private_key.public_key(),
         }],
 
@@ -57,7 +69,7 @@ fn main() {
             0,
             Hash::zero(),
             merkle_root,
-            btc_lib::MIN_TARGET,
+            params.min_target,
         ),
         transactions,
     );
@@ -1,4 +1,5 @@
 use btc_lib::crypto::PrivateKey;
+use btc_lib::params::{ChainParams, Network};
 use btc_lib::types::{Transaction, TransactionOutput};
 use btc_lib::util::Saveable;
 use uuid::Uuid;
@@ -17,11 +18,22 @@ fn main() {
 
         } else {
 
-            eprintln!("Usage: tx_gen <tx_file>");
+            eprintln!("Usage: tx_gen <tx_file> [network]");
 
             exit(1);
     };
 
+    let network: Network = env::args().nth(2)
+        .map(|arg| arg.parse().unwrap_or_else(|e| {
+
+            eprintln!("{e}");
+
+            exit(1);
+        }))
+        .unwrap_or(Network::Testnet);
+
+    let params = ChainParams::for_network(network);
+
     let private_key = PrivateKey::new_key();
 
     let transaction = Transaction::new(
@@ -31,7 +43,7 @@ fn main() {
 
                 unique_id: Uuid::new_v4(),
 
-                value: btc_lib::INITIAL_REWARD * 10u64.pow(8),
+                value: params.block_reward(0),
 
                 pubkey: private_key.public_key(),
                 }],
@@ -0,0 +1,228 @@
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+use crate::error::{FrameError, RequestError};
+use crate::network::{Exchange, Message};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+
+// a connection both sides may send on at any time.
+//
+// a task reads every frame as it arrives: replies go to the request waiting for them, everything
+// else is handed out as Incoming. requests can be made from several tasks at once, each one waits
+// only for its own reply, and gives up after a timeout
+
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how many incoming messages may wait to be handled before reading from the peer pauses
+const INCOMING_BUFFER: usize = 64;
+
+
+// the requests waiting for a reply, None once the connection is closed
+type Pending = Arc<Mutex<Option<HashMap<u32, oneshot::Sender<Message>>>>>;
+
+
+// a message the peer sent on its own. request is the id to reply with, None if no reply is expected
+
+#[derive(Debug)]
+pub struct Incoming {
+
+    pub request: Option<u32>,
+
+    pub message: Message,
+}
+
+
+// the messages the peer sent on its own, ending with the error that closed the connection.
+// drop it if they are not handled, otherwise reading stops once the buffer is full
+
+pub type IncomingReceiver = mpsc::Receiver<Result<Incoming, FrameError>>;
+
+
+pub struct Connection {
+
+    magic: [u8; 4],
+
+    writer: AsyncMutex<OwnedWriteHalf>,
+
+    pending: Pending,
+
+    next_id: AtomicU32,
+
+    reader: JoinHandle<()>,
+}
+
+
+impl Connection {
+
+    // take over a stream after the handshake, must be called inside a tokio runtime
+
+    pub fn new(stream: TcpStream, magic: [u8; 4]) -> (Self, IncomingReceiver) {
+
+        let (read_half, write_half) = stream.into_split();
+
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+
+        let (sender, receiver) = mpsc::channel(INCOMING_BUFFER);
+
+        let reader = tokio::spawn(read_frames(read_half, magic, pending.clone(), sender));
+
+        let connection = Self {
+            magic,
+            writer: AsyncMutex::new(write_half),
+            pending,
+            next_id: AtomicU32::new(0),
+            reader,
+        };
+
+        (connection, receiver)
+    }
+
+
+    pub async fn request(&self, message: Message) -> Result<Message, RequestError> {
+
+        self.request_with_timeout(message, REQUEST_TIMEOUT).await
+    }
+
+
+    pub async fn request_with_timeout(&self, message: Message, timeout: Duration) -> Result<Message, RequestError> {
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let (sender, receiver) = oneshot::channel();
+
+        match self.pending.lock().unwrap().as_mut() {
+
+            Some(pending) => {
+
+                pending.insert(id, sender);
+            }
+
+            None => return Err(RequestError::Closed),
+        }
+
+        let result = time::timeout(timeout, async {
+
+            self.send_frame(&message, Exchange::Request(id)).await?;
+
+            // the sender is dropped without a reply when the connection closes
+
+            receiver.await.map_err(|_| RequestError::Closed)
+        })
+        .await
+        .unwrap_or(Err(RequestError::TimedOut));
+
+        // a reply that still comes in after this is dropped by the reader
+
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+
+            pending.remove(&id);
+        }
+
+        result
+    }
+
+
+    pub async fn notify(&self, message: &Message) -> Result<(), FrameError> {
+
+        self.send_frame(message, Exchange::Notification).await
+    }
+
+
+    pub async fn reply(&self, request: u32, message: &Message) -> Result<(), FrameError> {
+
+        self.send_frame(message, Exchange::Reply(request)).await
+    }
+
+
+    // stop reading, waiting requests fail right away and the incoming messages end
+
+    pub fn close(&self) {
+
+        self.reader.abort();
+
+        self.pending.lock().unwrap().take();
+    }
+
+
+    pub fn is_closed(&self) -> bool {
+
+        self.pending.lock().unwrap().is_none()
+    }
+
+
+    // the whole frame is written under the lock, so frames of different tasks never interleave
+
+    async fn send_frame(&self, message: &Message, exchange: Exchange) -> Result<(), FrameError> {
+
+        let frame = message.frame(self.magic, exchange)?;
+
+        self.writer.lock().await.write_all(&frame).await?;
+
+        Ok(())
+    }
+}
+
+
+impl Drop for Connection {
+
+    fn drop(&mut self) {
+
+        self.reader.abort();
+    }
+}
+
+
+async fn read_frames(
+    mut stream: OwnedReadHalf,
+    magic: [u8; 4],
+    pending: Pending,
+    incoming: mpsc::Sender<Result<Incoming, FrameError>>,
+) {
+
+    let error = loop {
+
+        let (exchange, message) = match Message::receive_framed_async(&mut stream, magic).await {
+
+            Ok(frame) => frame,
+
+            Err(e) => break e,
+        };
+
+        let request = match exchange {
+
+            Exchange::Reply(id) => {
+
+                // nobody is waiting any more if the request timed out
+
+                let waiting = pending.lock().unwrap().as_mut().and_then(|pending| pending.remove(&id));
+
+                if let Some(waiting) = waiting {
+
+                    let _ = waiting.send(message);
+                }
+
+                continue;
+            }
+
+            Exchange::Request(id) => Some(id),
+
+            Exchange::Notification => None,
+        };
+
+        // if nobody handles incoming messages, the connection is still read for the replies
+
+        let _ = incoming.send(Ok(Incoming { request, message })).await;
+    };
+
+    // fail every request still waiting, and refuse new ones
+
+    pending.lock().unwrap().take();
+
+    let _ = incoming.send(Err(error)).await;
+}
@@ -27,13 +27,13 @@ pub struct Signature(pub ECDSASignature<Secp256k1>);
 
 impl Signature {
 
-    // sign a crate::type::TransactionOutput from its Sha256 hash
+    // sign the sighash of a transaction input, see crate::types::Transaction::signature_hash
 
-    pub fn sign_output (output_hash: &Hash, private_key: &PrivateKey) -> Self {
+    pub fn sign_hash (sighash: &Hash, private_key: &PrivateKey) -> Self {
 
         let signing_key = &private_key.0;
 
-        let signature = signing_key.sign(&output_hash.as_bytes());
+        let signature = signing_key.sign(&sighash.as_bytes());
 
         Signature(signature)
     }
@@ -42,9 +42,9 @@ impl Signature {
 
     // verify a signature 
 
-    pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
+    pub fn verify(&self, sighash: &Hash, public_key: &PublicKey) -> bool {
 
-        public_key.0.verify(&output_hash.as_bytes(), &self.0).is_ok()
+        public_key.0.verify(&sighash.as_bytes(), &self.0).is_ok()
     }
 }
 
@@ -53,6 +53,25 @@ pub struct PublicKey(VerifyingKey<Secp256k1>);
 
 // save and load as pem
 
+impl PublicKey {
+
+    // compressed SEC1 encoding, 33 bytes
+
+    pub fn to_sec1_bytes(&self) -> Box<[u8]> {
+
+        self.0.to_sec1_bytes()
+    }
+
+
+    // compressed or uncompressed SEC1 encoding, None if it is not a point on the curve
+
+    pub fn from_sec1_bytes(bytes: &[u8]) -> Option<Self> {
+
+        VerifyingKey::from_sec1_bytes(bytes).ok().map(PublicKey)
+    }
+}
+
+
 impl Saveable for PublicKey {
 
     fn load<I: Read>(mut reader: I) -> IoResult<Self> {
@@ -134,7 +153,7 @@ impl PrivateKey {
 
     pub fn public_key(&self) -> PublicKey {
 
-        PublicKey(self.0.verifying_key().clone())
+        PublicKey(*self.0.verifying_key())
     }
 }
 
@@ -0,0 +1,484 @@
+use crate::crypto::{PublicKey, Signature};
+use crate::sha256::Hash;
+use crate::types::{BlockHeader, OutPoint, SigHashType, Transaction, TransactionInput, TransactionOutput};
+use crate::util::MerkleRoot;
+use crate::U256;
+
+
+// The consensus encoding: a fixed binary layout that is hashed and signed.
+// CBOR (ciborium) is only used for storage and the wire, so changes in the encoder
+// can never change a block or transaction id.
+//
+// all integers are little-endian, lists are prefixed with their length as u32.
+// transactions and headers start with the version of this encoding
+//
+//      Hash / U256 / MerkleRoot    32 bytes, little-endian
+//      timestamp                   i64 unix seconds, u32 nanoseconds
+//      OutPoint                    txid (32) | vout u32
+//      PublicKey                   33 bytes, compressed SEC1
+//      Signature                   64 bytes, r | s
+//      SigHashType                 1 byte
+//      TransactionOutput           value u64 | unique_id (16) | pubkey (33)
+//      TransactionInput            outpoint (36) | sequence u32 | signature (64) | sighash type (1)
+//      Transaction                 version u8 | inputs | outputs
+//      BlockHeader                 fixed 117 bytes: version u8 | timestamp (12) | prev_block_hash (32)
+//                                  | merkle_root (32) | target (32) | nonce u64
+//                                  the nonce comes last so mining only rewrites the final 8 bytes
+
+// 2 added the input sequence
+pub const CONSENSUS_ENCODING_VERSION: u8 = 2;
+
+
+pub trait ConsensusEncode {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>);
+
+    fn consensus_bytes(&self) -> Vec<u8> {
+
+        let mut out = vec![];
+
+        self.consensus_encode(&mut out);
+
+        out
+    }
+}
+
+
+impl<T: ConsensusEncode + ?Sized> ConsensusEncode for &T {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        (**self).consensus_encode(out)
+    }
+}
+
+
+// pairs: the first, then the second
+
+impl<A: ConsensusEncode, B: ConsensusEncode> ConsensusEncode for (A, B) {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        self.0.consensus_encode(out);
+        self.1.consensus_encode(out);
+    }
+}
+
+
+// lists: u32 length, then the items
+
+impl<T: ConsensusEncode> ConsensusEncode for [T] {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        (self.len() as u32).consensus_encode(out);
+
+        for item in self {
+
+            item.consensus_encode(out);
+        }
+    }
+}
+
+
+impl ConsensusEncode for u32 {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+
+impl ConsensusEncode for u64 {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+
+impl ConsensusEncode for Hash {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        out.extend_from_slice(&self.as_bytes());
+    }
+}
+
+
+impl ConsensusEncode for U256 {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        out.extend_from_slice(&self.to_little_endian());
+    }
+}
+
+
+impl ConsensusEncode for MerkleRoot {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        self.as_hash().consensus_encode(out);
+    }
+}
+
+
+impl ConsensusEncode for PublicKey {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        out.extend_from_slice(&self.to_sec1_bytes());
+    }
+}
+
+
+impl ConsensusEncode for Signature {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        out.extend_from_slice(&self.0.to_bytes());
+    }
+}
+
+
+impl ConsensusEncode for SigHashType {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        out.push(self.0);
+    }
+}
+
+
+impl ConsensusEncode for OutPoint {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        self.txid.consensus_encode(out);
+        self.vout.consensus_encode(out);
+    }
+}
+
+
+impl ConsensusEncode for TransactionOutput {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        self.value.consensus_encode(out);
+        out.extend_from_slice(self.unique_id.as_bytes());
+        self.pubkey.consensus_encode(out);
+    }
+}
+
+
+impl ConsensusEncode for TransactionInput {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        self.prev_output.consensus_encode(out);
+        self.sequence.consensus_encode(out);
+        self.signature.consensus_encode(out);
+        self.sighash_type.consensus_encode(out);
+    }
+}
+
+
+impl ConsensusEncode for Transaction {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        out.push(CONSENSUS_ENCODING_VERSION);
+        self.inputs.consensus_encode(out);
+        self.outputs.consensus_encode(out);
+    }
+}
+
+
+impl ConsensusEncode for BlockHeader {
+
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+
+        out.extend_from_slice(&self.to_bytes());
+    }
+}
+
+
+// the encoding is consensus: a change to any of these bytes forks the chain,
+// so the layout is pinned byte for byte here
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use crate::crypto::PublicKey;
+    use crate::sha256::Hash;
+    use uuid::Uuid;
+
+
+    fn hex(bytes: &[u8]) -> String {
+
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+
+    // the first and the last byte that differ between two encodings of the same length
+
+    fn changed(a: &[u8], b: &[u8]) -> (usize, usize) {
+
+        assert_eq!(a.len(), b.len());
+
+        let first = (0..a.len()).find(|&i| a[i] != b[i]).expect("nothing changed");
+
+        let last = (0..a.len()).rfind(|&i| a[i] != b[i]).unwrap();
+
+        (first, last)
+    }
+
+
+    // the generator point of secp256k1
+
+    fn pubkey() -> PublicKey {
+
+        let bytes = [
+            0x02, 0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87, 0x0b, 0x07,
+            0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16, 0xf8, 0x17, 0x98,
+        ];
+
+        PublicKey::from_sec1_bytes(&bytes).unwrap()
+    }
+
+
+    fn signature() -> Signature {
+
+        let mut bytes = [0x11; 64];
+
+        bytes[32..].fill(0x22);
+
+        Signature(ecdsa::Signature::from_slice(&bytes).unwrap())
+    }
+
+
+    fn output() -> TransactionOutput {
+
+        TransactionOutput {
+
+            value: 5_000_000_000,
+
+            unique_id: Uuid::from_bytes([0xaa; 16]),
+
+            pubkey: pubkey(),
+        }
+    }
+
+
+    fn input() -> TransactionInput {
+
+        TransactionInput {
+
+            prev_output: OutPoint::new(Hash::hash_bytes(b"prev"), 1),
+
+            sequence: 0xffff_fffd,
+
+            signature: signature(),
+
+            sighash_type: SigHashType::ALL,
+        }
+    }
+
+
+    fn transaction() -> Transaction {
+
+        Transaction::new(vec![input()], vec![output()])
+    }
+
+
+    fn header() -> BlockHeader {
+
+        BlockHeader::new(
+            DateTime::<Utc>::from_timestamp(1_733_011_200, 500).unwrap(),
+            42,
+            Hash::hash_bytes(b"parent"),
+            MerkleRoot::calculate(&[transaction()]),
+            U256::MAX >> 8,
+        )
+    }
+
+
+    // a value goes through CBOR for storage and the wire, that must not change its consensus bytes
+
+    fn round_trip<T: ConsensusEncode + serde::Serialize + serde::de::DeserializeOwned>(value: &T) -> Vec<u8> {
+
+        let mut stored = vec![];
+
+        ciborium::into_writer(value, &mut stored).unwrap();
+
+        ciborium::from_reader::<T, _>(stored.as_slice()).unwrap().consensus_bytes()
+    }
+
+
+    const OUTPUT: [&str; 3] = [
+        "00f2052a01000000",                                                     // value
+        "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",                                     // unique id
+        "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",   // pubkey
+    ];
+
+    const INPUT: [&str; 5] = [
+        "650d0e8359b62c66299b5e8aaf0814dfae0e63ac399bb2963e66db780c63863b",     // prev txid
+        "01000000",                                                             // prev vout
+        "fdffffff",                                                             // sequence
+        "1111111111111111111111111111111111111111111111111111111111111111\
+         2222222222222222222222222222222222222222222222222222222222222222",     // signature r | s
+        "01",                                                                   // sighash type
+    ];
+
+    const HEADER: [&str; 7] = [
+        "02",                                                                   // encoding version
+        "00a74b6700000000",                                                     // timestamp seconds
+        "f4010000",                                                             // timestamp nanoseconds
+        "ca457d25d97c847926d5fef7bce7d068ffa689e927f05449012160fff72f36db",     // prev block hash
+        "04efcf14c627b0609f0fd54d75d23a7d01fd24de1f1f6350016af01191d0885e",     // merkle root
+        "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff00",     // target
+        "2a00000000000000",                                                     // nonce
+    ];
+
+
+    #[test]
+    fn transaction_output_layout() {
+
+        assert_eq!(hex(&output().consensus_bytes()), OUTPUT.concat());
+
+        assert_eq!(Hash::hash(&output()).to_string(), "fa468673c1d89b7490e7efd9d829799c795bb36864847e370be2e7d643d07582");
+    }
+
+
+    #[test]
+    fn transaction_input_layout() {
+
+        assert_eq!(hex(&input().consensus_bytes()), INPUT.concat());
+
+        assert_eq!(Hash::hash(&input()).to_string(), "271863ca115377eb33eb9d021519fae6e4a994b0391793c637d63be16eeaca6e");
+    }
+
+
+    #[test]
+    fn transaction_layout() {
+
+        let expected = [
+            "02",                           // encoding version
+            "01000000", &INPUT.concat(),    // inputs
+            "01000000", &OUTPUT.concat(),   // outputs
+        ].concat();
+
+        assert_eq!(hex(&transaction().consensus_bytes()), expected);
+
+        assert_eq!(transaction().hash().to_string(), "5e88d09111f06a0150631f1fde24fd017d3ad2754dd50f9f60b027c614cfef04");
+
+        assert_eq!(round_trip(&transaction()), transaction().consensus_bytes());
+    }
+
+
+    #[test]
+    fn block_header_layout() {
+
+        let bytes = header().consensus_bytes();
+
+        assert_eq!(bytes.len(), BlockHeader::SIZE);
+
+        assert_eq!(hex(&bytes), HEADER.concat());
+
+        assert_eq!(header().hash().to_string(), "8267716427eb0f2dc5b2129267025662ebd1c0ccc6e3517786c9ed4e406df6cd");
+
+        assert_eq!(round_trip(&header()), bytes);
+    }
+
+
+    // where each field sits: changing one field changes exactly its own bytes
+
+    fn field_ranges(fields: &[&str]) -> Vec<(usize, usize)> {
+
+        let mut start = 0;
+
+        fields.iter()
+            .map(|field| {
+
+                let len = field.len() / 2;
+
+                start += len;
+
+                (start - len, start - 1)
+            })
+            .collect()
+    }
+
+
+    #[test]
+    fn field_order() {
+
+        let ranges = field_ranges(&OUTPUT);
+
+        let base = output().consensus_bytes();
+
+        let mut changed_output = output();
+        changed_output.value += 1;
+        assert_eq!(changed(&base, &changed_output.consensus_bytes()), (ranges[0].0, ranges[0].0));
+
+        let mut changed_output = output();
+        changed_output.unique_id = Uuid::from_bytes([0xbb; 16]);
+        assert_eq!(changed(&base, &changed_output.consensus_bytes()), ranges[1]);
+
+        let ranges = field_ranges(&INPUT);
+
+        let base = input().consensus_bytes();
+
+        let mut changed_input = input();
+        changed_input.prev_output.vout = 2;
+        assert_eq!(changed(&base, &changed_input.consensus_bytes()), (ranges[1].0, ranges[1].0));
+
+        let mut changed_input = input();
+        changed_input.sequence = 0xffff_ffff;
+        assert_eq!(changed(&base, &changed_input.consensus_bytes()), (ranges[2].0, ranges[2].0));
+
+        let mut changed_input = input();
+        changed_input.sighash_type = SigHashType::NONE;
+        assert_eq!(changed(&base, &changed_input.consensus_bytes()), ranges[4]);
+
+        let ranges = field_ranges(&HEADER);
+
+        let base = header().consensus_bytes();
+
+        let mut changed_header = header();
+        changed_header.timestamp += chrono::Duration::seconds(1);
+        assert_eq!(changed(&base, &changed_header.consensus_bytes()), (ranges[1].0, ranges[1].0));
+
+        let mut changed_header = header();
+        changed_header.timestamp += chrono::Duration::nanoseconds(1);
+        assert_eq!(changed(&base, &changed_header.consensus_bytes()), (ranges[2].0, ranges[2].0));
+
+        let mut changed_header = header();
+        changed_header.target = U256::MAX;
+        assert_eq!(changed(&base, &changed_header.consensus_bytes()), (ranges[5].1, ranges[5].1));
+
+        let mut changed_header = header();
+        changed_header.nonce = 43;
+        assert_eq!(changed(&base, &changed_header.consensus_bytes()), (ranges[6].0, ranges[6].0));
+
+        assert_eq!(ranges[6].0, BlockHeader::NONCE_OFFSET);
+    }
+
+
+    // the version leads every transaction and header, bumping it changes every id
+
+    #[test]
+    fn encoding_version() {
+
+        assert_eq!(CONSENSUS_ENCODING_VERSION, 2);
+
+        assert_eq!(transaction().consensus_bytes()[0], CONSENSUS_ENCODING_VERSION);
+
+        assert_eq!(header().consensus_bytes()[0], CONSENSUS_ENCODING_VERSION);
+    }
+}
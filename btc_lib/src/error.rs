@@ -1,6 +1,9 @@
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+// travels back to whoever submitted a transaction, so they learn why it was refused
+
+#[derive(Error, Debug, Serialize, Deserialize)]
 
 pub enum BtcError {
 
@@ -17,6 +20,10 @@ pub enum BtcError {
     #[error("Invalid transaction input")]
     InvalidTransactionInput,
 
+    // not necessarily the sender's fault, the output may have been spent since it sent the transaction
+    #[error("Transaction input is spent or unknown")]
+    MissingInput,
+
     #[error("Invalid transaction output")]
     InvalidTransactionOutput,
 
@@ -35,7 +42,129 @@ pub enum BtcError {
     #[error("Invalid private key")]
     InvalidPrivateKey,
 
+    #[error("Block already known")]
+    DuplicateBlock,
+
+    #[error("Parent block unknown")]
+    OrphanBlock,
+
+    #[error("Transaction already in the mempool")]
+    DuplicateTransaction,
+
+    // not invalid, just not worth the space while the mempool is this full
+    #[error("Fee rate below the mempool minimum")]
+    InsufficientFee,
+
+    #[error("Block timestamp not after the median time of the blocks before it")]
+    TimeTooOld,
+
+    // may only be our clock, the block can become valid later
+    #[error("Block timestamp too far ahead of the network-adjusted time")]
+    TimeTooNew,
+
+    #[error("Too many unconfirmed ancestors or descendants")]
+    MempoolChainTooLong,
+
+    #[error("Conflicting mempool transaction does not signal replaceability")]
+    NotReplaceable,
+
+    #[error("Replacement would evict too many mempool transactions")]
+    TooManyReplacements,
+
+    #[error("Replacement spends unconfirmed outputs the replaced transactions did not")]
+    ReplacementSpendsUnconfirmed,
+
+    #[error("Replacement fee rate not above the replaced transactions")]
+    ReplacementFeeRateTooLow,
+
+    #[error("Replacement fee does not cover the replaced transactions and its own relay")]
+    ReplacementFeeTooLow,
+
+    // only ever local, never sent
+    #[serde(skip)]
+    #[error("Storage error: {0}")]
+    Storage(#[from] std::io::Error),
+
+
+}
+
+pub type Result<T> = std::result::Result<T, BtcError>;
+
+
+// why two peers could not agree to talk to each other
+
+#[derive(Error, Debug)]
+
+pub enum HandshakeError {
+
+    #[error("Peer is on another chain")]
+    WrongNetwork,
+
+    #[error("Peer speaks unsupported protocol version {0}")]
+    UnsupportedVersion(u32),
 
+    #[error("Peer refused the connection: {0}")]
+    Rejected(String),
+
+    #[error("Unexpected message during handshake")]
+    UnexpectedMessage,
+
+    #[error("Connected to ourselves")]
+    SelfConnection,
+
+    #[error("{0}")]
+    Frame(#[from] FrameError),
+}
+
+
+// a frame that could not be sent or was refused on receipt, the peer is disconnected
+
+#[derive(Error, Debug)]
+
+pub enum FrameError {
+
+    // the magic the frame came with
+    #[error("Frame is for another network")]
+    WrongMagic([u8; 4]),
+
+    #[error("Unknown command {0}")]
+    UnknownCommand(String),
+
+    #[error("{command} payload of {len} bytes exceeds the limit of {max}")]
+    Oversized { command: String, len: usize, max: usize },
+
+    #[error("Frame checksum mismatch")]
+    BadChecksum,
+
+    #[error("Payload does not match command {0}")]
+    CommandMismatch(String),
+
+    #[error("Unknown exchange kind {0}")]
+    UnknownExchange(u8),
+
+    #[error("Failed to encode message")]
+    Encode,
+
+    #[error("Failed to decode message")]
+    Decode,
+
+    #[error("Connection error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
-pub type Result<T> = std::result::Result<T, BtcError>;
\ No newline at end of file
+
+// a request on a Connection that did not get its reply
+
+#[derive(Error, Debug)]
+
+pub enum RequestError {
+
+    #[error("Request timed out")]
+    TimedOut,
+
+    #[error("Connection closed")]
+    Closed,
+
+    #[error("{0}")]
+    Frame(#[from] FrameError),
+}
@@ -0,0 +1,1019 @@
+use chrono::{DateTime, Utc};
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use crate::types::{OutPoint, Transaction, TransactionOutput};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+
+// the transactions waiting to be mined.
+//
+// entries are ordered by fee rate, so a full mempool evicts the worst paying ones. every eviction raises
+// the minimum fee rate for new transactions to above what was evicted, and that minimum decays back to
+// the relay fee while nothing is evicted. blocks are built from it by the assembler.
+// the mempool only keeps the indexes, checking transactions against the chain is up to Blockchain.
+//
+// a transaction may spend outputs of other transactions in the mempool. it then depends on them:
+// it can only be mined after them, and goes when they go.
+//
+// a transaction spending an output some entry spends already conflicts with it. it can only replace that
+// entry by paying more, and only if the entry opted in to that, see check_replacement
+
+// fee rates are in satoshis per 1000 bytes of the encoded transaction
+
+pub const DEFAULT_MAX_USAGE: usize = 300 * 1024 * 1024;
+
+pub const DEFAULT_MIN_RELAY_FEE: u64 = 1000;
+
+// how long an unconfirmed chain may get: the most transactions a transaction and its ancestors in the
+// mempool may add up to, and their size together. the same goes for a transaction and its descendants
+
+pub const MAX_ANCESTORS: usize = 25;
+
+pub const MAX_ANCESTOR_SIZE: usize = 101_000;
+
+pub const MAX_DESCENDANTS: usize = 25;
+
+pub const MAX_DESCENDANT_SIZE: usize = 101_000;
+
+// the most entries a replacement may evict, descendants included
+
+pub const MAX_REPLACED: usize = 100;
+
+// rough memory an entry takes beside the transaction itself, for the indexes and the entry
+const ENTRY_OVERHEAD: usize = 256;
+
+// the raised minimum halves every 12 hours
+const ROLLING_FEE_HALFLIFE: f64 = 12.0 * 60.0 * 60.0;
+
+
+#[derive(Clone, Debug)]
+pub struct MempoolEntry {
+
+    pub transaction: Transaction,
+
+    pub txid: Hash,
+
+    // when it entered the mempool
+    pub time: DateTime<Utc>,
+
+    pub fee: u64,
+
+    pub size: usize,
+
+    pub fee_rate: u64,
+}
+
+
+impl MempoolEntry {
+
+    pub fn new(transaction: Transaction, fee: u64, time: DateTime<Utc>) -> Self {
+
+        let size = transaction.size();
+
+        MempoolEntry {
+
+            txid: transaction.hash(),
+
+            transaction,
+
+            time,
+
+            fee,
+
+            size,
+
+            fee_rate: fee_rate(fee, size),
+        }
+    }
+
+
+    fn usage(&self) -> usize {
+
+        self.size + ENTRY_OVERHEAD
+    }
+}
+
+
+pub fn fee_rate(fee: u64, size: usize) -> u64 {
+
+    (fee as u128 * 1000 / size.max(1) as u128).min(u64::MAX as u128) as u64
+}
+
+
+pub struct Mempool {
+
+    entries: HashMap<Hash, MempoolEntry>,
+
+    by_fee_rate: BTreeSet<(u64, Hash)>,
+
+    by_time: BTreeSet<(DateTime<Utc>, Hash)>,
+
+    // which entry spends an output, to find conflicts without looking at every entry
+    spends: HashMap<OutPoint, Hash>,
+
+    usage: usize,
+
+    max_usage: usize,
+
+    min_relay_fee: u64,
+
+    // the minimum raised by the last eviction, and when
+    rolling_min_fee: u64,
+
+    rolling_since: DateTime<Utc>,
+}
+
+
+impl Default for Mempool {
+
+    fn default() -> Self {
+
+        Mempool::new(DEFAULT_MAX_USAGE, DEFAULT_MIN_RELAY_FEE)
+    }
+}
+
+
+impl Mempool {
+
+    pub fn new(max_usage: usize, min_relay_fee: u64) -> Self {
+
+        Mempool {
+
+            entries: HashMap::new(),
+
+            by_fee_rate: BTreeSet::new(),
+
+            by_time: BTreeSet::new(),
+
+            spends: HashMap::new(),
+
+            usage: 0,
+
+            max_usage,
+
+            min_relay_fee,
+
+            rolling_min_fee: 0,
+
+            rolling_since: Utc::now(),
+        }
+    }
+
+
+    pub fn len(&self) -> usize {
+
+        self.entries.len()
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+
+        self.entries.is_empty()
+    }
+
+
+    // estimated memory taken by the entries
+    pub fn usage(&self) -> usize {
+
+        self.usage
+    }
+
+
+    pub fn max_usage(&self) -> usize {
+
+        self.max_usage
+    }
+
+
+    pub fn min_relay_fee(&self) -> u64 {
+
+        self.min_relay_fee
+    }
+
+
+    // the fee rate a new transaction needs right now: the relay fee, or more after evictions
+
+    pub fn min_fee_rate(&self) -> u64 {
+
+        let elapsed = (Utc::now() - self.rolling_since).num_seconds().max(0) as f64;
+
+        let rolling = self.rolling_min_fee as f64 * 0.5f64.powf(elapsed / ROLLING_FEE_HALFLIFE);
+
+        self.min_relay_fee.max(rolling as u64)
+    }
+
+
+    pub fn get(&self, txid: &Hash) -> Option<&MempoolEntry> {
+
+        self.entries.get(txid)
+    }
+
+
+    pub fn contains(&self, txid: &Hash) -> bool {
+
+        self.entries.contains_key(txid)
+    }
+
+
+    // the entry spending an output, if any
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<&MempoolEntry> {
+
+        self.spends.get(outpoint).and_then(|txid| self.entries.get(txid))
+    }
+
+
+    // an output of a transaction in the mempool, spent or not
+
+    pub fn output(&self, outpoint: &OutPoint) -> Option<&TransactionOutput> {
+
+        self.entries.get(&outpoint.txid)?.transaction.outputs.get(outpoint.vout as usize)
+    }
+
+
+    // the transactions in the mempool whose outputs a transaction spends
+
+    pub fn parents(&self, transaction: &Transaction) -> HashSet<Hash> {
+
+        transaction.inputs.iter()
+            .map(|input| input.prev_output.txid)
+            .filter(|txid| self.entries.contains_key(txid))
+            .collect()
+    }
+
+
+    // the transactions in the mempool spending outputs of an entry
+
+    pub fn children(&self, txid: &Hash) -> HashSet<Hash> {
+
+        let Some(entry) = self.entries.get(txid) else {
+
+            return HashSet::new();
+        };
+
+        (0..entry.transaction.outputs.len())
+            .filter_map(|vout| self.spends.get(&OutPoint::new(*txid, vout as u32)))
+            .copied()
+            .collect()
+    }
+
+
+    // everything in the mempool a transaction depends on, directly or not
+
+    pub fn ancestors(&self, transaction: &Transaction) -> HashSet<Hash> {
+
+        let mut ancestors = HashSet::new();
+
+        let mut queue = self.parents(transaction).into_iter().collect::<Vec<_>>();
+
+        while let Some(txid) = queue.pop() {
+
+            if ancestors.insert(txid) {
+
+                queue.extend(self.parents(&self.entries[&txid].transaction));
+            }
+        }
+
+        ancestors
+    }
+
+
+    // everything in the mempool that depends on an entry, directly or not
+
+    pub fn descendants(&self, txid: &Hash) -> HashSet<Hash> {
+
+        let mut descendants = HashSet::new();
+
+        let mut queue = self.children(txid).into_iter().collect::<Vec<_>>();
+
+        while let Some(txid) = queue.pop() {
+
+            if descendants.insert(txid) {
+
+                queue.extend(self.children(&txid));
+            }
+        }
+
+        descendants
+    }
+
+
+    // whether a new transaction keeps the unconfirmed chains it joins within the limits,
+    // not counting the entries it replaces
+
+    pub fn within_chain_limits(&self, transaction: &Transaction, size: usize, replaced: &HashSet<Hash>) -> bool {
+
+        let ancestors = self.ancestors(transaction);
+
+        let ancestor_size = ancestors.iter().map(|txid| self.entries[txid].size).sum::<usize>();
+
+        if ancestors.len() + 1 > MAX_ANCESTORS || ancestor_size + size > MAX_ANCESTOR_SIZE {
+
+            return false;
+        }
+
+        // and every ancestor gets one more descendant
+
+        ancestors.iter().all(|ancestor| {
+
+            let descendants = self.descendants(ancestor).difference(replaced).copied().collect::<HashSet<_>>();
+
+            let descendant_size = self.entries[ancestor].size
+                + descendants.iter().map(|txid| self.entries[txid].size).sum::<usize>();
+
+            descendants.len() + 2 <= MAX_DESCENDANTS && descendant_size + size <= MAX_DESCENDANT_SIZE
+        })
+    }
+
+
+    // whether an entry opted in to replacement, itself or through one of its unconfirmed ancestors
+
+    pub fn replaceable(&self, txid: &Hash) -> bool {
+
+        let Some(entry) = self.entries.get(txid) else {
+
+            return false;
+        };
+
+        entry.transaction.signals_replacement()
+            || self.ancestors(&entry.transaction).iter().any(|txid| self.entries[txid].transaction.signals_replacement())
+    }
+
+
+    // the replace-by-fee rules, much like bip125. a new entry may replace the entries it conflicts with,
+    // and everything depending on them, when
+    //      every conflicting entry is replaceable
+    //      it evicts at most MAX_REPLACED entries
+    //      it spends no unconfirmed outputs beside the ones of the conflicting entries' parents
+    //      its fee rate is above the fee rate of every conflicting entry
+    //      its fee pays for everything it evicts, plus the relay of itself at the relay fee rate
+
+    pub fn check_replacement(&self, entry: &MempoolEntry, conflicting: &HashSet<Hash>, replaced: &HashSet<Hash>) -> Result<()> {
+
+        if !conflicting.iter().all(|txid| self.replaceable(txid)) {
+
+            return Err(BtcError::NotReplaceable);
+        }
+
+        if replaced.len() > MAX_REPLACED {
+
+            return Err(BtcError::TooManyReplacements);
+        }
+
+        let known_parents = conflicting.iter()
+            .flat_map(|txid| self.parents(&self.entries[txid].transaction))
+            .collect::<HashSet<_>>();
+
+        if !self.parents(&entry.transaction).is_subset(&known_parents) {
+
+            return Err(BtcError::ReplacementSpendsUnconfirmed);
+        }
+
+        if conflicting.iter().any(|txid| entry.fee_rate <= self.entries[txid].fee_rate) {
+
+            return Err(BtcError::ReplacementFeeRateTooLow);
+        }
+
+        let replaced_fees = replaced.iter().fold(0u64, |sum, txid| sum.saturating_add(self.entries[txid].fee));
+
+        let relay_fee = (self.min_relay_fee as u128 * entry.size as u128 / 1000).min(u64::MAX as u128) as u64;
+
+        if entry.fee < replaced_fees.saturating_add(relay_fee) {
+
+            return Err(BtcError::ReplacementFeeTooLow);
+        }
+
+        Ok(())
+    }
+
+
+    // highest fee rate first
+
+    pub fn iter(&self) -> impl Iterator<Item = &MempoolEntry> {
+
+        self.by_fee_rate.iter()
+            .rev()
+            .map(|(_, txid)| &self.entries[txid])
+    }
+
+
+    // add an entry, then evict the lowest fee rates along with their descendants until the mempool
+    // fits its limit again. returns the evicted entries, which can include the new one
+
+    pub fn insert(&mut self, entry: MempoolEntry) -> Vec<MempoolEntry> {
+
+        let txid = entry.txid;
+
+        if self.entries.contains_key(&txid) {
+
+            return vec![];
+        }
+
+        for input in &entry.transaction.inputs {
+
+            self.spends.insert(input.prev_output, txid);
+        }
+
+        self.by_fee_rate.insert((entry.fee_rate, txid));
+
+        self.by_time.insert((entry.time, txid));
+
+        self.usage += entry.usage();
+
+        self.entries.insert(txid, entry);
+
+        self.trim()
+    }
+
+
+    pub fn remove(&mut self, txid: &Hash) -> Option<MempoolEntry> {
+
+        let entry = self.entries.remove(txid)?;
+
+        for input in &entry.transaction.inputs {
+
+            if self.spends.get(&input.prev_output) == Some(txid) {
+
+                self.spends.remove(&input.prev_output);
+            }
+        }
+
+        self.by_fee_rate.remove(&(entry.fee_rate, *txid));
+
+        self.by_time.remove(&(entry.time, *txid));
+
+        self.usage -= entry.usage();
+
+        Some(entry)
+    }
+
+
+    // remove an entry and everything depending on it, which cannot be mined without it
+
+    pub fn remove_with_descendants(&mut self, txid: &Hash) -> Vec<MempoolEntry> {
+
+        let mut removed = vec![];
+
+        for txid in self.descendants(txid).into_iter().chain([*txid]) {
+
+            removed.extend(self.remove(&txid));
+        }
+
+        removed
+    }
+
+
+    // everything that entered before the cutoff
+
+    pub fn expired(&self, cutoff: DateTime<Utc>) -> Vec<Hash> {
+
+        self.by_time.iter()
+            .take_while(|(time, _)| *time < cutoff)
+            .map(|(_, txid)| *txid)
+            .collect()
+    }
+
+
+    // empty the mempool, keeping the limits. parents come before their children,
+    // so the entries can be added again in this order
+
+    pub fn take(&mut self) -> Vec<MempoolEntry> {
+
+        // a transaction always has more ancestors than any of its parents
+
+        let mut order = self.entries.values()
+            .map(|entry| (self.ancestors(&entry.transaction).len(), entry.txid))
+            .collect::<Vec<_>>();
+
+        order.sort();
+
+        order.into_iter()
+            .filter_map(|(_, txid)| self.remove(&txid))
+            .collect()
+    }
+
+
+    // change the limits, returns what no longer fits
+
+    pub fn set_limits(&mut self, max_usage: usize, min_relay_fee: u64) -> Vec<MempoolEntry> {
+
+        self.max_usage = max_usage;
+
+        self.min_relay_fee = min_relay_fee;
+
+        self.trim()
+    }
+
+
+    fn trim(&mut self) -> Vec<MempoolEntry> {
+
+        let mut evicted = vec![];
+
+        while self.usage > self.max_usage {
+
+            let Some(&(fee_rate, txid)) = self.by_fee_rate.first() else {
+
+                break;
+            };
+
+            // whatever comes next has to pay more than what was just thrown out
+
+            self.rolling_min_fee = self.min_fee_rate().max(fee_rate.saturating_add(self.min_relay_fee));
+
+            self.rolling_since = Utc::now();
+
+            evicted.extend(self.remove_with_descendants(&txid));
+        }
+
+        evicted
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+    use super::*;
+    use crate::assembler::assemble_block;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::params::ChainParams;
+    use crate::types::{Block, Blockchain, SigHashType, TransactionInput, SEQUENCE_FINAL, SEQUENCE_REPLACEABLE};
+    use std::slice;
+    use uuid::Uuid;
+
+
+    // an output and where it is
+    pub(crate) type Coin = (OutPoint, TransactionOutput);
+
+
+    pub(crate) fn mine(mut block: Block) -> Block {
+
+        while !block.header.mine(10_000) {}
+
+        block
+    }
+
+
+    // a regtest chain with one block on top of genesis, paying its coinbase to key
+
+    pub(crate) fn funded_chain(key: &PrivateKey) -> (Blockchain, Coin) {
+
+        funded_chain_with(ChainParams::regtest(), key)
+    }
+
+
+    pub(crate) fn funded_chain_with(params: ChainParams, key: &PrivateKey) -> (Blockchain, Coin) {
+
+        let mut blockchain = Blockchain::new(params);
+
+        let block = mine(assemble_block(&blockchain, key.public_key()).block);
+
+        let coinbase = block.transactions[0].clone();
+
+        blockchain.add_block(block).unwrap();
+
+        let (outpoint, output) = coinbase.outpoints().next().unwrap();
+
+        (blockchain, (outpoint, output.clone()))
+    }
+
+
+    // a transaction spending coins back to key: what they hold less the fee, split into equal outputs
+
+    pub(crate) fn spend(key: &PrivateKey, coins: &[Coin], outputs: u64, fee: u64) -> Transaction {
+
+        spend_with(key, coins, outputs, fee, SEQUENCE_FINAL)
+    }
+
+
+    // the same with every input at sequence, SEQUENCE_REPLACEABLE signals replace-by-fee
+
+    pub(crate) fn spend_with(key: &PrivateKey, coins: &[Coin], outputs: u64, fee: u64, sequence: u32) -> Transaction {
+
+        let value = coins.iter().map(|(_, output)| output.value).sum::<u64>() - fee;
+
+        let outputs = (0..outputs)
+            .map(|_| TransactionOutput { value: value / outputs, unique_id: Uuid::new_v4(), pubkey: key.public_key() })
+            .collect::<Vec<_>>();
+
+        let signed = coins.iter().map(|(outpoint, _)| (*outpoint, sequence)).collect::<Vec<_>>();
+
+        let inputs = coins.iter().enumerate().map(|(index, (outpoint, spent))| {
+
+            let sighash = Transaction::compute_signature_hash(&signed, &outputs, index, spent, SigHashType::ALL).unwrap();
+
+            TransactionInput {
+
+                prev_output: *outpoint,
+
+                sequence,
+
+                signature: Signature::sign_hash(&sighash, key),
+
+                sighash_type: SigHashType::ALL,
+            }
+        });
+
+        Transaction::new(inputs.collect(), outputs)
+    }
+
+
+    pub(crate) fn coins(transaction: &Transaction) -> Vec<Coin> {
+
+        transaction.outpoints().map(|(outpoint, output)| (outpoint, output.clone())).collect()
+    }
+
+
+    // splits coin into outputs coins and mines them, so they have no mempool ancestors
+
+    pub(crate) fn confirmed_coins(blockchain: &mut Blockchain, key: &PrivateKey, coin: Coin, outputs: u64) -> Vec<Coin> {
+
+        // a bit over the relay fee, however many outputs
+
+        let split = spend(key, &[coin], outputs, 1000 * outputs);
+
+        blockchain.add_to_mempool(split.clone()).unwrap();
+
+        let block = mine(assemble_block(blockchain, key.public_key()).block);
+
+        blockchain.add_block(block).unwrap();
+
+        coins(&split)
+    }
+
+
+    // parent -> child -> grandchild, each spending the one output of the one before
+
+    pub(crate) fn chain_of_three(key: &PrivateKey, coin: Coin, fees: [u64; 3]) -> [Transaction; 3] {
+
+        let parent = spend(key, &[coin], 1, fees[0]);
+
+        let child = spend(key, &coins(&parent), 1, fees[1]);
+
+        let grandchild = spend(key, &coins(&child), 1, fees[2]);
+
+        [parent, child, grandchild]
+    }
+
+
+    fn txids(entries: &[MempoolEntry]) -> Vec<Hash> {
+
+        entries.iter().map(|entry| entry.txid).collect()
+    }
+
+
+    #[test]
+    fn chain_relations() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let [parent, child, grandchild] = chain_of_three(&key, coin, [1000, 1000, 1000]);
+
+        for transaction in [&parent, &child, &grandchild] {
+
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        let mempool = blockchain.mempool();
+
+        assert_eq!(mempool.parents(&grandchild), HashSet::from([child.hash()]));
+
+        assert_eq!(mempool.ancestors(&grandchild), HashSet::from([parent.hash(), child.hash()]));
+
+        assert_eq!(mempool.children(&parent.hash()), HashSet::from([child.hash()]));
+
+        assert_eq!(mempool.descendants(&parent.hash()), HashSet::from([child.hash(), grandchild.hash()]));
+    }
+
+
+    #[test]
+    fn ancestor_limit() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, mut coin) = funded_chain(&key);
+
+        for _ in 0..MAX_ANCESTORS {
+
+            let transaction = spend(&key, &[coin], 1, 1000);
+
+            coin = coins(&transaction).remove(0);
+
+            blockchain.add_to_mempool(transaction).unwrap();
+        }
+
+        let too_long = spend(&key, &[coin], 1, 1000);
+
+        assert!(matches!(blockchain.add_to_mempool(too_long), Err(BtcError::MempoolChainTooLong)));
+
+        assert_eq!(blockchain.mempool().len(), MAX_ANCESTORS);
+    }
+
+
+    #[test]
+    fn descendant_limit() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let parent = spend(&key, &[coin], MAX_DESCENDANTS as u64, 10_000);
+
+        let mut outputs = coins(&parent);
+
+        blockchain.add_to_mempool(parent).unwrap();
+
+        // the parent and its children make a family of MAX_DESCENDANTS
+
+        let last = outputs.pop().unwrap();
+
+        for output in outputs {
+
+            blockchain.add_to_mempool(spend(&key, &[output], 1, 1000)).unwrap();
+        }
+
+        let one_too_many = spend(&key, &[last], 1, 1000);
+
+        assert!(matches!(blockchain.add_to_mempool(one_too_many), Err(BtcError::MempoolChainTooLong)));
+
+        assert_eq!(blockchain.mempool().len(), MAX_DESCENDANTS);
+    }
+
+
+    #[test]
+    fn eviction_takes_descendants() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        // a split into two, a cheap chain on one half and a well paying transaction on the other
+
+        let split = spend(&key, &[coin], 2, 5000);
+
+        let [cheap, high] = coins(&split).try_into().unwrap();
+
+        let freed = cheap.0;
+
+        let parent = spend(&key, &[cheap], 1, 1000);
+
+        let child = spend(&key, &coins(&parent), 1, 3000);
+
+        let other = spend(&key, &[high], 1, 4000);
+
+        for transaction in [&split, &parent, &child, &other] {
+
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        // the lowest fee rate goes first, and the child cannot stay without it
+
+        let usage = blockchain.mempool().usage();
+
+        blockchain.configure_mempool(usage - 1, DEFAULT_MIN_RELAY_FEE);
+
+        let mempool = blockchain.mempool();
+
+        assert!(!mempool.contains(&parent.hash()) && !mempool.contains(&child.hash()));
+
+        assert!(mempool.contains(&split.hash()) && mempool.contains(&other.hash()));
+
+        // the output the evicted parent spent is free again
+
+        assert!(mempool.spender(&freed).is_none());
+    }
+
+
+    #[test]
+    fn remove_with_descendants() {
+
+        let key = PrivateKey::new_key();
+
+        let (_, coin) = funded_chain(&key);
+
+        let chain = chain_of_three(&key, coin, [1000, 1000, 1000]);
+
+        let mut mempool = Mempool::default();
+
+        for transaction in &chain {
+
+            mempool.insert(MempoolEntry::new(transaction.clone(), 1000, Utc::now()));
+        }
+
+        let removed = mempool.remove_with_descendants(&chain[1].hash());
+
+        assert_eq!(
+            removed.iter().map(|entry| entry.txid).collect::<HashSet<_>>(),
+            HashSet::from([chain[1].hash(), chain[2].hash()])
+        );
+
+        assert!(mempool.contains(&chain[0].hash()));
+    }
+
+
+    #[test]
+    fn take_orders_parents_first() {
+
+        let key = PrivateKey::new_key();
+
+        let (_, coin) = funded_chain(&key);
+
+        let chain = chain_of_three(&key, coin, [1000, 1000, 1000]);
+
+        // inserted the wrong way round, the mempool does not check anything
+
+        let mut mempool = Mempool::default();
+
+        for transaction in chain.iter().rev() {
+
+            mempool.insert(MempoolEntry::new(transaction.clone(), 1000, Utc::now()));
+        }
+
+        let taken = mempool.take();
+
+        assert_eq!(txids(&taken), chain.iter().map(|transaction| transaction.hash()).collect::<Vec<_>>());
+
+        assert!(mempool.is_empty());
+    }
+
+
+    #[test]
+    fn confirmed_chain_leaves_the_mempool() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        // the grandchild pays the most, it still comes after its ancestors
+
+        let chain = chain_of_three(&key, coin, [1000, 2000, 30_000]);
+
+        for transaction in &chain {
+
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        let block = mine(assemble_block(&blockchain, key.public_key()).block);
+
+        assert_eq!(
+            block.transactions[1..].iter().map(|transaction| transaction.hash()).collect::<Vec<_>>(),
+            chain.iter().map(|transaction| transaction.hash()).collect::<Vec<_>>()
+        );
+
+        blockchain.add_block(block).unwrap();
+
+        assert!(blockchain.mempool().is_empty());
+    }
+
+
+    #[test]
+    fn signalled_replacement_is_accepted() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let original = spend_with(&key, slice::from_ref(&coin), 1, 1000, SEQUENCE_REPLACEABLE);
+
+        let child = spend(&key, &coins(&original), 1, 1000);
+
+        blockchain.add_to_mempool(original.clone()).unwrap();
+
+        blockchain.add_to_mempool(child.clone()).unwrap();
+
+        // a non signalling child is replaceable through its parent, it goes with it
+
+        let replacement = spend(&key, &[coin], 1, 5000);
+
+        blockchain.add_to_mempool(replacement.clone()).unwrap();
+
+        let mempool = blockchain.mempool();
+
+        assert!(mempool.contains(&replacement.hash()));
+
+        assert!(!mempool.contains(&original.hash()));
+
+        assert!(!mempool.contains(&child.hash()));
+    }
+
+
+    #[test]
+    fn original_not_signalling() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let original = spend(&key, slice::from_ref(&coin), 1, 1000);
+
+        blockchain.add_to_mempool(original.clone()).unwrap();
+
+        let replacement = spend(&key, &[coin], 1, 50_000);
+
+        assert!(matches!(blockchain.add_to_mempool(replacement), Err(BtcError::NotReplaceable)));
+
+        assert!(blockchain.mempool().contains(&original.hash()));
+    }
+
+
+    #[test]
+    fn replacement_fee_too_low() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        // a smaller replacement beats the fee rate with a few satoshis more, not the original fee and its own relay
+
+        let original = spend_with(&key, slice::from_ref(&coin), 3, 2000, SEQUENCE_REPLACEABLE);
+
+        blockchain.add_to_mempool(original.clone()).unwrap();
+
+        let replacement = spend(&key, &[coin], 1, 2001);
+
+        let entry = MempoolEntry::new(replacement.clone(), 2001, Utc::now());
+
+        assert!(entry.fee_rate > blockchain.mempool().entries[&original.hash()].fee_rate);
+
+        assert!(matches!(blockchain.add_to_mempool(replacement), Err(BtcError::ReplacementFeeTooLow)));
+
+        assert!(blockchain.mempool().contains(&original.hash()));
+    }
+
+
+    #[test]
+    fn replacement_fee_rate_too_low() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        // a much larger replacement pays more in total but less per byte
+
+        let original = spend_with(&key, slice::from_ref(&coin), 1, 2000, SEQUENCE_REPLACEABLE);
+
+        blockchain.add_to_mempool(original.clone()).unwrap();
+
+        let replacement = spend(&key, &[coin], 25, 5000);
+
+        assert!(matches!(blockchain.add_to_mempool(replacement), Err(BtcError::ReplacementFeeRateTooLow)));
+
+        assert!(blockchain.mempool().contains(&original.hash()));
+    }
+
+
+    #[test]
+    fn too_many_replacements() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let confirmed = confirmed_coins(&mut blockchain, &key, coin, MAX_REPLACED as u64 + 1);
+
+        for coin in &confirmed {
+
+            blockchain.add_to_mempool(spend_with(&key, slice::from_ref(coin), 1, 1000, SEQUENCE_REPLACEABLE)).unwrap();
+        }
+
+        // one transaction conflicting with all of them, however well it pays
+
+        let replacement = spend(&key, &confirmed, 1, 1_000_000);
+
+        assert!(matches!(blockchain.add_to_mempool(replacement), Err(BtcError::TooManyReplacements)));
+
+        assert_eq!(blockchain.mempool().len(), MAX_REPLACED + 1);
+    }
+
+
+    #[test]
+    fn replacement_spending_new_unconfirmed_outputs() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let [first, second] = confirmed_coins(&mut blockchain, &key, coin, 2).try_into().unwrap();
+
+        let original = spend_with(&key, slice::from_ref(&first), 1, 1000, SEQUENCE_REPLACEABLE);
+
+        let unrelated = spend(&key, &[second], 1, 1000);
+
+        blockchain.add_to_mempool(original.clone()).unwrap();
+
+        blockchain.add_to_mempool(unrelated.clone()).unwrap();
+
+        // the original spent nothing from the mempool, its replacement may not either
+
+        let mut spent = vec![first];
+
+        spent.extend(coins(&unrelated));
+
+        let replacement = spend(&key, &spent, 1, 50_000);
+
+        assert!(matches!(blockchain.add_to_mempool(replacement), Err(BtcError::ReplacementSpendsUnconfirmed)));
+
+        assert!(blockchain.mempool().contains(&original.hash()));
+    }
+}
@@ -1,25 +1,292 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use crate::assembler::BlockTemplate;
 use crate::crypto::PublicKey;
-use crate::types::{Block, Transaction, TransactionOutput};
-use std::io::{Error as IoError, Read, Write};
+use crate::error::{BtcError, FrameError, HandshakeError};
+use crate::params::ChainParams;
+use crate::sha256::Hash;
+use crate::store::HistoryEntry;
+use crate::types::{Block, BlockHeader, OutPoint, Transaction, TransactionOutput};
+use crate::util::MerkleProof;
+use std::io::{Read, Write};
+use std::ops::BitOr;
 
 use tokio::io::{
 
     AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt
 };
 
+// version of the wire protocol spoken by this build, and the oldest one it still talks to
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+
+// what one side of a connection offers to the other, announced in the handshake
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Services(u64);
+
+
+impl Services {
+
+    pub const NONE: Services = Services(0);
+
+    // answers FetchBlock, FetchBlockByHash and AskDifference
+    pub const BLOCKS: Services = Services(1);
+
+    // answers FetchHeaders
+    pub const HEADERS: Services = Services(1 << 1);
+
+    // answers FetchProof
+    pub const PROOFS: Services = Services(1 << 2);
+
+    // wants new blocks and transactions relayed to it
+    pub const RELAY: Services = Services(1 << 3);
+
+    // answers FetchTransaction and FetchHistory, only nodes that keep an index do
+    pub const INDEX: Services = Services(1 << 4);
+
+    // answers Subscribe with a stream of Events
+    pub const EVENTS: Services = Services(1 << 5);
+
+    // everything a full node offers
+    pub const NODE: Services = Services(Self::BLOCKS.0 | Self::HEADERS.0 | Self::PROOFS.0 | Self::RELAY.0 | Self::EVENTS.0);
+
+
+    pub fn contains(self, other: Services) -> bool {
+
+        self.0 & other.0 == other.0
+    }
+
+
+    // names of the offered services, for people to read
+
+    pub fn names(self) -> Vec<&'static str> {
+
+        [(Self::BLOCKS, "blocks"), (Self::HEADERS, "headers"), (Self::PROOFS, "proofs"), (Self::RELAY, "relay"), (Self::INDEX, "index"),
+            (Self::EVENTS, "events")]
+            .into_iter()
+            .filter(|(service, _)| self.contains(*service))
+            .map(|(_, name)| name)
+            .collect()
+    }
+}
+
+
+impl BitOr for Services {
+
+    type Output = Services;
+
+    fn bitor(self, other: Services) -> Services {
+
+        Services(self.0 | other.0)
+    }
+}
+
+
+// the first message on every connection, in both directions
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Version {
+
+    pub version: u32,
+
+    // the chain the peer is on, see ChainParams::magic
+    pub magic: [u8; 4],
+
+    pub user_agent: String,
+
+    // length of the peer's active chain, 0 for wallets and miners
+    pub best_height: u64,
+
+    pub services: Services,
+
+    // random for every node process, a node that receives its own nonce has dialed itself
+    pub nonce: u64,
+
+    // the port the peer accepts connections on, None for wallets and miners.
+    // together with the address the connection came from, that is where other nodes can reach it
+    pub listen_port: Option<u16>,
+
+    // when it was sent by the clock of the peer, nodes adjust their time by the clocks of their peers
+    pub timestamp: DateTime<Utc>,
+}
+
+
+impl Version {
+
+    pub fn new(params: &ChainParams, user_agent: &str, best_height: u64, services: Services) -> Self {
+
+        Version {
+            version: PROTOCOL_VERSION,
+            magic: params.magic(),
+            user_agent: user_agent.to_owned(),
+            best_height,
+            services,
+            nonce: rand::random(),
+            listen_port: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+
+    // whether we (self) can talk to a peer that sent `theirs`
+
+    pub fn check_peer(&self, theirs: &Version) -> Result<(), HandshakeError> {
+
+        if theirs.magic != self.magic {
+
+            return Err(HandshakeError::WrongNetwork);
+        }
+
+        if theirs.version < MIN_PROTOCOL_VERSION {
+
+            return Err(HandshakeError::UnsupportedVersion(theirs.version));
+        }
+
+        if theirs.nonce == self.nonce {
+
+            return Err(HandshakeError::SelfConnection);
+        }
+
+        Ok(())
+    }
+
+
+    // whether the side that sent this version offers what the message needs
+
+    pub fn supports(&self, message: &Message) -> bool {
+
+        self.services.contains(message.required_services())
+    }
+}
+
+
+// most headers sent in response to a single FetchHeaders
+
+pub const MAX_HEADERS: usize = 2000;
+
+// most items announced or requested in a single Inventory or GetData
+
+pub const MAX_INVENTORY: usize = 1000;
+
+
+// most entries sent in a single History, the most recent ones
+
+pub const MAX_HISTORY: usize = 20_000;
+
+// most addresses sent in a single Addresses
+
+pub const MAX_ADDRESSES: usize = 1000;
+
+
+// a transaction or block, named by its hash, that a node announces or asks for
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum InventoryItem {
+
+    Transaction(Hash),
+
+    Block(Hash),
+}
+
+
+// where a node accepts connections, and when it was last known to be there
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerAddress {
+
+    pub address: String,
+
+    pub last_seen: DateTime<Utc>,
+}
+
+
+// what a subscriber can ask to be told about
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Topic {
+
+    HashBlock,
+
+    RawBlock,
+
+    HashTx,
+
+    RawTx,
+
+    Reorg,
+}
+
+
+// something that happened to the node's chain or mempool, sent to the subscribers of its topic
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Event {
+
+    // a block was connected to the active chain
+    HashBlock(Hash),
+
+    RawBlock(Block),
+
+    // a transaction was accepted into the mempool
+    HashTx(Hash),
+
+    RawTx(Transaction),
+
+    // the active chain switched to another branch. the blocks taken off, tip first, and the
+    // ones added instead, oldest first. the added ones follow as HashBlock and RawBlock events
+    Reorg { disconnected: Vec<Hash>, connected: Vec<Hash> },
+}
+
+
+impl Event {
+
+    pub fn topic(&self) -> Topic {
+
+        match self {
+
+            Event::HashBlock(_) => Topic::HashBlock,
+
+            Event::RawBlock(_) => Topic::RawBlock,
+
+            Event::HashTx(_) => Topic::HashTx,
+
+            Event::RawTx(_) => Topic::RawTx,
+
+            Event::Reorg { .. } => Topic::Reorg,
+        }
+    }
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
 pub enum Message {
 
+    // Introduce ourselves, the first message on every connection
+    Version(Version),
+
+    // Accept the other side's Version, the handshake is done once both sides sent it
+    VerAck,
+
+    // Refuse the other side's Version and close the connection
+    Reject(String),
+
     // Fetch all UTXO's belonging to a public key
     FetchUTXOs(PublicKey),
 
-    // UTXO's belonging to a public key. Bool determines if marked 
-    UTXOs(Vec<(TransactionOutput, bool)>), 
+    // UTXO's belonging to a public key, with the outpoint needed to spend them. Bool determines if marked 
+    UTXOs(Vec<(OutPoint, TransactionOutput, bool)>), 
 
     // send the transaction to the network
     SubmitTransaction(Transaction),
 
+    // This is the response to SubmitTransaction: the hash of the transaction once it is in the
+    // mempool, or why the node refused it
+    SubmitResult(Result<Hash, BtcError>),
+
     // Broadcast a new transaction to other nodes
     NewTransaction(Transaction),
 
@@ -27,8 +294,8 @@ pub enum Message {
     // prepare the optimal block template with the coinbase transaction paying the specified public key
     FetchTemplate(PublicKey),
 
-    // The template
-    Template(Block),
+    // The template, with the fees it collects
+    Template(BlockTemplate),
 
     // Ask the node to validate a block template.
     // this is to prevent node from mining an invalid block{ 
@@ -43,11 +310,12 @@ pub enum Message {
     // Submit the mined block to a node
     SubmitTemplate(Block), 
 
-    /// Ask a node to report to all other nodes it knows about
-    DiscoverNodes,
+    // Ask a node for addresses of other nodes, it answers with a sample of the ones it knows
+    GetAddresses,
 
-    // This is the response to DiscoverNodes
-    NodeList(Vec<String>), 
+    // This is the response to GetAddresses, at most MAX_ADDRESSES of them. nodes also send it
+    // on their own, to spread the addresses of nodes that just joined or are still around
+    Addresses(Vec<PeerAddress>),
 
     // Ask a node what's the highest block it knows about in comparison to the local blockchain
     AskDifference(u32),
@@ -61,64 +329,394 @@ pub enum Message {
     // Broadcast a new block to other nodes
     NewBlock(Block),
 
+    // Ask a node to prove that the transaction with this hash is in the active chain
+    FetchProof(Hash),
+
+    // This is the response to FetchProof: the header of the containing block and the merkle branch,
+    // or None if the transaction is not in the active chain
+    Proof(Option<(BlockHeader, MerkleProof)>),
+
+    // Ask a node for the headers of its active chain that follow the first hash of the locator
+    // it knows (or its genesis if it knows none), at most MAX_HEADERS of them
+    FetchHeaders(Vec<Hash>),
+
+    // This is the response to FetchHeaders
+    Headers(Vec<BlockHeader>),
+
+    // Ask a node to send the block with this hash, active chain or not
+    FetchBlockByHash(Hash),
+
+    // This is the response to FetchBlockByHash when the node does not have the block,
+    // otherwise it answers with NewBlock
+    BlockNotFound(Hash),
+
+    // Announce transactions and blocks by hash, instead of pushing them whole
+    Inventory(Vec<InventoryItem>),
+
+    // This is the response to Inventory: the announced items the node does not have yet.
+    // the announcing side follows up with a NewTransaction or NewBlock for each of them
+    GetData(Vec<InventoryItem>),
+
+    // Check that the other side is still there
+    Ping(u64),
+
+    // This is the response to Ping, with the same number
+    Pong(u64),
+
+    // Ask a node for a transaction of its mempool or active chain
+    FetchTransaction(Hash),
 
+    // This is the response to FetchTransaction: the hash of the block holding the transaction,
+    // None while it is in the mempool, and the transaction itself. None if the node does not know it
+    TransactionInfo(Option<(Option<Hash>, Transaction)>),
+
+    // Ask a node for everything paid to a public key and spent from it on the active chain
+    FetchHistory(PublicKey),
+
+    // This is the response to FetchHistory, oldest first, at most MAX_HISTORY entries
+    History(Vec<HistoryEntry>),
+
+    // Ask for the banned hosts. this and the other ban messages are only answered
+    // on connections from the node's own machine
+    FetchBans,
+
+    // Ban a host, for this many seconds or the node's default ban time
+    Ban(String, Option<u64>),
+
+    // Lift the ban of a host
+    Unban(String),
+
+    // This is the response to FetchBans, Ban and Unban: every banned host and when its ban ends
+    Bans(Vec<(String, DateTime<Utc>)>),
+
+    // Ask a node to send Events of these topics from now on, until the connection ends.
+    // a later Subscribe replaces the topics, an empty one ends the subscription
+    Subscribe(Vec<Topic>),
+
+    // Something happened that the other side subscribed to
+    Event(Event),
+
+}
+
+
+// every message travels in a frame:
+// [magic: 4][command: 12, ascii, zero padded][exchange: 1][request id: u32 le][payload length: u32 le][checksum: 4]
+// [payload: cbor of the message]
+// the header is checked first, so a peer can never make us allocate more than the limit of the command it announced
+
+pub const FRAME_HEADER_SIZE: usize = 29;
+
+
+// how a frame relates to the other frames on its connection. a request id is picked by the side
+// that sends the request, and the reply carries the same id, so replies can be told apart from
+// messages the other side sends on its own
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exchange {
+
+    // sent on its own, no answer expected
+    Notification,
+
+    // the other side answers with a Reply carrying the same id
+    Request(u32),
+
+    Reply(u32),
+}
+
+
+impl Exchange {
+
+    fn to_bytes(self) -> [u8; 5] {
+
+        let (kind, id) = match self {
+
+            Exchange::Notification => (0u8, 0),
+
+            Exchange::Request(id) => (1, id),
+
+            Exchange::Reply(id) => (2, id),
+        };
+
+        let mut bytes = [kind, 0, 0, 0, 0];
+
+        bytes[1..].copy_from_slice(&id.to_le_bytes());
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, FrameError> {
+
+        let id = u32::from_le_bytes(bytes[1..5].try_into().unwrap());
+
+        match bytes[0] {
+
+            0 => Ok(Exchange::Notification),
+
+            1 => Ok(Exchange::Request(id)),
+
+            2 => Ok(Exchange::Reply(id)),
+
+            kind => Err(FrameError::UnknownExchange(kind)),
+        }
+    }
 }
 
+const COMMAND_SIZE: usize = 12;
+
+// payload limits per kind of message
+
+const MAX_SMALL_PAYLOAD: usize = 16 * 1024;
+
+const MAX_TRANSACTION_PAYLOAD: usize = 1024 * 1024;
+
+// a block at max_block_size takes less than 3 MB in CBOR
+const MAX_BLOCK_PAYLOAD: usize = 4 * 1024 * 1024;
+
+const MAX_ADDRESSES_PAYLOAD: usize = 128 * 1024;
+
+const MAX_HEADERS_PAYLOAD: usize = 1024 * 1024;
+
+const MAX_UTXOS_PAYLOAD: usize = 8 * 1024 * 1024;
+
+const MAX_INVENTORY_PAYLOAD: usize = 64 * 1024;
 
-// we are going to use length-prefixed encoding for message and we are going to use CBOR for serialization
-// first we send the message lenght then the actual message so that receiver will get 
-// to know how much of message to expect
 
 impl Message {
 
-    pub fn encode(&self) -> Result< Vec<u8>, ciborium::ser::Error<IoError>> {
+    // the service the receiving side has to offer to answer this message
+
+    pub fn required_services(&self) -> Services {
+
+        use Message::*;
+
+        match self {
+
+            FetchBlock(_) | FetchBlockByHash(_) | AskDifference(_) => Services::BLOCKS,
+
+            FetchHeaders(_) => Services::HEADERS,
+
+            FetchProof(_) => Services::PROOFS,
+
+            FetchTransaction(_) | FetchHistory(_) => Services::INDEX,
+
+            Subscribe(_) => Services::EVENTS,
+
+            NewBlock(_) | NewTransaction(_) | Inventory(_) => Services::RELAY,
+
+            _ => Services::NONE,
+        }
+    }
+
+
+    // the command written into the frame header, at most COMMAND_SIZE ascii characters
+
+    pub fn command(&self) -> &'static str {
+
+        use Message::*;
+
+        match self {
+
+            Version(_) => "version",
+            VerAck => "verack",
+            Reject(_) => "reject",
+            FetchUTXOs(_) => "fetchutxos",
+            UTXOs(_) => "utxos",
+            SubmitTransaction(_) => "submittx",
+            SubmitResult(_) => "submitresult",
+            NewTransaction(_) => "newtx",
+            FetchTemplate(_) => "fetchtmpl",
+            Template(_) => "template",
+            ValidateTemplate(_) => "validatetmpl",
+            TemplateValidity(_) => "tmplvalidity",
+            SubmitTemplate(_) => "submittmpl",
+            GetAddresses => "getaddr",
+            Addresses(_) => "addr",
+            AskDifference(_) => "askdiff",
+            Difference(_) => "difference",
+            FetchBlock(_) => "fetchblock",
+            NewBlock(_) => "newblock",
+            FetchProof(_) => "fetchproof",
+            Proof(_) => "proof",
+            FetchHeaders(_) => "fetchheaders",
+            Headers(_) => "headers",
+            FetchBlockByHash(_) => "getblock",
+            BlockNotFound(_) => "notfound",
+            Inventory(_) => "inv",
+            GetData(_) => "getdata",
+            Ping(_) => "ping",
+            Pong(_) => "pong",
+            FetchTransaction(_) => "fetchtx",
+            TransactionInfo(_) => "txinfo",
+            FetchHistory(_) => "fetchhistory",
+            History(_) => "history",
+            FetchBans => "fetchbans",
+            Ban(..) => "ban",
+            Unban(_) => "unban",
+            Bans(_) => "bans",
+            Subscribe(_) => "subscribe",
+            Event(_) => "event",
+        }
+    }
+
+
+    // largest payload accepted for a command, None if the command is unknown
+
+    pub fn max_payload(command: &str) -> Option<usize> {
+
+        let max = match command {
+
+            "version" | "verack" | "reject" | "fetchutxos" | "fetchtmpl" | "tmplvalidity" | "getaddr"
+            | "askdiff" | "difference" | "fetchblock" | "fetchproof" | "proof" | "fetchheaders"
+            | "getblock" | "notfound" | "ping" | "pong" | "fetchbans" | "ban" | "unban" | "fetchtx"
+            | "fetchhistory" | "subscribe" | "submitresult" => MAX_SMALL_PAYLOAD,
+
+            "submittx" | "newtx" => MAX_TRANSACTION_PAYLOAD,
+
+            // a transaction and the hash of its block
+            "txinfo" => MAX_TRANSACTION_PAYLOAD + MAX_SMALL_PAYLOAD,
+
+            "template" | "validatetmpl" | "submittmpl" | "newblock" | "event" => MAX_BLOCK_PAYLOAD,
+
+            "addr" | "bans" => MAX_ADDRESSES_PAYLOAD,
+
+            "inv" | "getdata" => MAX_INVENTORY_PAYLOAD,
+
+            "headers" => MAX_HEADERS_PAYLOAD,
+
+            "utxos" | "history" => MAX_UTXOS_PAYLOAD,
+
+            _ => return None,
+        };
+
+        Some(max)
+    }
+
+
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
 
         let mut bytes = Vec::new();
-        
-        ciborium::into_writer(self, &mut bytes)?;
+
+        ciborium::into_writer(self, &mut bytes).map_err(|_| FrameError::Encode)?;
 
         Ok(bytes)
+    }
+
+    pub fn decode(data: &[u8],) -> Result<Self, FrameError> {
+
+        ciborium::from_reader(data).map_err(|_| FrameError::Decode)
+    }
+
+
+    // the complete frame for this message, header and payload
+
+    pub fn frame(&self, magic: [u8; 4], exchange: Exchange) -> Result<Vec<u8>, FrameError> {
+
+        let payload = self.encode()?;
+
+        let command = self.command();
+
+        // never send what the other side would refuse
 
+        let max = Self::max_payload(command).expect("bug: message without a size limit");
 
+        if payload.len() > max {
+
+            return Err(FrameError::Oversized { command: command.to_owned(), len: payload.len(), max });
+        }
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+
+        let mut command_bytes = [0u8; COMMAND_SIZE];
+
+        command_bytes[..command.len()].copy_from_slice(command.as_bytes());
+
+        frame.extend_from_slice(&magic);
+        frame.extend_from_slice(&command_bytes);
+        frame.extend_from_slice(&exchange.to_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&checksum(&payload));
+        frame.extend_from_slice(&payload);
+
+        Ok(frame)
     }
 
-    pub fn decode(data: &[u8],) -> Result<Self, ciborium::de::Error<IoError>> {
 
-        ciborium::from_reader(data)
+    // check a frame header before anything is allocated for the payload
+
+    fn parse_frame_header(header: &[u8; FRAME_HEADER_SIZE], magic: [u8; 4]) -> Result<FrameHeader, FrameError> {
+
+        if header[..4] != magic {
+
+            return Err(FrameError::WrongMagic(header[..4].try_into().unwrap()));
+        }
+
+        let command_bytes = &header[4..4 + COMMAND_SIZE];
+
+        let command_len = command_bytes.iter().position(|&byte| byte == 0).unwrap_or(COMMAND_SIZE);
+
+        let command = String::from_utf8_lossy(&command_bytes[..command_len]).into_owned();
+
+        let Some(max) = Self::max_payload(&command) else {
+
+            return Err(FrameError::UnknownCommand(command));
+        };
+
+        let exchange = Exchange::from_bytes(&header[16..21])?;
+
+        let len = u32::from_le_bytes(header[21..25].try_into().unwrap()) as usize;
+
+        if len > max {
+
+            return Err(FrameError::Oversized { command, len, max });
+        }
+
+        Ok(FrameHeader {
+            command,
+            exchange,
+            len,
+            checksum: header[25..29].try_into().unwrap(),
+        })
     }
 
-    pub fn send(&self, stream: &mut impl Write,) -> Result<(), ciborium::ser::Error<IoError>> {
 
+    fn from_frame(header: &FrameHeader, payload: &[u8]) -> Result<Self, FrameError> {
 
-        let bytes  = self.encode()?;
+        if checksum(payload) != header.checksum {
 
-        let len = bytes.len() as u64;
+            return Err(FrameError::BadChecksum);
+        }
 
-        stream.write_all(&len.to_be_bytes())?;
+        let message = Self::decode(payload)?;
 
-        stream.write_all(&bytes)?;
+        if message.command() != header.command {
 
-        Ok(())
+            return Err(FrameError::CommandMismatch(header.command.clone()));
+        }
 
+        Ok(message)
     }
 
-    pub fn receive(stream: &mut impl Read,) -> Result<Self, ciborium::de::Error<IoError>> {
 
-        let mut len_bytes = [0u8; 8];  // what if it does not matches the expected length
+    pub fn send(&self, stream: &mut impl Write, magic: [u8; 4]) -> Result<(), FrameError> {
 
-        stream.read_exact(&mut len_bytes)?;
+        stream.write_all(&self.frame(magic, Exchange::Notification)?)?;
 
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        Ok(())
+    }
 
-        let mut data = vec![0u8; len];
+    pub fn receive(stream: &mut impl Read, magic: [u8; 4]) -> Result<Self, FrameError> {
 
-        stream.read_exact(&mut data)?;
+        let mut header = [0u8; FRAME_HEADER_SIZE];
 
-        Self::decode(&data)
+        stream.read_exact(&mut header)?;
 
+        let header = Self::parse_frame_header(&header, magic)?;
 
+        let mut payload = vec![0u8; header.len];
 
+        stream.read_exact(&mut payload)?;
 
+        Self::from_frame(&header, &payload)
     }
 
 //     Pinning in Rust
@@ -131,44 +729,270 @@ impl Message {
 // The Unpin trait is used to indicate that a type can be safely moved after it has been pinned. If a type implements Unpin, it means that the compiler can move the value in memory without violating the constraints of pinning
 
 
-    pub async fn send_async(&self, stream: &mut (impl AsyncWrite + Unpin)) -> Result<(), ciborium::ser::Error<IoError>> {
+    // send_async and receive_async are for connections where only one side talks at a time, like the handshake.
+    // once both sides may send on their own, a Connection keeps track of the exchanges
 
+    pub async fn send_async(&self, stream: &mut (impl AsyncWrite + Unpin), magic: [u8; 4]) -> Result<(), FrameError> {
 
-        let bytes = self.encode()?;
+        self.send_framed_async(stream, magic, Exchange::Notification).await
+    }
 
-        let len = bytes.len() as u64;
 
-        stream.write_all(&len.to_be_bytes()).await?;
+    pub async  fn receive_async(stream: &mut (impl AsyncRead + Unpin), magic: [u8; 4]) -> Result<Self, FrameError> {
 
-        stream.write_all(&bytes).await?;
+        let (_, message) = Self::receive_framed_async(stream, magic).await?;
+
+        Ok(message)
+    }
+
+
+    pub async fn send_framed_async(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        magic: [u8; 4],
+        exchange: Exchange,
+    ) -> Result<(), FrameError> {
+
+        stream.write_all(&self.frame(magic, exchange)?).await?;
 
         Ok(())
+    }
+
+
+    pub async fn receive_framed_async(
+        stream: &mut (impl AsyncRead + Unpin),
+        magic: [u8; 4],
+    ) -> Result<(Exchange, Self), FrameError> {
+
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+
+        stream.read_exact(&mut header).await?;
+
+        let header = Self::parse_frame_header(&header, magic)?;
 
+        // the length is bounded by the command's limit at this point
 
+        let mut payload = vec![0u8; header.len];
 
+        stream.read_exact(&mut payload).await?;
+
+        Ok((header.exchange, Self::from_frame(&header, &payload)?))
     }
+}
+
+
+struct FrameHeader {
+
+    command: String,
+
+    exchange: Exchange,
 
+    len: usize,
 
-    
+    checksum: [u8; 4],
+}
+
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+
+    Hash::hash_bytes(payload).as_bytes()[..4].try_into().unwrap()
+}
 
-    pub async  fn receive_async(stream: &mut (impl AsyncRead + Unpin),) -> Result<Self, ciborium::de::Error<IoError>> {
-        
 
-        let mut len_bytes = [0u8; 8];   // why only eight bytes
+// the side that opened the connection: send our Version, check theirs, then exchange VerAcks
 
-        stream.read_exact(&mut len_bytes).await ?;
+pub async fn handshake_outbound(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ours: &Version,
 
-        let len = u64::from_be_bytes(len_bytes) as usize;
+) -> Result<Version, HandshakeError> {
 
-        let mut data = vec![0u8; len];   
-        
-        stream.read_exact(&mut data ).await ?;
+    Message::Version(ours.clone()).send_async(stream, ours.magic).await?;
 
-        Self::decode(&data)
+    let theirs = receive_version(stream, ours).await?;
 
+    let Message::VerAck = Message::receive_async(stream, ours.magic).await? else {
 
+        return Err(HandshakeError::UnexpectedMessage);
+    };
 
+    Message::VerAck.send_async(stream, ours.magic).await?;
+
+    Ok(theirs)
+}
+
+
+// the side that accepted the connection: check their Version, answer with ours and a VerAck
+
+pub async fn handshake_inbound(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ours: &Version,
+
+) -> Result<Version, HandshakeError> {
+
+    let theirs = receive_version(stream, ours).await?;
+
+    Message::Version(ours.clone()).send_async(stream, ours.magic).await?;
+
+    Message::VerAck.send_async(stream, ours.magic).await?;
+
+    let Message::VerAck = Message::receive_async(stream, ours.magic).await? else {
+
+        return Err(HandshakeError::UnexpectedMessage);
+    };
+
+    Ok(theirs)
+}
+
+
+// receive the other side's Version and tell it why, if we refuse it
+
+async fn receive_version(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ours: &Version,
+
+) -> Result<Version, HandshakeError> {
+
+    let theirs = match Message::receive_async(stream, ours.magic).await {
+
+        Ok(Message::Version(theirs)) => theirs,
+
+        // a node of another network would not understand a reply in our magic, it gets one in its own
+
+        Err(FrameError::WrongMagic(magic)) => {
+
+            let _ = Message::Reject(HandshakeError::WrongNetwork.to_string()).send_async(stream, magic).await;
+
+            return Err(HandshakeError::WrongNetwork);
+        }
+
+        Err(e) => return Err(e.into()),
+
+        Ok(Message::Reject(reason)) => return Err(HandshakeError::Rejected(reason)),
+
+        Ok(_) => return Err(HandshakeError::UnexpectedMessage),
+    };
+
+    if let Err(e) = ours.check_peer(&theirs) {
+
+        // a node that dialed itself only finds out from our Version, which carries its own nonce.
+        // best effort, we are closing the connection anyway
+
+        let reply = match e {
+
+            HandshakeError::SelfConnection => Message::Version(ours.clone()),
+
+            _ => Message::Reject(e.to_string()),
+        };
+
+        let _ = reply.send_async(stream, ours.magic).await;
+
+        return Err(e);
     }
+
+    Ok(theirs)
 }
 
 
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn wrong_network_is_rejected() {
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        let (mut dialer, mut listener) = tokio::io::duplex(MAX_SMALL_PAYLOAD);
+
+        let regtest = Version::new(&ChainParams::regtest(), "test", 0, Services::NONE);
+
+        let testnet = Version::new(&ChainParams::testnet(), "test", 0, Services::NONE);
+
+        let (outbound, inbound) = runtime.block_on(async {
+
+            let inbound = tokio::spawn(async move { handshake_inbound(&mut listener, &testnet).await });
+
+            (handshake_outbound(&mut dialer, &regtest).await, inbound.await.unwrap())
+        });
+
+        assert!(matches!(inbound, Err(HandshakeError::WrongNetwork)));
+
+        // the dialer reads the reason in its own magic instead of a frame for another network
+
+        assert!(matches!(outbound, Err(HandshakeError::Rejected(reason)) if reason == HandshakeError::WrongNetwork.to_string()));
+    }
+
+
+    fn ping_frame() -> Vec<u8> {
+
+        Message::Ping(7).frame(ChainParams::regtest().magic(), Exchange::Notification).unwrap()
+    }
+
+
+    fn receive(frame: &[u8]) -> Result<Message, FrameError> {
+
+        Message::receive(&mut std::io::Cursor::new(frame), ChainParams::regtest().magic())
+    }
+
+
+    #[test]
+    fn valid_frame_is_received() {
+
+        assert!(matches!(receive(&ping_frame()), Ok(Message::Ping(7))));
+    }
+
+
+    #[test]
+    fn frame_of_another_network() {
+
+        let frame = Message::Ping(7).frame(ChainParams::testnet().magic(), Exchange::Notification).unwrap();
+
+        assert!(matches!(receive(&frame), Err(FrameError::WrongMagic(magic)) if magic == ChainParams::testnet().magic()));
+    }
+
+
+    #[test]
+    fn oversized_length_is_refused_before_the_payload() {
+
+        let mut frame = ping_frame();
+
+        frame[21..25].copy_from_slice(&(MAX_SMALL_PAYLOAD as u32 + 1).to_le_bytes());
+
+        // the header alone is enough to refuse it
+
+        frame.truncate(FRAME_HEADER_SIZE);
+
+        assert!(matches!(receive(&frame), Err(FrameError::Oversized { len, max, .. }) if len == MAX_SMALL_PAYLOAD + 1 && max == MAX_SMALL_PAYLOAD));
+    }
+
+
+    #[test]
+    fn bad_checksum() {
+
+        let mut frame = ping_frame();
+
+        *frame.last_mut().unwrap() ^= 1;
+
+        assert!(matches!(receive(&frame), Err(FrameError::BadChecksum)));
+    }
+
+
+    #[test]
+    fn payload_of_another_command() {
+
+        // a Ping payload under the pong command, with a checksum that matches
+
+        let mut frame = ping_frame();
+
+        frame[4..4 + COMMAND_SIZE].copy_from_slice(b"pong\0\0\0\0\0\0\0\0");
+
+        assert!(matches!(receive(&frame), Err(FrameError::CommandMismatch(command)) if command == "pong"));
+
+        frame[4..4 + COMMAND_SIZE].copy_from_slice(b"nosuchthing\0");
+
+        assert!(matches!(receive(&frame), Err(FrameError::UnknownCommand(command)) if command == "nosuchthing"));
+    }
+}
@@ -0,0 +1,382 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::crypto::PublicKey;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, Transaction, TransactionOutput};
+use crate::util::{MerkleRoot, Saveable};
+use crate::U256;
+use std::fmt;
+use std::str::FromStr;
+use std::io::{
+    Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write,
+};
+
+
+// satoshis in one coin, rewards are set in coins
+pub const COIN: u64 = 100_000_000;
+
+// the key every genesis coinbase pays to (miner/alice.pub.pem)
+
+const GENESIS_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MFYwEAYHKoZIzj0CAQYFK4EEAAoDQgAEflWT89R8EmNMyxn5wMviFVA0R3eNIGzu
+VhIGqLGWtgxF48f13KvBgGsw72xBv90uGnvnMmMqrh9k6kJSeQV/IQ==
+-----END PUBLIC KEY-----
+";
+
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Network {
+
+    Main,
+    Testnet,
+    Regtest,
+}
+
+
+impl fmt::Display for Network {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        match self {
+
+            Network::Main => write!(f, "main"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+
+// so the network can be picked on the command line
+
+impl FromStr for Network {
+
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+
+        match s {
+
+            "main" | "mainnet" => Ok(Network::Main),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network {s}, expected main, testnet or regtest")),
+        }
+    }
+}
+
+
+// everything that makes one chain different from another.
+// Blockchain, block validation, the node, the miner and the wallet all take these,
+// so a network is selected at runtime instead of being compiled in
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChainParams {
+
+    pub network: Network,
+
+    // initial reward in coins - multiply by COIN to get satoshis
+    pub initial_reward: u64,
+
+    // halving interval in blocks
+    pub halving_interval: u64,
+
+    // ideal block time in seconds
+    pub ideal_block_time: u64,
+
+    // minimum target : the easiest target a block may have
+    pub min_target: U256,
+
+    // difficulty update interval in blocks
+    pub difficulty_update_interval: u64,
+
+    // maximum mempool transaction age in seconds
+    pub max_mempool_transaction_age: u64,
+
+    // maximum size of a block, in bytes of its consensus encoding
+    pub max_block_size: usize,
+
+    // the first block of the chain, every node of the network starts with it
+    pub genesis_block: Block,
+}
+
+
+// save and load expecting CBOR from ciborium as format
+
+impl Saveable for ChainParams {
+
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+
+        let params: ChainParams = ciborium::de::from_reader(reader).map_err(|_| {
+
+            IoError::new(IoErrorKind::InvalidData, "failed to deserialize chain params")
+        })?;
+
+        params.check().map_err(|e| IoError::new(IoErrorKind::InvalidData, format!("invalid chain params: {e}")))?;
+
+        Ok(params)
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+
+            IoError::new(IoErrorKind::InvalidData, "failed to serialize chain params")
+        })
+    }
+}
+
+
+impl ChainParams {
+
+    // realistic values, like bitcoin itself
+
+    pub fn main() -> Self {
+
+        let min_target = U256([
+            0xFFFF_FFFF_FFFF_FFFF,
+            0xFFFF_FFFF_FFFF_FFFF,
+            0xFFFF_FFFF_FFFF_FFFF,
+            0x0000_0000_FFFF_FFFF,
+        ]);
+
+        Self::preset(Network::Main, 210_000, 600, min_target, 2016)
+    }
+
+    // realistic timing and rewards, but a minimum target a single machine can still mine at.
+    // meant for staging
+
+    pub fn testnet() -> Self {
+
+        // MIN_TARGET number is encoded in such a weird
+        // way - it is little-endian. The least significant 64 bits are the last
+        let min_target = U256([
+            0xFFFF_FFFF_FFFF_FFFF,
+            0xFFFF_FFFF_FFFF_FFFF,
+            0xFFFF_FFFF_FFFF_FFFF,
+            0x0000_FFFF_FFFF_FFFF,
+        ]);
+
+        Self::preset(Network::Testnet, 210_000, 600, min_target, 2016)
+    }
+
+    // trivial difficulty and a 10 block halving, for local development and integration tests
+
+    pub fn regtest() -> Self {
+
+        // every second hash matches
+        let min_target = U256::MAX >> 1;
+
+        Self::preset(Network::Regtest, 10, 10, min_target, 50)
+    }
+
+    // 50 coins halving every halving_interval blocks, on a genesis block from the same day for every network
+
+    fn preset(
+        network: Network,
+        halving_interval: u64,
+        ideal_block_time: u64,
+        min_target: U256,
+        difficulty_update_interval: u64,
+
+    ) -> Self {
+
+        let mut params = ChainParams {
+            network,
+            initial_reward: 50,
+            halving_interval,
+            ideal_block_time,
+            min_target,
+            difficulty_update_interval,
+            max_mempool_transaction_age: 14 * 24 * 3600,
+            max_block_size: 1_000_000,
+            genesis_block: genesis_block(network, "2024-12-01T00:00:00Z", min_target, 0),
+        };
+
+        // the genesis coinbase pays the reward of height 0, which takes the params to work out
+
+        params.genesis_block = genesis_block(network, "2024-12-01T00:00:00Z", min_target, params.block_reward(0));
+
+        params
+    }
+
+
+    pub fn for_network(network: Network) -> Self {
+
+        match network {
+
+            Network::Main => Self::main(),
+            Network::Testnet => Self::testnet(),
+            Network::Regtest => Self::regtest(),
+        }
+    }
+
+    // block reward in satoshis for a block at the given height.
+    //
+    // coinbase validation always counted the reward in satoshis, initial_reward * 10^8 halved per interval.
+    // block templates used to offer initial_reward * 10 instead, which no valid block could pay,
+    // both go through here now
+
+    pub fn block_reward(&self, block_height: u64) -> u64 {
+
+        let halving = block_height / self.halving_interval;
+
+        // after 64 halvings the shift would overflow, the reward is zero long before that
+        (self.initial_reward * COIN).checked_shr(halving as u32).unwrap_or(0)
+    }
+
+
+    // params from a file are only used once they cannot make the chain divide by zero or overflow
+
+    fn check(&self) -> Result<(), String> {
+
+        if self.halving_interval == 0 {
+
+            return Err("halving interval must be positive".to_owned());
+        }
+
+        if self.difficulty_update_interval == 0 || self.ideal_block_time == 0 {
+
+            return Err("difficulty update interval and ideal block time must be positive".to_owned());
+        }
+
+        if self.ideal_block_time.checked_mul(self.difficulty_update_interval).is_none() {
+
+            return Err("difficulty update interval is too long".to_owned());
+        }
+
+        // every coin that will ever exist has to add up in a u64, that is less than twice
+        // the first reward for every block of the first interval
+
+        let supply = self.initial_reward.checked_mul(COIN)
+            .and_then(|reward| reward.checked_mul(self.halving_interval))
+            .and_then(|supply| supply.checked_mul(2));
+
+        if supply.is_none() {
+
+            return Err("initial reward is out of range".to_owned());
+        }
+
+        Ok(())
+    }
+
+    pub fn genesis_hash(&self) -> Hash {
+
+        self.genesis_block.hash()
+    }
+
+
+    // identifies the chain on the wire. derived from the genesis block,
+    // so chains loaded from a custom params file get their own
+
+    pub fn magic(&self) -> [u8; 4] {
+
+        self.genesis_hash().as_bytes()[..4].try_into().unwrap()
+    }
+}
+
+
+// the genesis block is fixed per network: fixed timestamp, fixed output id and
+// a coinbase paying reward to GENESIS_PUBLIC_KEY.
+// it is trusted as is, so it does not need to be mined
+
+fn genesis_block(network: Network, timestamp: &str, target: U256, reward: u64) -> Block {
+
+    let pubkey = PublicKey::load(GENESIS_PUBLIC_KEY.as_bytes()).expect("bug: invalid genesis public key");
+
+    let timestamp: DateTime<Utc> = timestamp.parse().expect("bug: invalid genesis timestamp");
+
+    let transactions = vec![Transaction::new(
+        vec![],
+        vec![TransactionOutput {
+            value: reward,
+            unique_id: Uuid::from_u128(network as u128),
+            pubkey,
+        }],
+    )];
+
+    let merkle_root = MerkleRoot::calculate(&transactions);
+
+    Block::new(
+        BlockHeader::new(timestamp, 0, Hash::zero(), merkle_root, target),
+        transactions,
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    fn reload(params: &ChainParams) -> IoResult<ChainParams> {
+
+        let mut bytes = vec![];
+
+        params.save(&mut bytes)?;
+
+        ChainParams::load(bytes.as_slice())
+    }
+
+
+    #[test]
+    fn block_reward_halves() {
+
+        // regtest halves every 10 blocks
+
+        let params = ChainParams::regtest();
+
+        assert_eq!(params.block_reward(0), 50 * COIN);
+
+        assert_eq!(params.block_reward(9), 50 * COIN);
+
+        assert_eq!(params.block_reward(10), 25 * COIN);
+
+        assert_eq!(params.block_reward(19), 25 * COIN);
+
+        assert_eq!(params.block_reward(20), 25 * COIN / 2);
+
+        // 50 coins are just over 2^32 satoshis, the 32nd halving leaves one and the 33rd none
+
+        assert_eq!(params.block_reward(329), 1);
+
+        assert_eq!(params.block_reward(330), 0);
+
+        assert_eq!(params.block_reward(u64::MAX), 0);
+    }
+
+
+    #[test]
+    fn genesis_pays_the_first_reward() {
+
+        for network in [Network::Main, Network::Testnet, Network::Regtest] {
+
+            let params = ChainParams::for_network(network);
+
+            assert_eq!(params.genesis_block.transactions[0].outputs[0].value, params.block_reward(0));
+        }
+    }
+
+
+    #[test]
+    fn params_file_is_checked() {
+
+        assert!(reload(&ChainParams::regtest()).is_ok());
+
+        let invalid = [
+            ChainParams { halving_interval: 0, ..ChainParams::regtest() },
+            ChainParams { difficulty_update_interval: 0, ..ChainParams::regtest() },
+            ChainParams { ideal_block_time: 0, ..ChainParams::regtest() },
+            ChainParams { ideal_block_time: u64::MAX, ..ChainParams::regtest() },
+            ChainParams { initial_reward: u64::MAX / COIN + 1, ..ChainParams::regtest() },
+            ChainParams { initial_reward: 50, halving_interval: u64::MAX / (50 * COIN), ..ChainParams::regtest() },
+        ];
+
+        for params in invalid {
+
+            assert_eq!(reload(&params).unwrap_err().kind(), IoErrorKind::InvalidData);
+        }
+    }
+}
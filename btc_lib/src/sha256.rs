@@ -1,10 +1,12 @@
 use crate::U256;
+use crate::encoding::ConsensusEncode;
 use std::fmt;
+use std::str::FromStr;
 
-use sha256::digest;
+use sha2::{Digest, Sha256};
 use serde::{ Deserialize, Serialize };
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Hash(U256);
 
 impl fmt::Display for Hash {
@@ -18,6 +20,26 @@ impl fmt::Display for Hash {
 }
 
 
+// the hex that Display writes, leading zeros may be left out
+
+impl FromStr for Hash {
+
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+
+        if s.is_empty() || s.len() > 64 {
+
+            return Err(format!("not a hash: {s}"));
+        }
+
+        U256::from_str_radix(s, 16)
+            .map(Hash)
+            .map_err(|_| format!("not a hash: {s}"))
+    }
+}
+
+
 
 
 impl Hash {
@@ -34,34 +56,28 @@ impl Hash {
 
 impl Hash {
 
-    // hash anything that can be serde Serialized via ciborium
-
-    pub fn hash<T: serde::Serialize>(data: &T) -> Self {
-
-        let mut serialized: Vec<u8> = vec![];
+    // hash the consensus encoding of a value (see crate::encoding)
 
-        if let Err(e) = ciborium::into_writer(
-            data,
-            &mut serialized
-        ) {
-
-            panic!("failed to serialize the data {:?}", e);
-        }
+    #[allow(clippy::self_named_constructors)]
+    pub fn hash<T: ConsensusEncode + ?Sized>(data: &T) -> Self {
 
-        let hash = digest(&serialized);
-        let hash_bytes = hex::decode(hash).unwrap();
-        let hash_array: [u8; 32] = hash_bytes.as_slice().try_into().unwrap();
+        Self::hash_bytes(&data.consensus_bytes())
+    }
 
-        // @note check this , it can cause the problem. big_endian method, which interprets the array directly
-        let x = U256::from_big_endian(&hash_array);
 
+    // double sha256 of raw bytes, like bitcoin
 
+    pub fn hash_bytes(data: &[u8]) -> Self {
 
-        Hash(x)
+        Self::from_digest(Sha256::digest(Sha256::digest(data)))
+    }
 
-       
 
+    fn from_digest(digest: impl AsRef<[u8]>) -> Self {
 
+        // the digest is read as a big-endian number, so the leading zero bytes of the digest
+        // are the most significant ones when comparing against a target
+        Hash(U256::from_big_endian(digest.as_ref()))
     }
 
 
@@ -76,4 +92,48 @@ impl Hash {
 
         Hash(U256::zero())
     }
-}
\ No newline at end of file
+}
+
+
+// hashes many messages that share the same prefix, like block headers that only differ in the nonce.
+// the full 64 byte blocks of the prefix are compressed once, every message after that
+// only pays for the rest of the data and the second round
+
+#[derive(Clone)]
+pub struct PrefixHasher {
+
+    state: Sha256,
+
+    // prefix bytes that did not fill a whole 64 byte block
+    rest: Vec<u8>,
+}
+
+
+impl PrefixHasher {
+
+    pub fn new(prefix: &[u8]) -> Self {
+
+        let full_blocks = prefix.len() - prefix.len() % 64;
+
+        let mut state = Sha256::new();
+
+        state.update(&prefix[..full_blocks]);
+
+        PrefixHasher {
+            state,
+            rest: prefix[full_blocks..].to_vec(),
+        }
+    }
+
+    // double sha256 of prefix || suffix
+
+    pub fn hash(&self, suffix: &[u8]) -> Hash {
+
+        let mut state = self.state.clone();
+
+        state.update(&self.rest);
+        state.update(suffix);
+
+        Hash::from_digest(Sha256::digest(state.finalize()))
+    }
+}
@@ -0,0 +1,1314 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::crypto::PublicKey;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, BlockUndo, OutPoint, TransactionOutput};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use std::io::{
+
+    Cursor, Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write,
+};
+
+
+// on-disk storage of the node: an append-only block file with an index of the blocks in it,
+// and a utxo database that is changed by one atomic batch per connected or disconnected block.
+// optionally also an index of the transactions and public keys of the active chain, kept the same way
+//
+// every file is a list of records: [payload length: u32 le][checksum: 4 bytes][cbor payload].
+// a crash can only tear the last record of a file, which then fails its checksum
+// and is cut off the next time the file is opened
+
+const BLOCKS_FILE: &str = "blocks.dat";
+
+const INDEX_FILE: &str = "index.dat";
+
+const INVALID_FILE: &str = "invalid.dat";
+
+const UTXO_LOG_FILE: &str = "utxos.log";
+
+const UTXO_SNAPSHOT_FILE: &str = "utxos.dat";
+
+const CHAIN_INDEX_FILE: &str = "chainindex.log";
+
+const CHAIN_INDEX_SNAPSHOT_FILE: &str = "chainindex.dat";
+
+// length + checksum in front of every record
+const RECORD_HEADER_LEN: u64 = 8;
+
+// after this many batches the utxo and index logs are folded into a new snapshot
+const COMPACT_AFTER_BATCHES: usize = 1000;
+
+
+// what a record file is written to: a file on disk, or a buffer for chains that are not persisted
+
+trait Storage: Read + Write + Seek + Send {
+
+    fn sync(&mut self) -> IoResult<()>;
+
+    fn truncate(&mut self, len: u64) -> IoResult<()>;
+}
+
+
+impl Storage for File {
+
+    fn sync(&mut self) -> IoResult<()> {
+
+        self.sync_data()
+    }
+
+    fn truncate(&mut self, len: u64) -> IoResult<()> {
+
+        self.set_len(len)
+    }
+}
+
+
+impl Storage for Cursor<Vec<u8>> {
+
+    fn sync(&mut self) -> IoResult<()> {
+
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> IoResult<()> {
+
+        self.get_mut().truncate(len as usize);
+
+        Ok(())
+    }
+}
+
+
+// a file of checksummed records that only ever grows at the end.
+// reads go through a mutex so they can be served from a shared reference
+
+struct RecordFile {
+
+    storage: Mutex<Box<dyn Storage>>,
+
+    len: u64,
+}
+
+
+impl RecordFile {
+
+    fn open(path: &Path) -> IoResult<Self> {
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let len = file.metadata()?.len();
+
+        Ok(RecordFile {
+            storage: Mutex::new(Box::new(file)),
+            len,
+        })
+    }
+
+
+    fn memory() -> Self {
+
+        RecordFile {
+            storage: Mutex::new(Box::new(Cursor::new(vec![]))),
+            len: 0,
+        }
+    }
+
+
+    // read every record from the start, cutting the file off at the first one that is torn or damaged
+
+    fn read_all(&mut self) -> IoResult<Vec<Vec<u8>>> {
+
+        let mut records = vec![];
+
+        let mut offset = 0;
+
+        while let Some(payload) = self.try_read_at(offset)? {
+
+            offset += RECORD_HEADER_LEN + payload.len() as u64;
+
+            records.push(payload);
+        }
+
+        if offset < self.len {
+
+            println!("dropping {} bytes of damaged records", self.len - offset);
+
+            self.truncate(offset)?;
+        }
+
+        Ok(records)
+    }
+
+
+    // the payload of the record at offset, or None if there is no complete and intact record there
+
+    fn try_read_at(&self, offset: u64) -> IoResult<Option<Vec<u8>>> {
+
+        if offset + RECORD_HEADER_LEN > self.len {
+
+            return Ok(None);
+        }
+
+        let mut storage = self.storage.lock().expect("bug: record file lock poisoned");
+
+        storage.seek(SeekFrom::Start(offset))?;
+
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+
+        storage.read_exact(&mut header)?;
+
+        let payload_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+
+        if offset + RECORD_HEADER_LEN + payload_len > self.len {
+
+            return Ok(None);
+        }
+
+        let mut payload = vec![0; payload_len as usize];
+
+        storage.read_exact(&mut payload)?;
+
+        if header[4..] != checksum(&payload) {
+
+            return Ok(None);
+        }
+
+        Ok(Some(payload))
+    }
+
+
+    fn read_at(&self, offset: u64) -> IoResult<Vec<u8>> {
+
+        self.try_read_at(offset)?.ok_or_else(|| {
+
+            IoError::new(IoErrorKind::InvalidData, "damaged record")
+        })
+    }
+
+
+    // append a record and return its offset, it is only durable after sync
+
+    fn append(&mut self, payload: &[u8]) -> IoResult<u64> {
+
+        let offset = self.len;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(payload));
+        record.extend_from_slice(payload);
+
+        let storage = self.storage.get_mut().expect("bug: record file lock poisoned");
+
+        storage.seek(SeekFrom::Start(offset))?;
+
+        storage.write_all(&record)?;
+
+        // a failed write leaves len alone, so the next record overwrites whatever made it to disk
+
+        self.len += record.len() as u64;
+
+        Ok(offset)
+    }
+
+
+    fn sync(&mut self) -> IoResult<()> {
+
+        let storage = self.storage.get_mut().expect("bug: record file lock poisoned");
+
+        storage.flush()?;
+
+        storage.sync()
+    }
+
+
+    fn truncate(&mut self, len: u64) -> IoResult<()> {
+
+        self.storage.get_mut().expect("bug: record file lock poisoned").truncate(len)?;
+
+        self.len = len;
+
+        Ok(())
+    }
+}
+
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+
+    Hash::hash_bytes(payload).as_bytes()[..4].try_into().unwrap()
+}
+
+
+fn encode<T: Serialize>(value: &T) -> IoResult<Vec<u8>> {
+
+    let mut bytes = vec![];
+
+    ciborium::ser::into_writer(value, &mut bytes).map_err(|_| {
+
+        IoError::new(IoErrorKind::InvalidData, "failed to serialize record")
+    })?;
+
+    Ok(bytes)
+}
+
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> IoResult<T> {
+
+    ciborium::de::from_reader(bytes).map_err(|_| {
+
+        IoError::new(IoErrorKind::InvalidData, "failed to deserialize record")
+    })
+}
+
+
+// where a block and its undo data are in the block file.
+// a later entry for the same block replaces the earlier one
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BlockEntry {
+
+    header: BlockHeader,
+
+    block_offset: u64,
+
+    // only set once the block has been connected
+    undo_offset: Option<u64>,
+}
+
+
+// every block the node knows about, on the active chain or not.
+// only the index (the headers) is kept in memory, the blocks are read from disk when needed.
+// blocks found invalid are remembered by hash, stored or not, so they are never checked again
+
+pub struct BlockStore {
+
+    blocks: RecordFile,
+
+    index: RecordFile,
+
+    entries: HashMap<Hash, BlockEntry>,
+
+    invalid: RecordFile,
+
+    invalid_hashes: HashSet<Hash>,
+}
+
+
+impl BlockStore {
+
+    pub fn open<P: AsRef<Path>>(dir: P) -> IoResult<Self> {
+
+        let dir = dir.as_ref();
+
+        fs::create_dir_all(dir)?;
+
+        Self::load(
+            RecordFile::open(&dir.join(BLOCKS_FILE))?,
+            RecordFile::open(&dir.join(INDEX_FILE))?,
+            RecordFile::open(&dir.join(INVALID_FILE))?,
+        )
+    }
+
+
+    // a store that lives in memory only
+
+    pub fn memory() -> Self {
+
+        Self::load(RecordFile::memory(), RecordFile::memory(), RecordFile::memory()).expect("bug: empty store failed to load")
+    }
+
+
+    fn load(mut blocks: RecordFile, mut index: RecordFile, mut invalid: RecordFile) -> IoResult<Self> {
+
+        let mut entries = HashMap::new();
+
+        let mut last_offset = None;
+
+        for record in index.read_all()? {
+
+            let entry: BlockEntry = decode(&record)?;
+
+            last_offset = last_offset.max(Some(entry.block_offset)).max(entry.undo_offset);
+
+            entries.insert(entry.header.hash(), entry);
+        }
+
+        // the index is written after the data it points to, so anything behind the last
+        // indexed record is a block that was being written when we crashed
+
+        let end = match last_offset {
+
+            Some(offset) => offset + RECORD_HEADER_LEN + blocks.read_at(offset)?.len() as u64,
+
+            None => 0,
+        };
+
+        if end < blocks.len {
+
+            println!("dropping {} bytes of unindexed block data", blocks.len - end);
+
+            blocks.truncate(end)?;
+        }
+
+        let invalid_hashes = invalid.read_all()?
+            .iter()
+            .map(|record| decode(record))
+            .collect::<IoResult<_>>()?;
+
+        Ok(BlockStore {
+            blocks,
+            index,
+            entries,
+            invalid,
+            invalid_hashes,
+        })
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+
+        self.entries.is_empty()
+    }
+
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+
+        self.entries.contains_key(hash)
+    }
+
+
+    pub fn header(&self, hash: &Hash) -> Option<&BlockHeader> {
+
+        self.entries.get(hash).map(|entry| &entry.header)
+    }
+
+
+    pub fn is_invalid(&self, hash: &Hash) -> bool {
+
+        self.invalid_hashes.contains(hash)
+    }
+
+
+    // remember that a block is invalid, the block itself stays where it is
+
+    pub fn mark_invalid(&mut self, hash: Hash) -> IoResult<()> {
+
+        if self.is_invalid(&hash) {
+
+            return Ok(());
+        }
+
+        self.invalid.append(&encode(&hash)?)?;
+
+        self.invalid.sync()?;
+
+        self.invalid_hashes.insert(hash);
+
+        Ok(())
+    }
+
+
+    // headers of every stored block, in no particular order
+
+    pub fn headers(&self) -> impl Iterator<Item = (&Hash, &BlockHeader)> {
+
+        self.entries.iter().map(|(hash, entry)| (hash, &entry.header))
+    }
+
+
+    // append a block, a block that is already stored is not written again
+
+    pub fn put_block(&mut self, block: &Block) -> IoResult<()> {
+
+        let hash = block.hash();
+
+        if self.contains(&hash) {
+
+            return Ok(());
+        }
+
+        let block_offset = self.blocks.append(&encode(block)?)?;
+
+        self.blocks.sync()?;
+
+        self.put_entry(hash, BlockEntry {
+            header: block.header.clone(),
+            block_offset,
+            undo_offset: None,
+        })
+    }
+
+
+    // append the undo data of a stored block.
+    // it only depends on the chain below the block, so once written it never changes
+
+    pub fn put_undo(&mut self, hash: Hash, undo: &BlockUndo) -> IoResult<()> {
+
+        let Some(mut entry) = self.entries.get(&hash).cloned() else {
+
+            return Err(IoError::new(IoErrorKind::NotFound, "undo data for a block that is not stored"));
+        };
+
+        if entry.undo_offset.is_some() {
+
+            return Ok(());
+        }
+
+        entry.undo_offset = Some(self.blocks.append(&encode(undo)?)?);
+
+        self.blocks.sync()?;
+
+        self.put_entry(hash, entry)
+    }
+
+
+    fn put_entry(&mut self, hash: Hash, entry: BlockEntry) -> IoResult<()> {
+
+        self.index.append(&encode(&entry)?)?;
+
+        self.index.sync()?;
+
+        self.entries.insert(hash, entry);
+
+        Ok(())
+    }
+
+
+    pub fn block(&self, hash: &Hash) -> IoResult<Option<Block>> {
+
+        let Some(entry) = self.entries.get(hash) else {
+
+            return Ok(None);
+        };
+
+        decode(&self.blocks.read_at(entry.block_offset)?).map(Some)
+    }
+
+
+    pub fn undo(&self, hash: &Hash) -> IoResult<Option<BlockUndo>> {
+
+        let Some(offset) = self.entries.get(hash).and_then(|entry| entry.undo_offset) else {
+
+            return Ok(None);
+        };
+
+        decode(&self.blocks.read_at(offset)?).map(Some)
+    }
+}
+
+
+// the whole utxo set as of batch `sequence`, written in one piece when the log is compacted
+
+#[derive(Serialize, Deserialize)]
+struct UtxoSnapshot {
+
+    sequence: u64,
+
+    tip: Hash,
+
+    utxos: Vec<(OutPoint, TransactionOutput)>,
+}
+
+
+// the change one connected or disconnected block makes to the utxo set.
+// a batch is a single record, so it is applied completely or not at all
+
+#[derive(Serialize, Deserialize)]
+struct UtxoBatch {
+
+    sequence: u64,
+
+    // the tip of the active chain after this batch
+    tip: Hash,
+
+    removed: Vec<OutPoint>,
+
+    added: Vec<(OutPoint, TransactionOutput)>,
+}
+
+
+// persistent utxo set: a snapshot plus a log of the batches written since.
+// the set itself is handed to the caller on open, which keeps it in memory
+
+pub struct UtxoStore {
+
+    // None for a store in memory, which never writes a snapshot
+    dir: Option<PathBuf>,
+
+    log: RecordFile,
+
+    sequence: u64,
+
+    tip: Hash,
+
+    // batches in the log since the last snapshot
+    batches: usize,
+}
+
+
+impl UtxoStore {
+
+    pub fn open<P: AsRef<Path>>(dir: P) -> IoResult<(Self, HashMap<OutPoint, TransactionOutput>)> {
+
+        let dir = dir.as_ref();
+
+        fs::create_dir_all(dir)?;
+
+        // the snapshot is replaced by a rename, so it is either the old or the new one, never half of it
+
+        let snapshot_path = dir.join(UTXO_SNAPSHOT_FILE);
+
+        let snapshot: UtxoSnapshot = if snapshot_path.exists() {
+
+            decode(&fs::read(&snapshot_path)?)?
+
+        } else {
+
+            UtxoSnapshot {
+                sequence: 0,
+                tip: Hash::zero(),
+                utxos: vec![],
+            }
+        };
+
+        let mut utxos: HashMap<_, _> = snapshot.utxos.into_iter().collect();
+
+        let mut log = RecordFile::open(&dir.join(UTXO_LOG_FILE))?;
+
+        let mut sequence = snapshot.sequence;
+
+        let mut tip = snapshot.tip;
+
+        let mut batches = 0;
+
+        for record in log.read_all()? {
+
+            let batch: UtxoBatch = decode(&record)?;
+
+            // left over from a compaction that was interrupted after the snapshot was written
+
+            if batch.sequence <= sequence {
+
+                continue;
+            }
+
+            if batch.sequence != sequence + 1 {
+
+                return Err(IoError::new(IoErrorKind::InvalidData, "utxo log is missing batches"));
+            }
+
+            for outpoint in &batch.removed {
+
+                utxos.remove(outpoint);
+            }
+
+            utxos.extend(batch.added);
+
+            sequence = batch.sequence;
+
+            tip = batch.tip;
+
+            batches += 1;
+        }
+
+        let store = UtxoStore {
+            dir: Some(dir.to_path_buf()),
+            log,
+            sequence,
+            tip,
+            batches,
+        };
+
+        Ok((store, utxos))
+    }
+
+
+    // an empty store that lives in memory only
+
+    pub fn memory() -> Self {
+
+        UtxoStore {
+            dir: None,
+            log: RecordFile::memory(),
+            sequence: 0,
+            tip: Hash::zero(),
+            batches: 0,
+        }
+    }
+
+
+    // the block the stored utxo set belongs to, zero if no block was connected yet
+
+    pub fn tip(&self) -> Hash {
+
+        self.tip
+    }
+
+
+    // durably write the change one block makes, the outputs are removed before the new ones are added
+
+    pub fn commit(
+        &mut self,
+        tip: Hash,
+        removed: Vec<OutPoint>,
+        added: Vec<(OutPoint, TransactionOutput)>,
+
+    ) -> IoResult<()> {
+
+        let batch = UtxoBatch {
+            sequence: self.sequence + 1,
+            tip,
+            removed,
+            added,
+        };
+
+        self.log.append(&encode(&batch)?)?;
+
+        self.log.sync()?;
+
+        self.sequence = batch.sequence;
+
+        self.tip = tip;
+
+        self.batches += 1;
+
+        Ok(())
+    }
+
+
+    pub fn needs_compaction(&self) -> bool {
+
+        self.batches >= COMPACT_AFTER_BATCHES
+    }
+
+
+    // write the current set as a new snapshot and start over with an empty log
+
+    pub fn compact<'a>(
+        &mut self,
+        utxos: impl Iterator<Item = (&'a OutPoint, &'a TransactionOutput)>,
+
+    ) -> IoResult<()> {
+
+        if let Some(dir) = &self.dir {
+
+            let snapshot = UtxoSnapshot {
+                sequence: self.sequence,
+                tip: self.tip,
+                utxos: utxos.map(|(outpoint, output)| (*outpoint, output.clone())).collect(),
+            };
+
+            write_snapshot(dir, UTXO_SNAPSHOT_FILE, &snapshot)?;
+        }
+
+        // a crash before the log is emptied leaves batches the snapshot already contains, open skips those
+
+        self.log.truncate(0)?;
+
+        self.log.sync()?;
+
+        self.batches = 0;
+
+        Ok(())
+    }
+}
+
+
+// write a snapshot to dir through a temporary file that is renamed over the old one
+
+fn write_snapshot<T: Serialize>(dir: &Path, name: &str, snapshot: &T) -> IoResult<()> {
+
+    let tmp_path = dir.join(format!("{name}.tmp"));
+
+    let mut file = File::create(&tmp_path)?;
+
+    file.write_all(&encode(snapshot)?)?;
+
+    file.sync_all()?;
+
+    fs::rename(&tmp_path, dir.join(name))?;
+
+    // the rename itself is only durable once the directory is synced,
+    // which is not possible on every platform
+
+    if let Ok(dir) = File::open(dir) {
+
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+
+// where a transaction of the active chain is
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransactionLocation {
+
+    pub block: Hash,
+
+    // index in the transactions of the block
+    pub position: u32,
+}
+
+
+// a change to the coins of a public key: a transaction paid an output to it, or spent one of its outputs
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+
+    // the paying or spending transaction
+    pub txid: Hash,
+
+    pub block: Hash,
+
+    pub height: u64,
+
+    pub outpoint: OutPoint,
+
+    pub value: u64,
+
+    // whether the transaction spent the output rather than created it
+    pub spent: bool,
+}
+
+
+// the whole index as of batch `sequence`, written in one piece when the log is compacted
+
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot {
+
+    sequence: u64,
+
+    tip: Hash,
+
+    transactions: Vec<(Hash, TransactionLocation)>,
+
+    history: Vec<(PublicKey, Vec<HistoryEntry>)>,
+}
+
+
+// a batch as it is written to the log, numbered like the utxo batches
+
+#[derive(Serialize, Deserialize)]
+struct IndexRecord {
+
+    sequence: u64,
+
+    batch: IndexBatch,
+}
+
+
+// the change one connected or disconnected block makes to the index.
+// a disconnect lists what to take out again, so it does not need the block
+
+#[derive(Serialize, Deserialize)]
+enum IndexBatch {
+
+    Connect {
+
+        block: Hash,
+
+        transactions: Vec<Hash>,
+
+        history: Vec<(PublicKey, HistoryEntry)>,
+    },
+
+    Disconnect {
+
+        block: Hash,
+
+        // the tip of the active chain after this batch
+        tip: Hash,
+
+        transactions: Vec<Hash>,
+
+        keys: Vec<PublicKey>,
+    },
+}
+
+
+// txid -> location and public key -> history for the active chain, kept in memory
+// and rebuilt on open from a snapshot plus a log of the batches written since. the chain
+// connects and disconnects blocks here after the utxo set, so after a crash the index may be one block behind
+
+pub struct IndexStore {
+
+    // None for an index in memory, which never writes a snapshot
+    dir: Option<PathBuf>,
+
+    log: RecordFile,
+
+    sequence: u64,
+
+    // batches in the log since the last snapshot
+    batches: usize,
+
+    // the last block the index was written for, zero for an empty index
+    tip: Hash,
+
+    transactions: HashMap<Hash, TransactionLocation>,
+
+    history: BTreeMap<PublicKey, Vec<HistoryEntry>>,
+}
+
+
+impl IndexStore {
+
+    pub fn open<P: AsRef<Path>>(dir: P) -> IoResult<Self> {
+
+        let dir = dir.as_ref();
+
+        fs::create_dir_all(dir)?;
+
+        let snapshot_path = dir.join(CHAIN_INDEX_SNAPSHOT_FILE);
+
+        let snapshot: IndexSnapshot = if snapshot_path.exists() {
+
+            decode(&fs::read(&snapshot_path)?)?
+
+        } else {
+
+            IndexSnapshot {
+                sequence: 0,
+                tip: Hash::zero(),
+                transactions: vec![],
+                history: vec![],
+            }
+        };
+
+        let mut log = RecordFile::open(&dir.join(CHAIN_INDEX_FILE))?;
+
+        let records = log.read_all()?;
+
+        let mut index = IndexStore {
+            dir: Some(dir.to_path_buf()),
+            log,
+            sequence: snapshot.sequence,
+            batches: 0,
+            tip: snapshot.tip,
+            transactions: snapshot.transactions.into_iter().collect(),
+            history: snapshot.history.into_iter().collect(),
+        };
+
+        for record in records {
+
+            let record: IndexRecord = decode(&record)?;
+
+            // left over from a compaction that was interrupted after the snapshot was written
+
+            if record.sequence <= index.sequence {
+
+                continue;
+            }
+
+            if record.sequence != index.sequence + 1 {
+
+                return Err(IoError::new(IoErrorKind::InvalidData, "index log is missing batches"));
+            }
+
+            index.apply(record.batch);
+
+            index.sequence = record.sequence;
+
+            index.batches += 1;
+        }
+
+        Ok(index)
+    }
+
+
+    // an empty index that lives in memory only
+
+    pub fn memory() -> Self {
+
+        IndexStore {
+            dir: None,
+            log: RecordFile::memory(),
+            sequence: 0,
+            batches: 0,
+            tip: Hash::zero(),
+            transactions: HashMap::new(),
+            history: BTreeMap::new(),
+        }
+    }
+
+
+    pub fn tip(&self) -> Hash {
+
+        self.tip
+    }
+
+
+    pub fn transaction(&self, txid: &Hash) -> Option<TransactionLocation> {
+
+        self.transactions.get(txid).copied()
+    }
+
+
+    // oldest first
+
+    pub fn history(&self, pubkey: &PublicKey) -> &[HistoryEntry] {
+
+        self.history.get(pubkey).map(Vec::as_slice).unwrap_or_default()
+    }
+
+
+    // index a block that was connected at height, with the outputs it spent
+
+    pub fn connect(&mut self, block: &Block, height: u64, undo: &BlockUndo) -> IoResult<()> {
+
+        let hash = block.hash();
+
+        // the outputs an input can spend: what the block spent of the chain before it,
+        // and the outputs of earlier transactions in the block
+
+        let mut spendable: HashMap<_, _> = undo.spent_outputs.iter().cloned().collect();
+
+        let mut transactions = vec![];
+
+        let mut history = vec![];
+
+        for transaction in &block.transactions {
+
+            let txid = transaction.hash();
+
+            transactions.push(txid);
+
+            for input in &transaction.inputs {
+
+                let Some(output) = spendable.get(&input.prev_output) else {
+
+                    continue;
+                };
+
+                history.push((output.pubkey.clone(), HistoryEntry {
+                    txid,
+                    block: hash,
+                    height,
+                    outpoint: input.prev_output,
+                    value: output.value,
+                    spent: true,
+                }));
+            }
+
+            for (outpoint, output) in transaction.outpoints() {
+
+                history.push((output.pubkey.clone(), HistoryEntry {
+                    txid,
+                    block: hash,
+                    height,
+                    outpoint,
+                    value: output.value,
+                    spent: false,
+                }));
+
+                spendable.insert(outpoint, output.clone());
+            }
+        }
+
+        self.write(IndexBatch::Connect { block: hash, transactions, history })
+    }
+
+
+    // take a disconnected block out of the index again, tip is its parent
+
+    pub fn disconnect(&mut self, block: &Block, undo: &BlockUndo, tip: Hash) -> IoResult<()> {
+
+        let transactions = block.transactions.iter()
+            .map(|transaction| transaction.hash())
+            .collect();
+
+        let keys = block.transactions.iter()
+            .flat_map(|transaction| &transaction.outputs)
+            .chain(undo.spent_outputs.iter().map(|(_, output)| output))
+            .map(|output| output.pubkey.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        self.write(IndexBatch::Disconnect { block: block.hash(), tip, transactions, keys })
+    }
+
+
+    // forget everything, for an index that has to be built again
+
+    pub fn clear(&mut self) -> IoResult<()> {
+
+        self.tip = Hash::zero();
+
+        self.transactions.clear();
+
+        self.history.clear();
+
+        // an empty snapshot, so a crash halfway does not bring the old batches back
+
+        self.compact()
+    }
+
+
+    fn write(&mut self, batch: IndexBatch) -> IoResult<()> {
+
+        let record = IndexRecord { sequence: self.sequence + 1, batch };
+
+        self.log.append(&encode(&record)?)?;
+
+        self.log.sync()?;
+
+        self.apply(record.batch);
+
+        self.sequence = record.sequence;
+
+        self.batches += 1;
+
+        if self.batches >= COMPACT_AFTER_BATCHES {
+
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+
+    // write the index as a new snapshot and start over with an empty log
+
+    fn compact(&mut self) -> IoResult<()> {
+
+        if let Some(dir) = &self.dir {
+
+            let snapshot = IndexSnapshot {
+                sequence: self.sequence,
+                tip: self.tip,
+                transactions: self.transactions.iter().map(|(txid, location)| (*txid, *location)).collect(),
+                history: self.history.iter().map(|(pubkey, history)| (pubkey.clone(), history.clone())).collect(),
+            };
+
+            write_snapshot(dir, CHAIN_INDEX_SNAPSHOT_FILE, &snapshot)?;
+        }
+
+        // a crash before the log is emptied leaves batches the snapshot already contains, open skips those
+
+        self.log.truncate(0)?;
+
+        self.log.sync()?;
+
+        self.batches = 0;
+
+        Ok(())
+    }
+
+
+    fn apply(&mut self, batch: IndexBatch) {
+
+        match batch {
+
+            IndexBatch::Connect { block, transactions, history } => {
+
+                for (position, txid) in transactions.into_iter().enumerate() {
+
+                    self.transactions.insert(txid, TransactionLocation { block, position: position as u32 });
+                }
+
+                for (pubkey, entry) in history {
+
+                    self.history.entry(pubkey).or_default().push(entry);
+                }
+
+                self.tip = block;
+            }
+
+            IndexBatch::Disconnect { block, tip, transactions, keys } => {
+
+                for txid in transactions {
+
+                    if self.transactions.get(&txid).is_some_and(|location| location.block == block) {
+
+                        self.transactions.remove(&txid);
+                    }
+                }
+
+                for pubkey in keys {
+
+                    if let Some(history) = self.history.get_mut(&pubkey) {
+
+                        history.retain(|entry| entry.block != block);
+
+                        if history.is_empty() {
+
+                            self.history.remove(&pubkey);
+                        }
+                    }
+                }
+
+                self.tip = tip;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::params::ChainParams;
+    use uuid::Uuid;
+
+
+    #[test]
+    fn index_survives_compaction() {
+
+        let dir = std::env::temp_dir().join(format!("index-{}", Uuid::new_v4()));
+
+        let genesis = ChainParams::regtest().genesis_block;
+
+        let txid = genesis.transactions[0].hash();
+
+        let mut index = IndexStore::open(&dir).unwrap();
+
+        index.connect(&genesis, 0, &BlockUndo::default()).unwrap();
+
+        index.compact().unwrap();
+
+        // what comes after the snapshot is replayed from the log on top of it
+
+        index.disconnect(&genesis, &BlockUndo::default(), Hash::zero()).unwrap();
+
+        index.connect(&genesis, 0, &BlockUndo::default()).unwrap();
+
+        let mut index = IndexStore::open(&dir).unwrap();
+
+        assert_eq!(index.tip(), genesis.hash());
+
+        assert_eq!(index.transaction(&txid), Some(TransactionLocation { block: genesis.hash(), position: 0 }));
+
+        assert_eq!(index.history(&genesis.transactions[0].outputs[0].pubkey).len(), 1);
+
+        // cleared for good, the snapshot as well
+
+        index.clear().unwrap();
+
+        let index = IndexStore::open(&dir).unwrap();
+
+        assert_eq!(index.tip(), Hash::zero());
+
+        assert_eq!(index.transaction(&txid), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    fn temp_dir(name: &str) -> PathBuf {
+
+        std::env::temp_dir().join(format!("{name}-{}", Uuid::new_v4()))
+    }
+
+
+    // what a crash in the middle of a write leaves behind: the last record of the file cut short
+
+    fn tear(path: &Path, bytes: u64) {
+
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+
+        let len = file.metadata().unwrap().len();
+
+        file.set_len(len - bytes).unwrap();
+    }
+
+
+    #[test]
+    fn block_store_drops_a_torn_tail() {
+
+        let dir = temp_dir("blocks");
+
+        let first = ChainParams::regtest().genesis_block;
+
+        let second = ChainParams::testnet().genesis_block;
+
+        let mut store = BlockStore::open(&dir).unwrap();
+
+        store.put_block(&first).unwrap();
+
+        let blocks_len = fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len();
+
+        store.put_block(&second).unwrap();
+
+        // the index record of the second block is torn, its data in the block file is no longer pointed to
+
+        tear(&dir.join(INDEX_FILE), 3);
+
+        let mut store = BlockStore::open(&dir).unwrap();
+
+        assert_eq!(store.block(&first.hash()).unwrap().map(|block| block.hash()), Some(first.hash()));
+
+        assert!(!store.contains(&second.hash()));
+
+        assert_eq!(fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len(), blocks_len);
+
+        // written again it lands where the torn one was
+
+        store.put_block(&second).unwrap();
+
+        // a block torn before it was indexed is dropped as well
+
+        OpenOptions::new().append(true).open(dir.join(BLOCKS_FILE)).unwrap().write_all(&[1, 2, 3]).unwrap();
+
+        let store = BlockStore::open(&dir).unwrap();
+
+        assert_eq!(store.block(&first.hash()).unwrap().map(|block| block.hash()), Some(first.hash()));
+
+        assert_eq!(store.block(&second.hash()).unwrap().map(|block| block.hash()), Some(second.hash()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn utxo_log_drops_a_torn_batch() {
+
+        let dir = temp_dir("utxos");
+
+        let first = ChainParams::regtest().genesis_block;
+
+        let second = ChainParams::testnet().genesis_block;
+
+        let outputs = |block: &Block| block.transactions[0].outpoints().map(|(outpoint, output)| (outpoint, output.clone())).collect::<Vec<_>>();
+
+        let (mut store, _) = UtxoStore::open(&dir).unwrap();
+
+        store.commit(first.hash(), vec![], outputs(&first)).unwrap();
+
+        store.commit(second.hash(), vec![], outputs(&second)).unwrap();
+
+        // the second batch is torn, it is left out as a whole
+
+        tear(&dir.join(UTXO_LOG_FILE), 5);
+
+        let (mut store, utxos) = UtxoStore::open(&dir).unwrap();
+
+        assert_eq!(store.tip(), first.hash());
+
+        assert_eq!(utxos.keys().collect::<HashSet<_>>(), outputs(&first).iter().map(|(outpoint, _)| outpoint).collect());
+
+        // and the log carries on from the first batch
+
+        store.commit(second.hash(), vec![], outputs(&second)).unwrap();
+
+        let (store, utxos) = UtxoStore::open(&dir).unwrap();
+
+        assert_eq!(store.tip(), second.hash());
+
+        assert_eq!(utxos.len(), outputs(&first).len() + outputs(&second).len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
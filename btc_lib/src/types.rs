@@ -5,10 +5,10 @@ mod transaction;
 
 pub use block::{ Block, BlockHeader};
 
-pub use blockchain::Blockchain;
+pub use blockchain::{BlockUndo, Blockchain, ChainUpdate};
 pub use transaction:: {
 
-    Transaction, TransactionInput, TransactionOutput,
+    OutPoint, SigHashType, Transaction, TransactionInput, TransactionOutput, SEQUENCE_FINAL, SEQUENCE_REPLACEABLE,
 };
 
 
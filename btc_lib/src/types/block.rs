@@ -1,11 +1,13 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use super::{Transaction, TransactionOutput};
+use super::{OutPoint, Transaction, TransactionOutput};
 use crate::error::{BtcError, Result};
-use crate::sha256::Hash;
+use crate::params::ChainParams;
+use crate::encoding::{ConsensusEncode, CONSENSUS_ENCODING_VERSION};
+use crate::sha256::{Hash, PrefixHasher};
 use crate::util::MerkleRoot;
 use crate::U256;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::util::Saveable;
 use std::io::{
 Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write,
@@ -52,21 +54,36 @@ impl Block {
 
         Block{
             
-            header: header,
-            transactions: transactions,
+            header,
+            transactions,
         }
     }
 
+    // a block is identified by the hash of its header, which commits to the transactions via the merkle root
+
     pub fn hash(&self) -> Hash {
 
-       Hash::hash(self)
+       self.header.hash()
         
     }
 
-    pub fn verify_transactions(&self, predicted_block_height: u64, utxos: &HashMap<Hash,(bool, TransactionOutput)>) -> Result<()> {
+    // bytes of the consensus encoding: the header and the list of transactions
+
+    pub fn size(&self) -> usize {
+
+        BlockHeader::SIZE + 4 + self.transactions.iter().map(Transaction::size).sum::<usize>()
+    }
+
+
+    // a transaction may spend the outputs of the transactions before it in the block, except the coinbase,
+    // so unconfirmed chains can be mined in one go
+
+    pub fn verify_transactions(&self, predicted_block_height: u64, utxos: &HashMap<OutPoint, (bool, TransactionOutput)>, params: &ChainParams) -> Result<()> {
 
 
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        let mut inputs: HashSet<OutPoint> = HashSet::new();
+
+        let mut created: HashMap<OutPoint, &TransactionOutput> = HashMap::new();
 
         // reject the completely empty blocks
 
@@ -77,16 +94,18 @@ impl Block {
 
         // verify coinbase transaction 
 
-        self.verify_coinbase_transaction(predicted_block_height, utxos);
+        self.verify_coinbase_transaction(predicted_block_height, utxos, params)?;
 
         for transaction in self.transactions.iter().skip(1) {
 
             let mut input_value = 0;
             let mut output_value = 0;
 
-            for input in &transaction.inputs {
+            for (index, input) in transaction.inputs.iter().enumerate() {
 
-                let prev_output = utxos.get(&input.prev_transaction_output_hash).map(|(_, output) | output);
+                let prev_output = utxos.get(&input.prev_output)
+                    .map(|(_, output) | output)
+                    .or_else(|| created.get(&input.prev_output).copied());
 
                 if prev_output.is_none() {
                     
@@ -98,32 +117,34 @@ impl Block {
 
                 // prevent same-block double spending
 
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
+                if inputs.contains(&input.prev_output) {
 
                     return Err(BtcError::InvalidTransaction);
                 }
 
-                // check if the signature is valid
+                // check if the signature is valid for this transaction
 
-                if !input.signature.verify(&input.prev_transaction_output_hash, &prev_output.pubkey) {
+                if !transaction.verify_input(index, prev_output) {
 
                     return Err(BtcError::InvalidSignature);
                 }
 
-                input_value += prev_output.value;
+                input_value = checked_sum(input_value, prev_output.value)?;
 
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
+                inputs.insert(input.prev_output);
             }
 
             for output in &transaction.outputs {
 
-                output_value += output.value;
+                output_value = checked_sum(output_value, output.value)?;
             }
 
             if input_value < output_value {
 
                 return Err(BtcError::InvalidTransaction);
             }
+
+            created.extend(transaction.outpoints());
         }
 
         Ok(())
@@ -132,31 +153,29 @@ impl Block {
     }
 
     // verify coinbase transaction 
-    pub fn verify_coinbase_transaction(&self, predicted_block_height: u64, utxos: &HashMap<Hash, (bool, TransactionOutput)>) -> Result<()> {
+    pub fn verify_coinbase_transaction(&self, predicted_block_height: u64, utxos: &HashMap<OutPoint, (bool, TransactionOutput)>, params: &ChainParams) -> Result<()> {
 
         // coinbase is the first transaction in the block 
 
         let coinbase_transaction = &self.transactions[0];
 
-        if coinbase_transaction.inputs.len() != 0 {
+        if !coinbase_transaction.inputs.is_empty() {
 
             return Err(BtcError::InvalidTransaction);
         }
 
-        if coinbase_transaction.outputs.len() == 0 {
+        if coinbase_transaction.outputs.is_empty() {
 
             return Err(BtcError::InvalidTransaction);
         }
 
         let miner_fees = self.calculate_miner_fees(utxos)?;
 
-        let block_reward = crate::INITIAL_REWARD 
-            * 10u64.pow(8) 
-            / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL)  as u32);    // here its 210 but actually its 210, 000
+        let block_reward = params.block_reward(predicted_block_height);
 
-        let total_coinbase_outputs: u64 = coinbase_transaction.outputs.iter().map(|output| output.value).sum();
+        let total_coinbase_outputs = coinbase_transaction.outputs.iter().try_fold(0, |sum, output| checked_sum(sum, output.value))?;
 
-        if total_coinbase_outputs != block_reward + miner_fees {
+        if total_coinbase_outputs != checked_sum(block_reward, miner_fees)? {
 
             return Err(BtcError::InvalidTransaction);
         }
@@ -166,12 +185,20 @@ impl Block {
     }
 
 
-   pub fn calculate_miner_fees(&self, utxos: &HashMap<Hash, (bool, TransactionOutput) >)-> Result<u64> {
+   pub fn calculate_miner_fees(&self, utxos: &HashMap<OutPoint, (bool, TransactionOutput)>)-> Result<u64> {
+
+
+        let mut  inputs: HashSet<OutPoint> = HashSet::new();
+
+        // summed as they come, two outputs can be the same and still both count
 
+        let mut input_value: u64 = 0;
 
-        let mut  inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        let mut output_value: u64 = 0;
 
-        let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        // outputs of earlier transactions in the block, which later ones may spend
+
+        let mut created: HashMap<OutPoint, &TransactionOutput> = HashMap::new();
 
         // check every transaction after coinbase
 
@@ -181,7 +208,9 @@ impl Block {
 
                 // input does not contain the values of outputs , so we need to match the inputs to outputs
 
-                let prev_output = utxos.get(&input.prev_transaction_output_hash).map(|(_, output)| output);
+                let prev_output = utxos.get(&input.prev_output)
+                    .map(|(_, output)| output)
+                    .or_else(|| created.get(&input.prev_output).copied());
 
                 if prev_output.is_none() {
 
@@ -190,38 +219,25 @@ impl Block {
 
                 let prev_output = prev_output.unwrap();
 
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
+                if !inputs.insert(input.prev_output) {
 
                     return Err(BtcError::InvalidTransaction)
                 }
 
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
+                input_value = checked_sum(input_value, prev_output.value)?;
             }
 
             for output in &transaction.outputs {
 
-            
-                if outputs.contains_key(&output.hash()) {
-
-                        return Err(BtcError::InvalidTransaction);
-                    }
-            
-
-                outputs.insert(output.hash(), output.clone());
-
-
+                output_value = checked_sum(output_value, output.value)?;
             }
 
-          
-
-
-            
+            created.extend(transaction.outpoints());
         }
 
-        let input_value: u64 = inputs.values().map(|output| output.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
+        // spending more than the inputs is caught per transaction as well, this is only never negative
 
-        Ok(input_value - output_value)
+        input_value.checked_sub(output_value).ok_or(BtcError::InvalidTransaction)
 
 
    }
@@ -261,17 +277,59 @@ impl BlockHeader {
     }
 
 
+    // size of the fixed header layout, see crate::encoding
+
+    pub const SIZE: usize = 117;
+
+    // the nonce is the last field of the layout
+
+    pub const NONCE_OFFSET: usize = Self::SIZE - 8;
+
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+
+        let mut out = Vec::with_capacity(Self::SIZE);
+
+        out.push(CONSENSUS_ENCODING_VERSION);
+        out.extend_from_slice(&self.timestamp.timestamp().to_le_bytes());
+        self.timestamp.timestamp_subsec_nanos().consensus_encode(&mut out);
+        self.prev_block_hash.consensus_encode(&mut out);
+        self.merkle_root.consensus_encode(&mut out);
+        self.target.consensus_encode(&mut out);
+        self.nonce.consensus_encode(&mut out);
+
+        out.try_into().expect("bug: header layout has the wrong size")
+    }
+
+
     pub fn hash(&self) -> Hash {
 
-        Hash::hash(self)
+        Hash::hash_bytes(&self.to_bytes())
          
     }
 
+    // expected number of hashes needed to find a block at this target
+    // the chain with the most cumulative work wins
+
+    pub fn work(&self) -> U256 {
+
+        // 2^256 / (target + 1), written so it does not overflow U256
+
+        (!self.target / (self.target + U256::one())) + U256::one()
+    }
+
     pub fn mine(&mut self, steps: usize) -> bool {
 
+        // everything before the nonce stays the same between attempts,
+        // so it is serialized and hashed up front and only the nonce bytes change
+
+        let mut bytes = self.to_bytes();
+
+        let mut hasher = PrefixHasher::new(&bytes[..Self::NONCE_OFFSET]);
+
         // if the block already matches target, return early
 
-        if self.hash().matches_target(self.target) {
+        if hasher.hash(&bytes[Self::NONCE_OFFSET..]).matches_target(self.target) {
 
             return true;
         }
@@ -284,11 +342,18 @@ impl BlockHeader {
 
             } else {
                 
+                // the nonce space is exhausted, a new timestamp changes the prefix
                 self.nonce  = 0;
                 self.timestamp = Utc::now();
+
+                bytes = self.to_bytes();
+
+                hasher = PrefixHasher::new(&bytes[..Self::NONCE_OFFSET]);
             }
 
-            if self.hash().matches_target(self.target) {
+            bytes[Self::NONCE_OFFSET..].copy_from_slice(&self.nonce.to_le_bytes());
+
+            if hasher.hash(&bytes[Self::NONCE_OFFSET..]).matches_target(self.target) {
 
                 return true;
             }
@@ -300,4 +365,12 @@ impl BlockHeader {
     
 
 
-}
\ No newline at end of file
+}
+
+
+// values that add up to more than there can ever be make the transaction invalid
+
+fn checked_sum(sum: u64, value: u64) -> Result<u64> {
+
+    sum.checked_add(value).ok_or(BtcError::InvalidTransaction)
+}
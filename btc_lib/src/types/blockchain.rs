@@ -1,557 +1,1990 @@
 use bigdecimal::BigDecimal;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use super::{Block, Transaction, TransactionOutput};
+use super::{Block, BlockHeader, OutPoint, Transaction, TransactionOutput};
+use crate::crypto::PublicKey;
 use crate::error::{BtcError, Result};
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::params::ChainParams;
 use crate::sha256::Hash;
-use crate::util::MerkleRoot;
+use crate::store::{BlockStore, HistoryEntry, IndexStore, UtxoStore};
+use crate::util::{MerkleProof, MerkleRoot};
 use crate::U256;
 use std::collections::{HashMap, HashSet};
-use crate::util::Saveable;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::iter;
+use std::path::Path;
 
-use std::io:: {
 
-    Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write,
+// a block has to be later than the median timestamp of this many blocks before it,
+// so a few blocks with wrong clocks can neither stall the chain nor move its time back
 
-};
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+// and no further ahead of the network-adjusted time than this, in seconds
+
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+
+
+// the outputs a block spent when it was connected, in the order it spent them.
+// with these a block can be disconnected again without replaying the chain
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BlockUndo {
+
+    pub spent_outputs: Vec<(OutPoint, TransactionOutput)>,
+}
+
+
+// how the active chain moved since it was last asked. a block that was connected and
+// disconnected again in between, like on a failed reorg, shows up in neither list
+
+#[derive(Clone, Debug, Default)]
+pub struct ChainUpdate {
+
+    // blocks taken off the active chain, tip first
+    pub disconnected: Vec<Hash>,
+
+    // blocks added to the active chain, oldest first
+    pub connected: Vec<Hash>,
+}
 
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
 
+    // the network this chain belongs to
+
+    params: ChainParams,
+
+    // every known block, on the active chain and on side branches, with the undo data of connected blocks
+
+    store: BlockStore,
 
-    //HashMap, with the Hash of the transaction output being used as
+    // persistent copy of the utxo set, updated with one batch per connected or disconnected block
+
+    utxo_store: UtxoStore,
+
+    //HashMap, with the outpoint (txid, vout) of the transaction output being used as
    // the key type:
 
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
+    utxos: HashMap<OutPoint, (bool, TransactionOutput)>,
+
+    // headers of the active chain, from genesis to the tip with the most work.
+    // the blocks themselves are read from the store when needed
+
+    chain: Vec<BlockHeader>,
 
-    blocks: Vec<Block>,
+    // hashes of the stored blocks on branches other than the active chain
+
+    side_blocks: HashSet<Hash>,
+
+    // hash -> height of every block on the active chain
+
+    block_index: HashMap<Hash, usize>,
 
     target: U256,
 
-    #[serde(default, skip_serializing)]
-    mempool: Vec<(DateTime<Utc>, Transaction)>,
+    mempool: Mempool,
+
+    // transactions and public keys of the active chain, None unless enabled
+
+    index: Option<IndexStore>,
+
+    update: ChainUpdate,
+
+    // how far the network-adjusted time is off our clock
+    time_offset: Duration,
 }
 
 
-impl Saveable for Blockchain {
 
 
-    fn load<I: Read>(reader: I) -> IoResult<Self> {
+impl Blockchain  {
+
+    // a new chain holding only the genesis block of the network, kept in memory
+
+    pub fn new(params: ChainParams) -> Self {
 
-        ciborium::de::from_reader(reader).map_err(|_| {
+        Self::with_stores(params, BlockStore::memory(), UtxoStore::memory(), HashMap::new())
+            .expect("bug: invalid genesis block")
+    }
 
-            IoError::new(IoErrorKind::InvalidData, "failed to deserialize the blockchain")
-        })
-    } 
 
+    // open the chain stored in dir, starting a new one if there is none yet.
+    // only the block headers and the utxo set are read, the blocks stay on disk
 
-    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+    pub fn open<P: AsRef<Path>>(params: ChainParams, dir: P) -> Result<Self> {
 
+        let store = BlockStore::open(&dir)?;
 
-        ciborium::ser::into_writer(self, writer).map_err(|_| {
+        let (utxo_store, utxos) = UtxoStore::open(&dir)?;
 
-            IoError::new(IoErrorKind::InvalidData, "failed to serialize blockchain")
-        })
+        Self::with_stores(params, store, utxo_store, utxos)
     }
-}
 
 
+    fn with_stores(
+        params: ChainParams,
+        store: BlockStore,
+        utxo_store: UtxoStore,
+        utxos: HashMap<OutPoint, TransactionOutput>,
 
+    ) -> Result<Self> {
 
-impl Blockchain  {
+        let genesis_block = params.genesis_block.clone();
 
-    pub fn new() -> Self {
+        // the stored blocks have to belong to the network we were started for
 
-        Blockchain{
+        if !store.is_empty() && !store.contains(&genesis_block.hash()) {
 
-            utxos: HashMap::new(),
-            
-            blocks: vec![],
+            return Err(stored_chain_error(format!("the stored chain does not belong to {}", params.network)));
+        }
 
-            target: crate::MIN_TARGET,
+        // the active chain is the one the utxo set was last written for, walk it back from that tip
 
-            mempool: vec![],
-            
-            }
-    }
+        let mut chain = vec![];
 
+        let mut cursor = utxo_store.tip();
 
-    // Rebuild Utxo set from the block chain 
+        while cursor != Hash::zero() {
 
-    pub fn rebuild_utxos(&mut self) {
+            let Some(header) = store.header(&cursor) else {
 
+                return Err(stored_chain_error(format!("utxo set refers to unknown block {cursor}")));
+            };
 
-        for block in &self.blocks {
+            cursor = header.prev_block_hash;
 
-            for transaction in &block.transactions {
+            chain.push(header.clone());
+        }
 
-                for input in &transaction.inputs {
+        chain.reverse();
 
+        let target = params.min_target;
 
-                    self.utxos.remove(&input.prev_transaction_output_hash);
-                }
+        let mut blockchain = Blockchain{
 
-                for output in  transaction.outputs.iter() {
+            params,
 
-                    self.utxos.insert(transaction.hash(), (false, output.clone()));
-                }
-            }
+            store,
+
+            utxo_store,
+
+            // the mempool is not saved, so no utxo can be marked by it
+
+            utxos: utxos.into_iter().map(|(outpoint, output)| (outpoint, (false, output))).collect(),
+
+            chain,
+
+            side_blocks: HashSet::new(),
+
+            block_index: HashMap::new(),
+
+            target,
+
+            mempool: Mempool::default(),
+
+            index: None,
+
+            update: ChainUpdate::default(),
+
+            time_offset: Duration::zero(),
+
+            };
+
+        blockchain.rebuild_block_index();
+
+        blockchain.rebuild_target();
+
+        if blockchain.chain.is_empty() {
+
+            blockchain.connect_block(genesis_block)?;
         }
+
+        blockchain.side_blocks = blockchain.store.headers()
+            .map(|(hash, _)| *hash)
+            .filter(|hash| !blockchain.block_index.contains_key(hash) && !blockchain.store.is_invalid(hash))
+            .collect();
+
+        blockchain.resume_best_branch();
+
+        // opening the chain is not news to anyone
+
+        blockchain.update = ChainUpdate::default();
+
+        Ok(blockchain)
     }
 
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
 
-        // check if the block is valid
+    // keep an index of the transactions and public keys of the active chain in dir.
+    // an index that fell behind the chain catches up, one that went off on another branch is built again
 
-        if self.blocks.is_empty() {
+    pub fn enable_index<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
 
-            // if this is the first block, check if the block's prev hash is all zeros
+        let mut index = IndexStore::open(dir)?;
 
-            if block.header.prev_block_hash != Hash::zero() {
+        let start = if index.tip() == Hash::zero() {
 
-                println!("zero hash");
-                return Err(BtcError::InvalidBlock);
-            }
+            0
+
+        } else if let Some(height) = self.block_index.get(&index.tip()) {
+
+            height + 1
 
         } else {
 
-            // if this is not the first block , check if the block's prev_block_hash is the hash of the last block
+            println!("index does not match the active chain, building it again");
 
-            let last_block = self.blocks.last().unwrap();
+            index.clear()?;
 
-            if block.header.prev_block_hash != last_block.hash() {
+            0
+        };
 
-                println!("prev hash is wrong");
-                return Err(BtcError::InvalidBlock);
-            }
+        if start < self.chain.len() {
 
-            // check if the block's hash is less than the target
+            println!("indexing {} blocks...", self.chain.len() - start);
+        }
 
-            if !block.header.hash().matches_target(block.header.target) {
+        for height in start..self.chain.len() {
 
-                println!("does not match the target");
-                return Err(BtcError::InvalidBlock);
-            }
+            let hash = self.chain[height].hash();
+
+            let block = self.stored_block(&hash)?;
 
-            // check if the block's merkle root is correct
+            let Some(undo) = self.store.undo(&hash)? else {
 
-            let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
+                return Err(stored_chain_error(format!("no undo data for block {hash}")));
+            };
 
-            if calculated_merkle_root != block.header.merkle_root {
+            index.connect(&block, height as u64, &undo)?;
+        }
 
+        self.index = Some(index);
 
-                println!("invalid merkle root");
-                return Err(BtcError::InvalidMerkleRoot);
-            }
+        Ok(())
+    }
 
-            // check if the block's timestamp is after the last block's timestamp 
-            if block.header.timestamp <= last_block.header.timestamp {
 
-                return Err(BtcError::InvalidBlock);
-            }
+    pub fn has_index(&self) -> bool {
 
-            // verify the all the transaction in the block
+        self.index.is_some()
+    }
 
-            block.verify_transactions(self.blocks_height(), &self.utxos)?;
 
-            
-            
+    // the blocks connected and disconnected since the last call
+
+    pub fn take_update(&mut self) -> ChainUpdate {
+
+        std::mem::take(&mut self.update)
+    }
 
-        }
 
-        // Remove the transaction from mempool that are now in the block
+    // an interrupted reorg, or a utxo set that was deleted, leaves a branch with more work
+    // among the side blocks. try every branch tip so the chain ends up on the best one again
 
-        let block_transactions: HashSet<_> = block.transactions.iter()
-            .map(|tx| tx.hash())
+    fn resume_best_branch(&mut self) {
+
+        let parents: HashSet<Hash> = self.side_blocks.iter()
+            .filter_map(|hash| self.store.header(hash))
+            .map(|header| header.prev_block_hash)
             .collect();
 
-        self.mempool.retain(|(_, tx)| {
+        let tips: Vec<Hash> = self.side_blocks.iter()
+            .filter(|hash| !parents.contains(hash))
+            .copied()
+            .collect();
 
-            !block_transactions.contains(&tx.hash())
+        for tip in tips {
 
-        });
+            // an earlier reorg may have dropped this branch already
 
-        self.blocks.push(block);
+            if !self.side_blocks.contains(&tip) {
 
-        self.try_adjust_target();
+                continue;
+            }
 
-        Ok(())
+            if let Err(e) = self.try_reorganize(tip) {
+
+                println!("stored branch ending in {tip} rejected: {e}");
+            }
+        }
     }
 
 
-    pub fn try_adjust_target(&mut self) {
+    // the outputs a block spends, taken from the utxo set before the block is applied
 
-        if self.blocks.is_empty() {
+    fn spent_outputs(&self, block: &Block) -> BlockUndo {
 
-            return;
-        }
+        let spent_outputs = block.transactions
+            .iter()
+            .flat_map(|transaction| &transaction.inputs)
+            .filter_map(|input| {
 
-        if self.blocks.len() % crate::DIFFICULTY_UPDATE_INTERVAL as usize != 0 {
+                self.utxos.get(&input.prev_output).map(|(_, output)| (input.prev_output, output.clone()))
+            })
+            .collect();
 
-            return;
-        }
+        BlockUndo { spent_outputs }
+    }
 
-        // measure the time it took mine the last blocks
 
-        let start_time = self.blocks[self.blocks.len() - crate::DIFFICULTY_UPDATE_INTERVAL as usize].header.timestamp;
+    // spend the inputs and create the outputs of a block
 
-        let end_time = self.blocks.last().unwrap().header.timestamp;
+    fn apply_block(utxos: &mut HashMap<OutPoint, (bool, TransactionOutput)>, block: &Block) {
 
-        let time_diff = end_time - start_time;
+        for transaction in &block.transactions {
 
-        // convert time_diff to seconds
+            for input in &transaction.inputs {
 
-        let time_diff_seconds = time_diff.num_seconds();
+                utxos.remove(&input.prev_output);
+            }
 
-        // calculate the ideal number of seconds
+            for (outpoint, output) in transaction.outpoints() {
 
-        let target_seconds =  crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL;
+                utxos.insert(outpoint, (false, output.clone()));
+            }
+        }
+    }
 
-        // let new_target = self.target * (time_diff_seconds as f64 / target_seconds as f64) as usize;
 
-        let new_target = BigDecimal::parse_bytes(&self.target.to_string().as_bytes(), 10)
-            .expect("bug")
-                * (BigDecimal::from(time_diff_seconds)  
-                    /  BigDecimal::from(target_seconds));
+    // read a block that has to be in the store
 
-            // cut off decimal point and everything after it from the string representation
+    fn stored_block(&self, hash: &Hash) -> Result<Block> {
 
-        let new_target_str = new_target.to_string().split('.').next().expect("bug expected a decimal point").to_owned();
+        self.store.block(hash)?.ok_or_else(|| stored_chain_error(format!("block {hash} is missing from the store")))
+    }
 
-        let new_target: U256 = U256::from_str_radix(&new_target_str, 10).expect("bug");
 
+    // remove the tip of the active chain, restoring the outputs it spent.
+    // the block stays in the store
 
-        // clamp new_target to within range of 4 * self.target and self.target / 4
-       // we can multiply or divide either by 1, 2, 3, 4
+    fn disconnect_tip(&mut self) -> Result<Option<Block>> {
 
-        let new_target  = if new_target < self.target / 4 {
+        let Some(header) = self.chain.last() else {
 
-            self.target / 4
+            return Ok(None);
+        };
 
-        } else if new_target > self.target * 4  {
+        let hash = header.hash();
 
-            self.target * 4
+        let prev_block_hash = header.prev_block_hash;
 
+        let block = self.stored_block(&hash)?;
 
-        } else {
+        let Some(undo) = self.store.undo(&hash)? else {
 
-            new_target
+            return Err(stored_chain_error(format!("no undo data for block {hash}")));
         };
 
+        let created: Vec<OutPoint> = block.transactions
+            .iter()
+            .flat_map(|transaction| transaction.outpoints().map(|(outpoint, _)| outpoint))
+            .collect();
 
-        // if the new_target is more than the minimum target 
-        // set it to the minimm target
+        self.utxo_store.commit(prev_block_hash, created.clone(), undo.spent_outputs.clone())?;
 
-        self.target = new_target.min(crate::MIN_TARGET);
+        // undo in reverse order: first drop what the block created, then bring back what it spent
 
+        for outpoint in &created {
 
+            self.utxos.remove(outpoint);
+        }
 
-    }
+        for (outpoint, output) in undo.spent_outputs.iter().rev() {
 
+            self.utxos.insert(*outpoint, (false, output.clone()));
+        }
 
+        self.chain.pop();
 
+        self.block_index.remove(&hash);
 
-    pub fn blocks_height(&self) -> u64 {
-        
-        self.blocks.len() as u64
-    }
-    
+        match self.update.connected.iter().position(|connected| *connected == hash) {
 
-    // utxo's
+            Some(position) => {
 
-    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
+                self.update.connected.remove(position);
+            }
 
-        &self.utxos
-    }
+            None => self.update.disconnected.push(hash),
+        }
 
-    // target
-    pub fn target(&self) -> U256 {
+        // the block is off the chain once the utxo set is written, whatever fails after that
 
-        self.target
-    }
+        if let Some(index) = &mut self.index {
 
-    // blocks
+            index.disconnect(&block, &undo, prev_block_hash)?;
+        }
 
-    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.compact_utxo_store()?;
 
-        self.blocks.iter()
+        Ok(Some(block))
     }
 
 
-    // mempool
+    // fold the utxo log into a new snapshot once it has grown long enough
 
-    pub fn mempool (&self) -> &[(DateTime<Utc>, Transaction)] {
+    fn compact_utxo_store(&mut self) -> Result<()> {
 
-        // later we also need to track of time
+        if self.utxo_store.needs_compaction() {
 
-        &self.mempool
+            println!("compacting the utxo database...");
+
+            self.utxo_store.compact(self.utxos.iter().map(|(outpoint, (_, output))| (outpoint, output)))?;
+        }
+
+        Ok(())
     }
 
 
-    // now we need to teach blockchain to receive and add transaction to mempool
+    // rebuild the hash -> height index of the active chain
 
-    pub fn add_to_mempool(&mut self, transactions: Transaction) -> Result<()> {
+    pub fn rebuild_block_index(&mut self) {
+
+        self.block_index = self.chain
+            .iter()
+            .enumerate()
+            .map(|(height, header)| (header.hash(), height))
+            .collect();
+    }
 
 
-        // output should be less or equal to inputs
-        // all inputs must have known UTXO
-        // all inputs must be unique(no double spending)
+    // replay the difficulty adjustments from genesis
+    // needed after the active chain has been loaded or cut back during a reorg
 
-    //         There is yet another security issue we must take care of: So far, it is still possible
-    // to add multiple transactions to the mempool that reference the same unspent
-    // transaction outputs. Furthermore, transactions in the mempool will stay stuck
-    // there until the node is restarted (at which point, other nodes would share the same
-    // transactions with the node again).
-    // Therefore we need a mechanism to detect this type of double-spending, to discard
-    // old unprocessed transactions, and to replace transactions with newer transactions
-    // that reference the same inputs (which will prevent a potential double-spending
-    // problem). To do this, we will need to make two adjustments:
-    // ● Track the time when a particular transaction was inserted into the mem-
-    // pool, and dump it if it has been there for too long.
-    // ● Mark UTXOs that are being referenced by a transaction in mempool, and
-    // find and remove the old transaction that marks those UTXOs.
+    fn rebuild_target(&mut self) {
 
+        let chain = std::mem::take(&mut self.chain);
 
-        // validate transaction before insertion
-        // all inputs must match known UTXO's and must be unique
+        self.target = self.params.min_target;
 
-        let mut known_inputs = HashSet::new();
+        for header in chain {
 
-        for input in &transactions.inputs {
+            self.chain.push(header);
 
-            if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
+            self.try_adjust_target();
+        }
+    }
 
-                return Err(BtcError::InvalidTransaction);
-            }
 
-            if known_inputs.contains(&input.prev_transaction_output_hash) {
+    // add a block to the block tree
+    // a block extending the tip is connected right away, a block on another branch is stored
+    // as a side block and the chain reorganizes onto its branch once it has more work
 
-                return Err(BtcError::InvalidTransaction);
-            }
+    pub fn add_block(&mut self, block: Block) -> Result<()> {
 
-            known_inputs.insert(input.prev_transaction_output_hash);
-        
+        let block_hash = block.hash();
+
+        if self.block_index.contains_key(&block_hash) || self.side_blocks.contains(&block_hash) {
+
+            return Err(BtcError::DuplicateBlock);
         }
 
-        // check if any of the utxos have the bool mark set to true 
-        // and if so, find the transaction that reference them in mempool
-        // remove it and set all utxo it refernce to false
-        
+        // known to be invalid, or building on such a block
 
+        if self.store.is_invalid(&block_hash) {
 
-        for input in &transactions.inputs {
+            return Err(BtcError::InvalidBlock);
+        }
 
-            if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash) {
+        if self.store.is_invalid(&block.header.prev_block_hash) {
 
-                // find the transaction that references th utxo
+            return Err(self.reject(block_hash, BtcError::InvalidBlock));
+        }
 
-                let referencing_transaction: Option<(usize, &(DateTime<Utc>, Transaction))> = self.mempool.iter()
-                    .enumerate()
-                    .find(|( _, (_, transaction))| {
+        let extends_tip = self.tip_hash() == block.header.prev_block_hash;
 
-                        transaction.outputs
-                        .iter()
-                        .any(|output| {
-                            output.hash() == input.prev_transaction_output_hash
+        if extends_tip {
 
+            let block_transactions = block.transactions.clone();
 
-                        })
+            self.connect_block(block).map_err(|e| self.reject(block_hash, e))?;
 
+            // Remove the transaction from mempool that are now in the block
 
-                    } );
+            self.remove_from_mempool(&block_transactions);
 
-                    // if we found one, unmark all of its utxos
+            return Ok(());
+        }
 
-                if let Some((idx, (_, referencing_transaction))) = referencing_transaction {
+        // the block forks off somewhere below the tip,
+        // so its parent has to be either on the active chain or a known side block
 
-                    for input in &referencing_transaction.inputs {
+        let prev_block_hash = block.header.prev_block_hash;
 
-                        // set all the utxo's to false
+        if !self.block_index.contains_key(&prev_block_hash) && !self.side_blocks.contains(&prev_block_hash) {
 
-                        self.utxos.entry(input.prev_transaction_output_hash).and_modify(|(marked, _)| {
+            println!("unknown parent block");
+            return Err(BtcError::OrphanBlock);
+        }
 
-                            *marked = false;
+        // we can only check what does not depend on the chain state until the branch is connected,
+        // the blocks before it are known though
 
+        self.check_block_header(&block)
+            .and_then(|()| self.check_timestamp(self.recent_timestamps(prev_block_hash, MEDIAN_TIME_SPAN), &block.header))
+            .map_err(|e| self.reject(block_hash, e))?;
 
-                        });
+        self.store.put_block(&block)?;
 
+        self.side_blocks.insert(block_hash);
 
-                    }
+        self.try_reorganize(block_hash)
+    }
 
-                    // remove the transaction from the mempool
 
-                    self.mempool.remove(idx);
+    // remember a block that failed with e as invalid, if that is what e means
 
-                } else {
+    fn reject(&mut self, hash: Hash, e: BtcError) -> BtcError {
 
-                    // if somehow , there is no matching transaction, set this utxo to false
+        if proves_invalid(&e) {
 
-                    self.utxos.entry(input.prev_transaction_output_hash).and_modify(|(marked, _)| {
+            if let Err(io) = self.store.mark_invalid(hash) {
 
-                        *marked = false;
-                    });
+                println!("failed to remember invalid block {hash}: {io}");
+            }
+        }
 
+        e
+    }
 
 
+    // remember a stored block as invalid, together with every side block built on it
 
+    fn invalidate(&mut self, hash: Hash) -> Result<()> {
 
+        let descendants = self.side_blocks.iter()
+            .filter(|side| self.descends_from(side, &hash))
+            .copied()
+            .collect::<Vec<_>>();
 
-                }
+        for hash in descendants.into_iter().chain([hash]) {
 
+            self.side_blocks.remove(&hash);
 
-            }
+            self.store.mark_invalid(hash)?;
         }
 
-     
-
-        // sort by miner fee
+        Ok(())
+    }
 
 
-        let all_inputs = transactions.inputs
-            .iter()
-            .map(|input| {
+    // whether a side block is the ancestor or built on it, through side blocks only
 
-                self.utxos.get(&input.prev_transaction_output_hash)
-                    .expect("bug")
-                    .1
-                    .value
+    fn descends_from(&self, side: &Hash, ancestor: &Hash) -> bool {
 
-            })
-            .sum::<u64>();
+        let mut cursor = *side;
 
-        let all_ouputs: u64 = transactions.outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
+        while self.side_blocks.contains(&cursor) {
 
+            if cursor == *ancestor {
 
-        if all_inputs < all_ouputs {
+                return true;
+            }
 
-            return Err(BtcError::InvalidTransaction);
+            cursor = self.side_header(&cursor).prev_block_hash;
         }
 
+        false
+    }
 
-        // Mark the UTXO's as used
- 
-        for input in &transactions.inputs {
 
-            self.utxos.entry(input.prev_transaction_output_hash).and_modify(|(marked, _)| {
+    // the target of a block on top of parent, following the received headers before it that are not stored yet
+    // like check_header. None if parent is unknown. on the active chain this is what try_adjust_target arrives at
 
-                *marked = true;
+    fn expected_target(&self, unstored: &[BlockHeader], parent: &Hash) -> Option<U256> {
 
+        let parent_header = self.ancestors(unstored, *parent).next()?;
 
-            });
+        let interval = self.params.difficulty_update_interval as usize;
+
+        let height = self.height_after(unstored, parent)? + 1;
+
+        if !height.is_multiple_of(interval) {
+
+            return Some(parent_header.target);
         }
 
-        self.mempool.push((Utc::now(), transactions));
+        let first = self.ancestors(unstored, *parent).nth(interval - 1)?;
 
-        // sort by miner fee
+        Some(self.retarget(parent_header.target, first.timestamp, parent_header.timestamp))
+    }
 
-        self.mempool.sort_by_key(|(_, transaction)| {
 
-            let all_inputs = transaction
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .expect("bug")
-                        .1
-                        .value
+    // a header and the ones before it, newest first: the received headers that are not stored yet,
+    // hash has to be the last of them then, followed by the stored ones
 
-                })
-                .sum::<u64>();
+    fn ancestors<'a>(&'a self, unstored: &'a [BlockHeader], hash: Hash) -> impl Iterator<Item = &'a BlockHeader> {
 
+        let stored = unstored.first().map_or(hash, |header| header.prev_block_hash);
 
-            let all_outputs: u64 = transaction
-                .outputs
-                .iter()
-                .map(|output| output.value)
-                .sum();
+        unstored.iter()
+            .rev()
+            .chain(iter::successors(self.store.header(&stored), |header| self.store.header(&header.prev_block_hash)))
+    }
 
-            let miner_fee = all_inputs - all_outputs;
 
-            miner_fee
+    // the height of a block on any branch, like ancestors
 
+    fn height_after(&self, unstored: &[BlockHeader], hash: &Hash) -> Option<usize> {
 
+        let (mut cursor, mut steps) = match unstored.first() {
 
+            Some(first) => (first.prev_block_hash, unstored.len()),
 
+            None => (*hash, 0),
+        };
 
-        });
+        // every branch leads back to the active chain
 
-        Ok(())
+        loop {
+
+            if let Some(height) = self.block_index.get(&cursor) {
+
+                return Some(height + steps);
+            }
+
+            cursor = self.store.header(&cursor)?.prev_block_hash;
 
-       
+            steps += 1;
+        }
     }
 
 
+    // checks that only need the header itself
 
-    // remove transaction older than Max_Mempool_transaction_age
+    fn check_proof_of_work(&self, unstored: &[BlockHeader], header: &BlockHeader) -> Result<()> {
 
+        // check if the target is the one the chain before it asks for
 
-    pub fn cleanup_mempool(&mut self) {
+        let expected = self.expected_target(unstored, &header.prev_block_hash).ok_or(BtcError::OrphanBlock)?;
+
+        if header.target != expected {
 
+            println!("target is not the expected target");
+            return Err(BtcError::InvalidBlockHeader);
+        }
 
-        let now = Utc::now();
+        // check if the block's hash is less than the target
 
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+        if !header.hash().matches_target(header.target) {
 
-        self.mempool.retain(|(timestamp, transaction )| {
+            println!("does not match the target");
+            return Err(BtcError::InvalidBlock);
+        }
 
-            if now - timestamp > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64) {
+        Ok(())
+    }
 
-                // push all utxos to unmark to the vector
-                // so we can unmark them later
 
-                utxo_hashes_to_unmark.extend(transaction.inputs.iter().map(|input| {
+    // check a header received ahead of its block, following the received headers before it that are not
+    // stored yet (oldest first, none when its parent is stored), so a header chain can be validated
+    // before any block body is downloaded
 
-                    input.prev_transaction_output_hash
+    pub fn check_header(&self, unstored: &[BlockHeader], header: &BlockHeader) -> Result<()> {
 
-             
-                }) );
+        if self.store.is_invalid(&header.hash()) || self.store.is_invalid(&header.prev_block_hash) {
 
-                false
+            return Err(BtcError::InvalidBlockHeader);
+        }
 
+        match unstored.last() {
 
-            } else {
+            Some(prev_header) if header.prev_block_hash != prev_header.hash() => {
 
-                true
+                return Err(BtcError::InvalidBlockHeader);
             }
 
-        });
+            None if self.header(&header.prev_block_hash).is_none() => {
 
-            // unmark all the utxos
+                return Err(BtcError::OrphanBlock);
+            }
 
-            for hash in utxo_hashes_to_unmark {
+            _ => {}
+        }
 
-                self.utxos.entry(hash).and_modify(|(marked, _)| {
+        // the timestamps before it, received ones first
 
-                    *marked = false
-                });
-            }
+        let timestamps = self.ancestors(unstored, header.prev_block_hash)
+            .take(MEDIAN_TIME_SPAN)
+            .map(|header| header.timestamp)
+            .collect();
 
+        self.check_timestamp(timestamps, header)?;
+
+        self.check_proof_of_work(unstored, header)
+    }
 
 
-     
+    // the timestamp rules: later than the median of the timestamps before it, and not too far in the future
 
+    fn check_timestamp(&self, prev_timestamps: Vec<DateTime<Utc>>, header: &BlockHeader) -> Result<()> {
 
+        if header.timestamp <= median_time(prev_timestamps) {
+
+            return Err(BtcError::TimeTooOld);
         }
 
+        if header.timestamp > self.adjusted_time() + Duration::seconds(MAX_FUTURE_BLOCK_TIME) {
 
+            return Err(BtcError::TimeTooNew);
+        }
 
-    
-       pub fn calculate_block_reward(&self) -> u64 {
+        Ok(())
+    }
 
-          let block_height = self.blocks_height();
 
-          let halving = block_height / crate::HALVING_INTERVAL;
+    // the timestamps of a stored block and the blocks before it, newest first, at most count of them
 
-          (crate::INITIAL_REWARD * 10u64) >> halving
-       }
+    fn recent_timestamps(&self, mut hash: Hash, count: usize) -> Vec<DateTime<Utc>> {
+
+        let mut timestamps = vec![];
+
+        while timestamps.len() < count {
+
+            let Some(header) = self.header(&hash) else {
 
+                break;
+            };
 
+            timestamps.push(header.timestamp);
+
+            hash = header.prev_block_hash;
+        }
+
+        timestamps
+    }
 
 
+    // the median time of a stored block and the blocks before it, a block on top of it has to be later
 
+    pub fn median_time_past(&self, hash: &Hash) -> Option<DateTime<Utc>> {
+
+        self.header(hash)?;
+
+        Some(median_time(self.recent_timestamps(*hash, MEDIAN_TIME_SPAN)))
+    }
+
+
+    // the time blocks are judged by: our clock, corrected by what the other nodes report
+
+    pub fn adjusted_time(&self) -> DateTime<Utc> {
+
+        Utc::now() + self.time_offset
+    }
+
+
+    pub fn set_time_offset(&mut self, offset: Duration) {
+
+        self.time_offset = offset;
+    }
+
+
+    // checks that only need the block itself
+
+    fn check_block_header(&self, block: &Block) -> Result<()> {
+
+        self.check_proof_of_work(&[], &block.header)?;
+
+        // check if the block's merkle root is correct
+
+        let (calculated_merkle_root, mutated) = MerkleRoot::calculate_checked(&block.transactions);
+
+        if calculated_merkle_root != block.header.merkle_root {
+
+
+            println!("invalid merkle root");
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+
+        // same root, but a duplicated transaction list (CVE-2012-2459)
+
+        if mutated {
+
+            println!("mutated transaction list");
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+
+        Ok(())
+    }
+
+
+    // validate a block against the tip of the active chain and append it
+
+    fn connect_block(&mut self, block: Block) -> Result<()> {
+
+        // check if the block is valid
+
+        if let Some(last_header) = self.chain.last() {
+
+            // if this is not the first block , check if the block's prev_block_hash is the hash of the last block
+
+            if block.header.prev_block_hash != last_header.hash() {
+
+                println!("prev hash is wrong");
+                return Err(BtcError::InvalidBlock);
+            }
+
+            self.check_block_header(&block)?;
+
+            self.check_timestamp(self.recent_timestamps(last_header.hash(), MEDIAN_TIME_SPAN), &block.header)?;
+
+            if block.size() > self.params.max_block_size {
+
+                return Err(BtcError::InvalidBlock);
+            }
+
+            // verify the all the transaction in the block
+
+            block.verify_transactions(self.blocks_height(), &self.utxos, &self.params)?;
+
+        } else {
+
+            // if this is the first block, check if the block's prev hash is all zeros
+
+            if block.header.prev_block_hash != Hash::zero() {
+
+                println!("zero hash");
+                return Err(BtcError::InvalidBlock);
+            }
+        }
+
+        let block_hash = block.hash();
+
+        let undo = self.spent_outputs(&block);
+
+        // the block and its undo data go to disk before the utxo batch that makes it the tip,
+        // so a crash in between only leaves a stored block that is not connected yet
+
+        self.store.put_block(&block)?;
+
+        self.store.put_undo(block_hash, &undo)?;
+
+        // outputs spent by a later transaction of the same block never make it into the set
+
+        let spent_in_block: HashSet<OutPoint> = block.transactions
+            .iter()
+            .flat_map(|transaction| &transaction.inputs)
+            .map(|input| input.prev_output)
+            .collect();
+
+        let created = block.transactions
+            .iter()
+            .flat_map(|transaction| transaction.outpoints())
+            .filter(|(outpoint, _)| !spent_in_block.contains(outpoint))
+            .map(|(outpoint, output)| (outpoint, output.clone()))
+            .collect();
+
+        self.utxo_store.commit(
+            block_hash,
+            undo.spent_outputs.iter().map(|(outpoint, _)| *outpoint).collect(),
+            created,
+        )?;
+
+        Self::apply_block(&mut self.utxos, &block);
+
+        let height = self.chain.len();
+
+        self.block_index.insert(block_hash, height);
+
+        self.chain.push(block.header.clone());
+
+        match self.update.disconnected.iter().position(|disconnected| *disconnected == block_hash) {
+
+            Some(position) => {
+
+                self.update.disconnected.remove(position);
+            }
+
+            None => self.update.connected.push(block_hash),
+        }
+
+        self.try_adjust_target();
+
+        // the block is connected once the utxo set is written, whatever fails after that.
+        // the index follows the utxo set, if we crash in between it catches up when it is enabled again
+
+        if let Some(index) = &mut self.index {
+
+            index.connect(&block, height as u64, &undo)?;
+        }
+
+        self.compact_utxo_store()
+    }
+
+
+    // compare the branch ending in `tip` with the active chain
+    // and switch over to it if it carries more cumulative work
+
+    fn try_reorganize(&mut self, tip: Hash) -> Result<()> {
+
+        // walk back through the side blocks until we reach the active chain
+
+        let mut branch = vec![];
+
+        let mut cursor = tip;
+
+        while self.side_blocks.contains(&cursor) {
+
+            branch.push(cursor);
+
+            cursor = self.side_header(&cursor).prev_block_hash;
+        }
+
+        let Some(&fork_height) = self.block_index.get(&cursor) else {
+
+            // the branch does not reach the active chain, nothing to compare against
+            return Ok(());
+        };
+
+        branch.reverse();
+
+        let branch_work = branch.iter()
+            .map(|hash| self.side_header(hash).work())
+            .fold(U256::zero(), |total, work| total + work);
+
+        let active_work = self.chain[fork_height + 1..].iter()
+            .map(|header| header.work())
+            .fold(U256::zero(), |total, work| total + work);
+
+        // on equal work we keep the branch we saw first
+
+        if branch_work <= active_work {
+
+            return Ok(());
+        }
+
+        println!("branch with more work found, reorganizing {} blocks", self.chain.len() - fork_height - 1);
+
+        self.reorganize(fork_height, branch)
+    }
+
+
+    fn side_header(&self, hash: &Hash) -> &BlockHeader {
+
+        self.store.header(hash).expect("bug: side block not in the store")
+    }
+
+
+    // disconnect the active chain down to fork_height and connect the branch instead.
+    // if anything goes wrong on the way, an invalid block as well as a failed write, the old chain is restored.
+    // an invalid block is remembered along with everything built on it
+
+    fn reorganize(&mut self, fork_height: usize, branch: Vec<Hash>) -> Result<()> {
+
+        let previous = self.chain[fork_height + 1..].iter()
+            .map(|header| header.hash())
+            .collect::<Vec<_>>();
+
+        let switched = self.switch_branch(fork_height, &branch);
+
+        let (failed, e) = match switched {
+
+            Ok(disconnected) => {
+
+                self.update_side_blocks(branch.iter().chain(&previous));
+
+                // the transactions of the old chain go back to the mempool
+
+                let transactions = disconnected.into_iter()
+                    .flat_map(|block| block.transactions.into_iter().skip(1))
+                    .collect();
+
+                self.resubmit_mempool(transactions);
+
+                return Ok(());
+            }
+
+            Err(failed) => failed,
+        };
+
+        println!("reorg failed, restoring the previous chain: {e}");
+
+        if let Err((_, restore_error)) = self.switch_branch(fork_height, &previous) {
+
+            // the chain is left on a valid part of one of the two, the next block or start tries again
+            println!("failed to restore the previous chain: {restore_error}");
+        }
+
+        self.update_side_blocks(branch.iter().chain(&previous));
+
+        if let Some(hash) = failed.filter(|_| proves_invalid(&e)) {
+
+            self.invalidate(hash)?;
+        }
+
+        self.resubmit_mempool(vec![]);
+
+        Err(e)
+    }
+
+
+    // disconnect the active chain down to fork_height and connect the stored blocks of branch on top.
+    // returns the blocks taken off, oldest first. on an error, the block that failed to connect if it was one
+
+    fn switch_branch(&mut self, fork_height: usize, branch: &[Hash]) -> std::result::Result<Vec<Block>, (Option<Hash>, BtcError)> {
+
+        let mut disconnected = vec![];
+
+        while self.chain.len() > fork_height + 1 {
+
+            let block = self.disconnect_tip().map_err(|e| (None, e))?;
+
+            disconnected.push(block.expect("bug: chain shorter than fork point"));
+        }
+
+        disconnected.reverse();
+
+        self.rebuild_target();
+
+        for hash in branch {
+
+            self.stored_block(hash)
+                .and_then(|block| self.connect_block(block))
+                .map_err(|e| (Some(*hash), e))?;
+        }
+
+        Ok(disconnected)
+    }
+
+
+    // stored blocks off the active chain are side blocks, unless they are known to be invalid
+
+    fn update_side_blocks<'a>(&mut self, hashes: impl Iterator<Item = &'a Hash>) {
+
+        for hash in hashes {
+
+            if self.block_index.contains_key(hash) || self.store.is_invalid(hash) {
+
+                self.side_blocks.remove(hash);
+
+            } else {
+
+                self.side_blocks.insert(*hash);
+            }
+        }
+    }
+
+
+    // the utxo marks of the mempool transactions may no longer be accurate after a reorg,
+    // so unmark everything and run the mempool (plus any returned transactions) through add_to_mempool again.
+    // transactions that are no longer valid on the new chain are dropped
+
+    fn resubmit_mempool(&mut self, mut transactions: Vec<Transaction>) {
+
+        let mempool = self.mempool.take();
+
+        for entry in &mempool {
+
+            self.unmark_inputs(&entry.transaction);
+        }
+
+        transactions.extend(mempool.into_iter().map(|entry| entry.transaction));
+
+        for transaction in transactions {
+
+            let _ = self.add_to_mempool(transaction);
+        }
+    }
+
+
+    // Remove the transactions from mempool that are now in a block,
+    // and the ones that conflict with it because one of their inputs was spent by the block
+
+    fn remove_from_mempool(&mut self, block_transactions: &[Transaction]) {
+
+        for transaction in block_transactions {
+
+            // the transaction itself, its inputs are gone from the utxo set already.
+            // its children stay, and the outputs they spend are now confirmed ones to mark
+
+            let txid = transaction.hash();
+
+            self.mempool.remove(&txid);
+
+            for outpoint in transaction.outpoints().map(|(outpoint, _)| outpoint) {
+
+                if self.mempool.spender(&outpoint).is_some() {
+
+                    self.utxos.entry(outpoint).and_modify(|(marked, _)| *marked = true);
+                }
+            }
+
+            let conflicting: HashSet<Hash> = transaction.inputs
+                .iter()
+                .filter_map(|input| self.mempool.spender(&input.prev_output))
+                .map(|other| other.txid)
+                .collect();
+
+            for other in conflicting {
+
+                self.drop_from_mempool(&other);
+            }
+        }
+    }
+
+
+    pub fn try_adjust_target(&mut self) {
+
+        if self.chain.is_empty() {
+
+            return;
+        }
+
+        if !self.chain.len().is_multiple_of(self.params.difficulty_update_interval as usize) {
+
+            return;
+        }
+
+        // measure the time it took mine the last blocks
+
+        let start_time = self.chain[self.chain.len() - self.params.difficulty_update_interval as usize].timestamp;
+
+        let end_time = self.chain.last().unwrap().timestamp;
+
+        self.target = self.retarget(self.target, start_time, end_time);
+    }
+
+
+    // the target after an adjustment period that went from start_time to end_time at target
+
+    fn retarget(&self, target: U256, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> U256 {
+
+        let time_diff = end_time - start_time;
+
+        // convert time_diff to seconds
+
+        // timestamps only have to be later than the median before them, so the span can even be negative
+
+        let time_diff_seconds = time_diff.num_seconds().max(0);
+
+        // calculate the ideal number of seconds
+
+        let target_seconds =  self.params.ideal_block_time * self.params.difficulty_update_interval;
+
+        // let new_target = self.target * (time_diff_seconds as f64 / target_seconds as f64) as usize;
+
+        let new_target = BigDecimal::parse_bytes(target.to_string().as_bytes(), 10)
+            .expect("bug")
+                * (BigDecimal::from(time_diff_seconds)  
+                    /  BigDecimal::from(target_seconds));
+
+            // cut off decimal point and everything after it from the string representation
+
+        let new_target_str = new_target.to_string().split('.').next().expect("bug expected a decimal point").to_owned();
+
+        // a target that does not even fit into U256 is clamped to the minimum target below anyway
+
+        let new_target: U256 = U256::from_str_radix(&new_target_str, 10).unwrap_or(U256::MAX);
+
+
+        // clamp new_target to within range of 4 * self.target and self.target / 4
+       // we can multiply or divide either by 1, 2, 3, 4
+
+        let new_target  = if new_target < target / 4 {
+
+            target / 4
+
+        } else if new_target > target.saturating_mul(U256::from(4))  {
+
+            target.saturating_mul(U256::from(4))
+
+
+        } else {
+
+            new_target
+        };
+
+
+        // if the new_target is more than the minimum target 
+        // set it to the minimm target
+
+        new_target.min(self.params.min_target)
+    }
+
+
+
+
+    pub fn blocks_height(&self) -> u64 {
+        
+        self.chain.len() as u64
+    }
+    
+
+    // utxo's
+
+    pub fn utxos(&self) -> &HashMap<OutPoint, (bool, TransactionOutput)> {
+
+        &self.utxos
+    }
+
+    // target
+    pub fn target(&self) -> U256 {
+
+        self.target
+    }
+
+    // headers of the active chain
+
+    pub fn headers(&self) -> impl Iterator<Item = &BlockHeader> {
+
+        self.chain.iter()
+    }
+
+
+    // hash of the last block of the active chain
+
+    pub fn tip_hash(&self) -> Hash {
+
+        self.chain
+            .last()
+            .map(|last_header| last_header.hash())
+            .unwrap_or(Hash::zero())
+    }
+
+
+    // header of any stored block, on the active chain or not
+
+    pub fn header(&self, hash: &Hash) -> Option<&BlockHeader> {
+
+        self.store.header(hash)
+    }
+
+
+    // height of a block on the active chain, None for side branches and unknown blocks
+
+    pub fn block_height(&self, hash: &Hash) -> Option<usize> {
+
+        self.block_index.get(hash).copied()
+    }
+
+
+    // whether the block is on the active chain or a side branch
+
+    pub fn contains_block(&self, hash: &Hash) -> bool {
+
+        self.block_index.contains_key(hash) || self.side_blocks.contains(hash)
+    }
+
+
+    // total work of the chain ending in the stored block with this hash
+
+    pub fn chain_work(&self, hash: &Hash) -> Option<U256> {
+
+        let mut work = U256::zero();
+
+        let mut cursor = *hash;
+
+        while cursor != Hash::zero() {
+
+            let header = self.store.header(&cursor)?;
+
+            work += header.work();
+
+            cursor = header.prev_block_hash;
+        }
+
+        Some(work)
+    }
+
+
+    // hashes describing the active chain to a peer: the last 10 blocks,
+    // then exponentially fewer on the way down, and always the genesis block
+
+    pub fn block_locator(&self) -> Vec<Hash> {
+
+        locator_heights(self.chain.len())
+            .into_iter()
+            .map(|height| self.chain[height].hash())
+            .collect()
+    }
+
+
+    // a block_locator for the chain received headers are building: the newest of them first,
+    // spaced out the same way, then the locator of the active chain
+
+    pub fn block_locator_after(&self, unstored: &[BlockHeader]) -> Vec<Hash> {
+
+        locator_heights(unstored.len())
+            .into_iter()
+            .map(|index| unstored[index].hash())
+            .chain(self.block_locator())
+            .collect()
+    }
+
+
+    // the headers of the active chain after the first locator hash on it,
+    // or from genesis if the locator shares nothing with the active chain
+
+    pub fn headers_after(&self, locator: &[Hash], max: usize) -> Vec<BlockHeader> {
+
+        let start = locator.iter()
+            .find_map(|hash| self.block_index.get(hash))
+            .map(|height| height + 1)
+            .unwrap_or(0);
+
+        self.chain.iter().skip(start).take(max).cloned().collect()
+    }
+
+
+    // read any stored block from the store
+
+    pub fn block_by_hash(&self, hash: &Hash) -> Result<Option<Block>> {
+
+        Ok(self.store.block(hash)?)
+    }
+
+
+    // read the block at height of the active chain from the store
+
+    pub fn block(&self, height: usize) -> Result<Option<Block>> {
+
+        let Some(header) = self.chain.get(height) else {
+
+            return Ok(None);
+        };
+
+        Ok(self.store.block(&header.hash())?)
+    }
+
+
+    // find a transaction in the active chain and prove its inclusion
+    // searches from the tip, recent payments are the ones usually asked about.
+    // every block on the way is read from the store
+
+    pub fn merkle_proof(&self, txid: Hash) -> Result<Option<(BlockHeader, MerkleProof)>> {
+
+        for header in self.chain.iter().rev() {
+
+            let block = self.stored_block(&header.hash())?;
+
+            if let Some(tx_index) = block.transactions.iter().position(|tx| tx.hash() == txid) {
+
+                return Ok(MerkleRoot::build_proof(&block.transactions, tx_index).map(|proof| (header.clone(), proof)));
+            }
+        }
+
+        Ok(None)
+    }
+
+
+    // find a transaction in the active chain, with the header of its block.
+    // without an index it searches from the tip like merkle_proof
+
+    pub fn find_transaction(&self, txid: &Hash) -> Result<Option<(BlockHeader, Transaction)>> {
+
+        if let Some(index) = &self.index {
+
+            let Some(location) = index.transaction(txid) else {
+
+                return Ok(None);
+            };
+
+            let mut block = self.stored_block(&location.block)?;
+
+            let transaction = block.transactions.swap_remove(location.position as usize);
+
+            return Ok(Some((block.header, transaction)));
+        }
+
+        for header in self.chain.iter().rev() {
+
+            let block = self.stored_block(&header.hash())?;
+
+            if let Some(transaction) = block.transactions.into_iter().find(|tx| tx.hash() == *txid) {
+
+                return Ok(Some((header.clone(), transaction)));
+            }
+        }
+
+        Ok(None)
+    }
+
+
+    // every output paid to the public key on the active chain, and every spend of one, oldest first.
+    // None without an index
+
+    pub fn history(&self, pubkey: &PublicKey) -> Option<&[HistoryEntry]> {
+
+        self.index.as_ref().map(|index| index.history(pubkey))
+    }
+
+
+    // the unspent outputs of a public key and whether a mempool transaction spends them.
+    // outputs of mempool transactions count too, so change can be spent before it confirms.
+    // with an index only the outputs ever paid to the key are looked at, not the whole utxo set
+
+    pub fn utxos_of(&self, pubkey: &PublicKey) -> Vec<(OutPoint, TransactionOutput, bool)> {
+
+        let mut utxos: Vec<_> = match &self.index {
+
+            Some(index) => index.history(pubkey).iter()
+                .filter(|entry| !entry.spent)
+                .filter_map(|entry| self.utxos.get(&entry.outpoint).map(|(marked, output)| (entry.outpoint, output.clone(), *marked)))
+                .collect(),
+
+            None => self.utxos.iter()
+                .filter(|(_, (_, output))| output.pubkey == *pubkey)
+                .map(|(outpoint, (marked, output))| (*outpoint, output.clone(), *marked))
+                .collect(),
+        };
+
+        for entry in self.mempool.iter() {
+
+            for (vout, output) in entry.transaction.outputs.iter().enumerate() {
+
+                if output.pubkey == *pubkey {
+
+                    let outpoint = OutPoint::new(entry.txid, vout as u32);
+
+                    utxos.push((outpoint, output.clone(), self.mempool.spender(&outpoint).is_some()));
+                }
+            }
+        }
+
+        utxos
+    }
+
+
+    // mempool
+
+    pub fn mempool(&self) -> &Mempool {
+
+        &self.mempool
+    }
+
+
+    pub fn mempool_transaction(&self, txid: &Hash) -> Option<&Transaction> {
+
+        self.mempool.get(txid).map(|entry| &entry.transaction)
+    }
+
+
+    // change how much the mempool may hold and the lowest fee rate it accepts.
+    // what no longer fits is dropped, lowest fee rate first
+
+    pub fn configure_mempool(&mut self, max_usage: usize, min_relay_fee: u64) {
+
+        let evicted = self.mempool.set_limits(max_usage, min_relay_fee);
+
+        for entry in evicted {
+
+            self.unmark_inputs(&entry.transaction);
+        }
+    }
+
+
+    fn unmark_inputs(&mut self, transaction: &Transaction) {
+
+        for input in &transaction.inputs {
+
+            self.utxos.entry(input.prev_output).and_modify(|(marked, _)| {
+
+                *marked = false;
+            });
+        }
+    }
+
+
+    // take a transaction and its descendants out of the mempool and free the outputs they reserved
+
+    fn drop_from_mempool(&mut self, txid: &Hash) {
+
+        for entry in self.mempool.remove_with_descendants(txid) {
+
+            self.unmark_inputs(&entry.transaction);
+        }
+    }
+
+
+    // now we need to teach blockchain to receive and add transaction to mempool
+
+    pub fn add_to_mempool(&mut self, transactions: Transaction) -> Result<()> {
+
+
+        // output should be less or equal to inputs
+        // all inputs must have known UTXO
+        // all inputs must be unique(no double spending)
+
+    //         There is yet another security issue we must take care of: So far, it is still possible
+    // to add multiple transactions to the mempool that reference the same unspent
+    // transaction outputs. Furthermore, transactions in the mempool will stay stuck
+    // there until the node is restarted (at which point, other nodes would share the same
+    // transactions with the node again).
+    // Therefore we need a mechanism to detect this type of double-spending, to discard
+    // old unprocessed transactions, and to replace transactions with newer transactions
+    // that reference the same inputs (which will prevent a potential double-spending
+    // problem). To do this, we will need to make two adjustments:
+    // ● Track the time when a particular transaction was inserted into the mem-
+    // pool, and dump it if it has been there for too long.
+    // ● Mark UTXOs that are being referenced by a transaction in mempool, and
+    // find and remove the old transaction that marks those UTXOs.
+
+
+        // validate transaction before insertion
+        // all inputs must match known UTXO's or outputs of transactions in the mempool,
+        // be signed for this transaction and must be unique
+
+        let txid = transactions.hash();
+
+        if self.mempool.contains(&txid) {
+
+            return Err(BtcError::DuplicateTransaction);
+        }
+
+        let mut known_inputs = HashSet::new();
+
+        let mut all_inputs: u64 = 0;
+
+        for (index, input) in transactions.inputs.iter().enumerate() {
+
+            let prev_output = match self.utxos.get(&input.prev_output) {
+
+                Some((_, prev_output)) => prev_output,
+
+                None => self.mempool.output(&input.prev_output).ok_or(BtcError::MissingInput)?,
+            };
+
+            // the signature has to commit to this transaction
+
+            if !transactions.verify_input(index, prev_output) {
+
+                return Err(BtcError::InvalidSignature);
+            }
+
+            if known_inputs.contains(&input.prev_output) {
+
+                return Err(BtcError::InvalidTransaction);
+            }
+
+            known_inputs.insert(input.prev_output);
+
+            all_inputs = all_inputs.checked_add(prev_output.value).ok_or(BtcError::InvalidTransaction)?;
+        }
+
+        let all_ouputs = transactions.outputs
+            .iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+            .ok_or(BtcError::InvalidTransaction)?;
+
+        if all_inputs < all_ouputs {
+
+            return Err(BtcError::InvalidTransaction);
+        }
+
+        let entry = MempoolEntry::new(transactions, all_inputs - all_ouputs, Utc::now());
+
+        if entry.fee_rate < self.mempool.min_fee_rate() {
+
+            return Err(BtcError::InsufficientFee);
+        }
+
+        // a transaction spending an output that is already reserved by a transaction in mempool
+        // replaces that transaction and its descendants if it pays enough more, see Mempool::check_replacement.
+        // the other outputs they reserved are free again
+
+        let conflicting: HashSet<Hash> = entry.transaction.inputs
+            .iter()
+            .filter_map(|input| self.mempool.spender(&input.prev_output))
+            .map(|other| other.txid)
+            .collect();
+
+        // it cannot replace what it spends from itself
+
+        let ancestors = self.mempool.ancestors(&entry.transaction);
+
+        let replaced = conflicting.iter()
+            .flat_map(|other| self.mempool.descendants(other).into_iter().chain([*other]))
+            .collect::<HashSet<_>>();
+
+        if !ancestors.is_disjoint(&replaced) {
+
+            return Err(BtcError::InvalidTransaction);
+        }
+
+        if !conflicting.is_empty() {
+
+            self.mempool.check_replacement(&entry, &conflicting, &replaced)?;
+        }
+
+        if !self.mempool.within_chain_limits(&entry.transaction, entry.size, &replaced) {
+
+            return Err(BtcError::MempoolChainTooLong);
+        }
+
+        for other in conflicting {
+
+            self.drop_from_mempool(&other);
+        }
+
+        // Mark the UTXO's as used, the outputs of mempool transactions are tracked by the mempool
+
+        for input in &entry.transaction.inputs {
+
+            self.utxos.entry(input.prev_output).and_modify(|(marked, _)| {
+
+                *marked = true;
+            });
+        }
+
+        // a full mempool makes room by dropping the lowest fee rates, that can be this one
+
+        let evicted = self.mempool.insert(entry);
+
+        let mut accepted = true;
+
+        for entry in evicted {
+
+            accepted &= entry.txid != txid;
+
+            self.unmark_inputs(&entry.transaction);
+        }
+
+        if !accepted {
+
+            return Err(BtcError::InsufficientFee);
+        }
+
+        Ok(())
+    }
+
+
+
+    // remove transaction older than Max_Mempool_transaction_age
+
+
+    pub fn cleanup_mempool(&mut self) {
+
+        let max_age = chrono::Duration::seconds(self.params.max_mempool_transaction_age.min(i64::MAX as u64) as i64);
+
+        let cutoff = Utc::now() - max_age;
+
+        for txid in self.mempool.expired(cutoff) {
+
+            self.drop_from_mempool(&txid);
+        }
+    }
+
+
+
+    
+       pub fn calculate_block_reward(&self) -> u64 {
+
+          self.params.block_reward(self.blocks_height())
+       }
+
+
+       pub fn params(&self) -> &ChainParams {
+
+           &self.params
+       }
+
+
+
+
+
+}
+
+
+// the data on disk does not describe a usable chain
+
+fn stored_chain_error(message: String) -> BtcError {
+
+    BtcError::Storage(IoError::new(IoErrorKind::InvalidData, message))
+}
+
+
+// whether a block that failed with this error can never be valid. one that is early by our clock, waits
+// for its parent, or came with other transactions than its header commits to may be fine another time
+
+fn proves_invalid(error: &BtcError) -> bool {
+
+    !matches!(
+        error,
+        BtcError::TimeTooNew | BtcError::OrphanBlock | BtcError::DuplicateBlock | BtcError::InvalidMerkleRoot | BtcError::Storage(_)
+    )
+}
+
+
+// the median of block timestamps, the earliest possible time for an empty list
+
+fn median_time(mut timestamps: Vec<DateTime<Utc>>) -> DateTime<Utc> {
+
+    timestamps.sort();
+
+    timestamps.get(timestamps.len() / 2).copied().unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
+
+
+// the heights a locator names in a chain of len blocks: the last 10, then exponentially fewer
+// on the way down, and always the first
+
+fn locator_heights(len: usize) -> Vec<usize> {
+
+    let mut heights = vec![];
+
+    let mut height = len;
+
+    let mut step = 1;
+
+    while height > 0 {
+
+        height = height.saturating_sub(step);
+
+        heights.push(height);
+
+        if heights.len() >= 10 {
+
+            step *= 2;
+        }
+    }
+
+    heights
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::assembler::assemble_block;
+    use crate::crypto::PrivateKey;
+    use crate::mempool::tests::{funded_chain, mine, spend};
+    use uuid::Uuid;
+
+
+    // a block paying coinbase to key on top of any stored block, later than its parent
+    // by one second. the chain never retargets within the few blocks these tests need
+
+    fn block_on(blockchain: &Blockchain, parent: Hash, key: &PrivateKey, coinbase: u64) -> Block {
+
+        let timestamp = blockchain.header(&parent).unwrap().timestamp + Duration::seconds(1);
+
+        block_at(blockchain, parent, key, coinbase, timestamp)
+    }
+
+
+    fn block_at(blockchain: &Blockchain, parent: Hash, key: &PrivateKey, coinbase: u64, timestamp: DateTime<Utc>) -> Block {
+
+        let transactions = vec![Transaction::new(vec![], vec![TransactionOutput {
+
+            pubkey: key.public_key(),
+
+            unique_id: Uuid::new_v4(),
+
+            value: coinbase,
+        }])];
+
+        let header = BlockHeader::new(timestamp, 0, parent, MerkleRoot::calculate(&transactions), blockchain.params().min_target);
+
+        mine(Block::new(header, transactions))
+    }
+
+
+    fn reward_block_on(blockchain: &Blockchain, parent: Hash, height: u64, key: &PrivateKey) -> Block {
+
+        block_on(blockchain, parent, key, blockchain.params().block_reward(height))
+    }
+
+
+    // the funded chain, genesis and A1, and A2 on top: the fork point for branches of B blocks
+
+    fn two_blocks(key: &PrivateKey) -> (Blockchain, Hash, Hash) {
+
+        let (mut blockchain, _) = funded_chain(key);
+
+        let fork = blockchain.tip_hash();
+
+        let block = mine(assemble_block(&blockchain, key.public_key()).block);
+
+        blockchain.add_block(block).unwrap();
+
+        let tip = blockchain.tip_hash();
+
+        (blockchain, fork, tip)
+    }
+
+
+    #[test]
+    fn branch_with_more_work_takes_over() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, fork, a2) = two_blocks(&key);
+
+        let b2 = reward_block_on(&blockchain, fork, 2, &key);
+
+        blockchain.add_block(b2.clone()).unwrap();
+
+        // as much work as the active chain, the branch seen first stays
+
+        assert_eq!(blockchain.tip_hash(), a2);
+
+        let b3 = reward_block_on(&blockchain, b2.hash(), 3, &key);
+
+        blockchain.add_block(b3.clone()).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), b3.hash());
+
+        assert_eq!(blockchain.block_height(&b2.hash()), Some(2));
+
+        assert_eq!(blockchain.block_height(&a2), None);
+
+        // the old tip is kept as a side block
+
+        assert!(blockchain.contains_block(&a2));
+
+        let a2 = blockchain.block_by_hash(&a2).unwrap().unwrap();
+
+        assert!(matches!(blockchain.add_block(a2), Err(BtcError::DuplicateBlock)));
+    }
+
+
+    #[test]
+    fn invalid_branch_restores_the_old_chain() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, fork, a2) = two_blocks(&key);
+
+        let utxos = blockchain.utxos().keys().copied().collect::<HashSet<_>>();
+
+        let b2 = reward_block_on(&blockchain, fork, 2, &key);
+
+        blockchain.add_block(b2.clone()).unwrap();
+
+        // a coinbase paying more than the reward only shows once the block is connected
+
+        let b3 = block_on(&blockchain, b2.hash(), &key, blockchain.params().block_reward(3) + 1);
+
+        assert!(blockchain.add_block(b3.clone()).is_err());
+
+        assert_eq!(blockchain.tip_hash(), a2);
+
+        assert_eq!(blockchain.blocks_height(), 3);
+
+        assert_eq!(blockchain.utxos().keys().copied().collect::<HashSet<_>>(), utxos);
+
+        // the invalid block is remembered, and so is everything built on it
+
+        assert!(matches!(blockchain.add_block(b3.clone()), Err(BtcError::InvalidBlock)));
+
+        let b4 = reward_block_on(&blockchain, b3.hash(), 4, &key);
+
+        assert!(matches!(blockchain.add_block(b4), Err(BtcError::InvalidBlock)));
+
+        // the valid part of the branch can still win
+
+        let b3 = reward_block_on(&blockchain, b2.hash(), 3, &key);
+
+        blockchain.add_block(b3.clone()).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), b3.hash());
+    }
+
+
+    #[test]
+    fn disconnected_transactions_return_to_the_mempool() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, coin) = funded_chain(&key);
+
+        let fork = blockchain.tip_hash();
+
+        let transaction = spend(&key, &[coin], 1, 1000);
+
+        blockchain.add_to_mempool(transaction.clone()).unwrap();
+
+        let a2 = mine(assemble_block(&blockchain, key.public_key()).block);
+
+        assert_eq!(a2.transactions.len(), 2);
+
+        blockchain.add_block(a2).unwrap();
+
+        assert!(blockchain.mempool_transaction(&transaction.hash()).is_none());
+
+        // a branch without the transaction, where the output it spends is still unspent
+
+        let b2 = reward_block_on(&blockchain, fork, 2, &key);
+
+        blockchain.add_block(b2.clone()).unwrap();
+
+        let b3 = reward_block_on(&blockchain, b2.hash(), 3, &key);
+
+        blockchain.add_block(b3.clone()).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), b3.hash());
+
+        assert!(blockchain.mempool_transaction(&transaction.hash()).is_some());
+    }
+
+
+    #[test]
+    fn timestamp_after_median_time_past() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, _) = funded_chain(&key);
+
+        while blockchain.blocks_height() < 13 {
+
+            let block = reward_block_on(&blockchain, blockchain.tip_hash(), blockchain.blocks_height(), &key);
+
+            blockchain.add_block(block).unwrap();
+        }
+
+        let tip = blockchain.tip_hash();
+
+        let median = blockchain.median_time_past(&tip).unwrap();
+
+        // the median of the last 11 blocks is the 6th newest, 5 seconds before the tip
+
+        assert_eq!(median, blockchain.header(&tip).unwrap().timestamp - Duration::seconds(5));
+
+        let reward = blockchain.calculate_block_reward();
+
+        let at_median = block_at(&blockchain, tip, &key, reward, median);
+
+        assert!(matches!(blockchain.add_block(at_median), Err(BtcError::TimeTooOld)));
+
+        // earlier than its parent is fine, as long as it is after the median
+
+        let after_median = block_at(&blockchain, tip, &key, reward, median + Duration::seconds(1));
+
+        blockchain.add_block(after_median.clone()).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), after_median.hash());
+    }
+
+
+    #[test]
+    fn timestamp_not_too_far_ahead() {
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, _) = funded_chain(&key);
+
+        let tip = blockchain.tip_hash();
+
+        let reward = blockchain.calculate_block_reward();
+
+        // a minute past the limit, so the clock moving on during the test does not matter
+
+        let limit = blockchain.adjusted_time() + Duration::seconds(MAX_FUTURE_BLOCK_TIME);
+
+        let too_new = block_at(&blockchain, tip, &key, reward, limit + Duration::seconds(60));
+
+        assert!(matches!(blockchain.add_block(too_new.clone()), Err(BtcError::TimeTooNew)));
+
+        // not remembered as invalid, it may be fine later
+
+        assert!(matches!(blockchain.add_block(too_new), Err(BtcError::TimeTooNew)));
+
+        let at_limit = block_at(&blockchain, tip, &key, reward, limit);
+
+        blockchain.add_block(at_limit.clone()).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), at_limit.hash());
+    }
 }
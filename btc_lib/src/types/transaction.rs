@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use crate::crypto::{PublicKey, Signature};
+use crate::encoding::{ConsensusEncode, CONSENSUS_ENCODING_VERSION};
+use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
 
 use crate::util::Saveable;
@@ -16,6 +18,15 @@ use std::io:: {
 
 
 
+// the sequence of an input that makes no claim
+
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+
+// the highest sequence that opts a transaction in to replace-by-fee (bip125)
+
+pub const SEQUENCE_REPLACEABLE: u32 = u32::MAX - 2;
+
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
 
@@ -62,8 +73,8 @@ impl Transaction {
     ) -> Self {
 
         Transaction {
-            inputs: inputs,
-            outputs: outputs,
+            inputs,
+            outputs,
         }
     }
 
@@ -73,6 +84,127 @@ impl Transaction {
         Hash::hash(self)
          
     }
+
+
+    // bytes the transaction takes in a block, what fee rates and the block size limit are measured in
+
+    pub fn size(&self) -> usize {
+
+        self.consensus_bytes().len()
+    }
+
+
+    // whether the transaction may be replaced in the mempool by one paying more,
+    // which it opts in to with the sequence of any of its inputs
+
+    pub fn signals_replacement(&self) -> bool {
+
+        self.inputs.iter().any(|input| input.sequence <= SEQUENCE_REPLACEABLE)
+    }
+
+
+    // the hash an input signs, see SigHashType for what it commits to
+
+    pub fn signature_hash(
+        &self,
+        input_index: usize,
+        spent_output: &TransactionOutput,
+        sighash_type: SigHashType,
+    ) -> Result<Hash> {
+
+        let inputs: Vec<(OutPoint, u32)> = self.inputs.iter().map(|input| (input.prev_output, input.sequence)).collect();
+
+        Self::compute_signature_hash(&inputs, &self.outputs, input_index, spent_output, sighash_type)
+    }
+
+
+    // same as signature_hash, but works on the (outpoint, sequence) of the inputs and the outputs of a
+    // transaction that is still being built, as the signatures themselves are never part of what is signed
+
+    pub fn compute_signature_hash(
+        inputs: &[(OutPoint, u32)],
+        outputs: &[TransactionOutput],
+        input_index: usize,
+        spent_output: &TransactionOutput,
+        sighash_type: SigHashType,
+    ) -> Result<Hash> {
+
+        if input_index >= inputs.len() {
+
+            return Err(BtcError::InvalidTransactionInput);
+        }
+
+        let signed_inputs = if sighash_type.anyone_can_pay() {
+
+            // other inputs may be added freely, so only this one is signed
+            vec![inputs[input_index]]
+
+        } else {
+
+            inputs.to_vec()
+        };
+
+        let signed_outputs = match sighash_type.base_type() {
+
+            SigHashType::ALL => outputs.iter().collect(),
+
+            SigHashType::NONE => vec![],
+
+            // unlike bitcoin we refuse to sign when there is no output with the same index
+            SigHashType::SINGLE => match outputs.get(input_index) {
+
+                Some(output) => vec![output],
+
+                None => return Err(BtcError::InvalidTransactionInput),
+            },
+
+            _ => return Err(BtcError::InvalidSignature),
+        };
+
+        // what actually gets hashed and signed for an input, in consensus encoding:
+        // version | signed inputs | input index | spent output | signed outputs | sighash type
+
+        let mut preimage = vec![CONSENSUS_ENCODING_VERSION];
+
+        signed_inputs.consensus_encode(&mut preimage);
+        (input_index as u32).consensus_encode(&mut preimage);
+        spent_output.consensus_encode(&mut preimage);
+        signed_outputs.consensus_encode(&mut preimage);
+        sighash_type.consensus_encode(&mut preimage);
+
+        Ok(Hash::hash_bytes(&preimage))
+    }
+
+
+    // verify the signature of the input at input_index, which spends spent_output
+
+    pub fn verify_input(&self, input_index: usize, spent_output: &TransactionOutput) -> bool {
+
+        let Some(input) = self.inputs.get(input_index) else {
+
+            return false;
+        };
+
+        match self.signature_hash(input_index, spent_output, input.sighash_type) {
+
+            Ok(sighash) => input.signature.verify(&sighash, &spent_output.pubkey),
+
+            Err(_) => false,
+        }
+    }
+
+
+    // the outpoints of all outputs of this transaction, paired with the outputs
+
+    pub fn outpoints(&self) -> impl Iterator<Item = (OutPoint, &TransactionOutput)> {
+
+        let txid = self.hash();
+
+        self.outputs
+            .iter()
+            .enumerate()
+            .map(move |(vout, output)| (OutPoint::new(txid, vout as u32), output))
+    }
 }
 
 
@@ -81,14 +213,83 @@ impl Transaction {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionInput {
 
-// the hash of the transaction output, which
-// we are linking into this transaction as input. Real bitcoin uses a slightly dif-
-// ferent scheme - it stores the previous transaction hash, and the index of the
+// the output we are linking into this transaction as input,
+// identified like in real bitcoin by the previous transaction hash and the index of the
 // output in that transaction.
 
-    pub prev_transaction_output_hash: Hash,
+    pub prev_output: OutPoint,
+
+    // like bitcoin, a sequence up to SEQUENCE_REPLACEABLE lets the transaction be replaced in the mempool
+    pub sequence: u32,
+
     pub signature: Signature,
 
+    // which parts of the spending transaction the signature commits to
+    pub sighash_type: SigHashType,
+
+}
+
+
+// bitcoin style sighash flags.
+// the signature always commits to the spent output (value and key) and the index of the input,
+// and to the following parts of the spending transaction:
+//      ALL: every input and every output
+//      NONE: every input, but none of the outputs
+//      SINGLE: every input, and only the output with the same index as the input
+// ANYONECANPAY can be combined with any of them and restricts the signed inputs to the input itself.
+// a signed input is its outpoint and its sequence
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SigHashType(pub u8);
+
+
+impl SigHashType {
+
+    pub const ALL: SigHashType = SigHashType(0x01);
+    pub const NONE: SigHashType = SigHashType(0x02);
+    pub const SINGLE: SigHashType = SigHashType(0x03);
+
+    pub const ANYONECANPAY: u8 = 0x80;
+
+    pub fn with_anyone_can_pay(self) -> Self {
+
+        SigHashType(self.0 | Self::ANYONECANPAY)
+    }
+
+    pub fn anyone_can_pay(&self) -> bool {
+
+        self.0 & Self::ANYONECANPAY != 0
+    }
+
+    // the type without the ANYONECANPAY flag
+
+    pub fn base_type(&self) -> SigHashType {
+
+        SigHashType(self.0 & !Self::ANYONECANPAY)
+    }
+}
+
+
+// points at a single output of a transaction: (txid, vout)
+// this is the key of the utxo set
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+
+    pub txid: Hash,
+    pub vout: u32,
+}
+
+
+impl OutPoint {
+
+    pub fn new(txid: Hash, vout: u32) -> Self {
+
+        OutPoint {
+            txid,
+            vout,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -111,3 +312,137 @@ impl TransactionOutput {
         Hash::hash(self)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+
+    fn output(key: &PrivateKey, value: u64) -> TransactionOutput {
+
+        TransactionOutput { value, unique_id: Uuid::new_v4(), pubkey: key.public_key() }
+    }
+
+
+    fn outpoint(vout: u32) -> OutPoint {
+
+        OutPoint::new(Hash::hash(&vout), vout)
+    }
+
+
+    // a transaction spending spent with input index signed as sighash_type.
+    // the other inputs carry the same signature, only input index is checked
+
+    fn signed(
+        key: &PrivateKey,
+        spent: &[(OutPoint, TransactionOutput)],
+        outputs: Vec<TransactionOutput>,
+        index: usize,
+        sighash_type: SigHashType,
+
+    ) -> Transaction {
+
+        let signed = spent.iter().map(|(outpoint, _)| (*outpoint, SEQUENCE_FINAL)).collect::<Vec<_>>();
+
+        let sighash = Transaction::compute_signature_hash(&signed, &outputs, index, &spent[index].1, sighash_type).unwrap();
+
+        let inputs = spent.iter().map(|(outpoint, _)| TransactionInput {
+
+            prev_output: *outpoint,
+
+            sequence: SEQUENCE_FINAL,
+
+            signature: Signature::sign_hash(&sighash, key),
+
+            sighash_type,
+        });
+
+        Transaction::new(inputs.collect(), outputs)
+    }
+
+
+    #[test]
+    fn none_lets_outputs_change() {
+
+        let key = PrivateKey::new_key();
+
+        let spent = [(outpoint(0), output(&key, 100))];
+
+        for (sighash_type, still_valid) in [(SigHashType::ALL, false), (SigHashType::NONE, true)] {
+
+            let mut transaction = signed(&key, &spent, vec![output(&key, 90)], 0, sighash_type);
+
+            assert!(transaction.verify_input(0, &spent[0].1));
+
+            transaction.outputs = vec![output(&key, 50), output(&key, 40)];
+
+            assert_eq!(transaction.verify_input(0, &spent[0].1), still_valid);
+        }
+    }
+
+
+    #[test]
+    fn single_needs_an_output_at_the_same_index() {
+
+        let key = PrivateKey::new_key();
+
+        let spent = [(outpoint(0), output(&key, 100)), (outpoint(1), output(&key, 100))];
+
+        let inputs = spent.iter().map(|(outpoint, _)| (*outpoint, SEQUENCE_FINAL)).collect::<Vec<_>>();
+
+        let outputs = vec![output(&key, 150)];
+
+        assert!(matches!(
+            Transaction::compute_signature_hash(&inputs, &outputs, 1, &spent[1].1, SigHashType::SINGLE),
+            Err(BtcError::InvalidTransactionInput)
+        ));
+
+        // only its own output is signed, the others may change
+
+        let mut transaction = signed(&key, &spent, outputs, 0, SigHashType::SINGLE);
+
+        assert!(transaction.verify_input(0, &spent[0].1));
+
+        transaction.outputs.push(output(&key, 40));
+
+        assert!(transaction.verify_input(0, &spent[0].1));
+
+        transaction.outputs[0].value = 140;
+
+        assert!(!transaction.verify_input(0, &spent[0].1));
+    }
+
+
+    #[test]
+    fn anyone_can_pay_lets_inputs_be_added() {
+
+        let key = PrivateKey::new_key();
+
+        let spent = [(outpoint(0), output(&key, 100))];
+
+        let added = TransactionInput {
+
+            prev_output: outpoint(1),
+
+            sequence: SEQUENCE_FINAL,
+
+            signature: Signature::sign_hash(&Hash::zero(), &key),
+
+            sighash_type: SigHashType::ALL,
+        };
+
+        for (sighash_type, still_valid) in [(SigHashType::ALL, false), (SigHashType::ALL.with_anyone_can_pay(), true)] {
+
+            let mut transaction = signed(&key, &spent, vec![output(&key, 150)], 0, sighash_type);
+
+            assert!(transaction.verify_input(0, &spent[0].1));
+
+            transaction.inputs.push(added.clone());
+
+            assert_eq!(transaction.verify_input(0, &spent[0].1), still_valid);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 
 use crate::sha256::Hash;
-use crate::types::Transaction;
+use crate::types::{BlockHeader, Transaction};
 use serde::{Deserialize, Serialize};
 use std::io::{Read, Write, Result as IoResult};
 use std::fs::File;
@@ -20,13 +20,36 @@ impl MerkleRoot {
 
     ) -> MerkleRoot {
 
+        Self::calculate_checked(transactions).0
+    }
+
+
+    // calculate the merkle root, and report whether the transaction list is mutated.
+    //
+    // duplicating the last hash of an odd layer means [a, b, c] and [a, b, c, c] share
+    // the same root (CVE-2012-2459). a list where two siblings are equal is such a mutation
+    // (or contains a duplicated transaction), so blocks for which this returns true are rejected
+
+    pub fn calculate_checked(
+        transactions: &[Transaction],
+
+    ) -> (MerkleRoot, bool) {
+
+        // an empty list has no root, blocks without transactions are rejected elsewhere
+
+        if transactions.is_empty() {
+
+            return (MerkleRoot(Hash::zero()), false);
+        }
+
         let mut layer: Vec<Hash> = vec![];
 
         for transaction in transactions {
 
             layer.push(Hash::hash(transaction));
         }
-        
+
+        let mut mutated = false;
 
         while layer.len() > 1 {
 
@@ -36,18 +59,144 @@ impl MerkleRoot {
 
                 let left = pair[0];
 
+                if pair.len() == 2 && pair[0] == pair[1] {
+
+                    mutated = true;
+                }
+
                 // if there is no right , use the left hash again
 
-                let right = pair.get(1).unwrap_or((&pair[0]));
+                let right = pair.get(1).unwrap_or(&pair[0]);
 
-                new_layer.push(Hash::hash(&[left, *right]));
+                new_layer.push(hash_pair(&left, right));
             }
 
             layer = new_layer;
         }
 
-        MerkleRoot(layer[0])
+        (MerkleRoot(layer[0]), mutated)
+
+    }
+
+
+    // build the branch proving that the transaction at tx_index is part of the tree
+
+    pub fn build_proof(
+        transactions: &[Transaction],
+        tx_index: usize,
+
+    ) -> Option<MerkleProof> {
+
+        if tx_index >= transactions.len() {
+
+            return None;
+        }
+
+        let mut layer: Vec<Hash> = transactions.iter().map(Hash::hash).collect();
+
+        let mut index = tx_index;
+
+        let mut branch = vec![];
+
+        while layer.len() > 1 {
+
+            // the sibling of the last hash of an odd layer is the hash itself
+
+            let sibling = layer.get(index ^ 1).unwrap_or(&layer[index]);
+
+            branch.push(*sibling);
+
+            layer = layer
+                .chunks(2)
+                .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+                .collect();
+
+            index /= 2;
+        }
+
+        Some(MerkleProof {
+            tx_index: tx_index as u32,
+            branch,
+        })
+    }
+
+
+    pub fn as_hash(&self) -> Hash {
+
+        self.0
+    }
+}
+
+
+// parent = hash(left || right)
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+
+    let mut bytes = left.as_bytes().to_vec();
+
+    bytes.extend_from_slice(&right.as_bytes());
+
+    Hash::hash_bytes(&bytes)
+}
+
+
+// proof that a transaction is included in a block, without the rest of the block:
+// the sibling hashes on the way from the transaction up to the merkle root
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+
+    // position of the transaction in the block
+    pub tx_index: u32,
+
+    // sibling hashes, starting at the leaf layer
+    pub branch: Vec<Hash>,
+}
+
+
+impl MerkleProof {
+
+    // check that the transaction with txid is committed to by the merkle root of header
+
+    pub fn verify(&self, txid: Hash, header: &BlockHeader) -> bool {
+
+        // a tree of depth 32 already holds more transactions than a block can
+
+        if self.branch.len() > 32 {
+
+            return false;
+        }
+
+        let mut hash = txid;
+
+        let mut index = self.tx_index as u64;
+
+        for sibling in &self.branch {
+
+            // the index decides on which side we are at every layer
+
+            hash = if index.is_multiple_of(2) {
+
+                hash_pair(&hash, sibling)
+
+            } else {
+
+                // a right node never has itself as sibling, that would be the mutated case
+
+                if *sibling == hash {
+
+                    return false;
+                }
+
+                hash_pair(sibling, &hash)
+            };
+
+            index /= 2;
+        }
+
+        // the index must not point beyond the tree the branch describes
 
+        index == 0 && MerkleRoot(hash) == header.merkle_root
     }
 }
 
@@ -89,4 +238,102 @@ pub trait Saveable
 
         Self::load(file)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use chrono::Utc;
+    use uuid::Uuid;
+    use crate::crypto::PrivateKey;
+    use crate::error::BtcError;
+    use crate::mempool::tests::{funded_chain, mine};
+    use crate::types::{Block, TransactionOutput};
+    use crate::U256;
+
+
+    // distinct transactions, their validity does not matter to the tree
+
+    fn transactions(count: usize) -> Vec<Transaction> {
+
+        let pubkey = PrivateKey::new_key().public_key();
+
+        (0..count)
+            .map(|_| Transaction::new(vec![], vec![TransactionOutput { value: 1, unique_id: Uuid::new_v4(), pubkey: pubkey.clone() }]))
+            .collect()
+    }
+
+
+    fn header_for(root: MerkleRoot) -> BlockHeader {
+
+        BlockHeader::new(Utc::now(), 0, Hash::zero(), root, U256::MAX)
+    }
+
+
+    #[test]
+    fn proof_for_every_position() {
+
+        // odd counts duplicate the last hash on some layer
+
+        for count in 1..=9 {
+
+            let transactions = transactions(count);
+
+            let header = header_for(MerkleRoot::calculate(&transactions));
+
+            for (index, transaction) in transactions.iter().enumerate() {
+
+                let proof = MerkleRoot::build_proof(&transactions, index).unwrap();
+
+                assert!(proof.verify(transaction.hash(), &header), "{index} of {count}");
+
+                // not for another transaction, and not for a position beyond the tree
+
+                if count > 1 {
+
+                    assert!(!proof.verify(transactions[(index + 1) % count].hash(), &header));
+                }
+
+                let beyond = MerkleProof { tx_index: proof.tx_index + (1 << proof.branch.len()), ..proof };
+
+                assert!(!beyond.verify(transaction.hash(), &header));
+            }
+
+            assert!(MerkleRoot::build_proof(&transactions, count).is_none());
+        }
+    }
+
+
+    #[test]
+    fn duplicated_tail_is_rejected() {
+
+        let transactions = transactions(3);
+
+        let mut duplicated = transactions.clone();
+
+        duplicated.push(transactions[2].clone());
+
+        let (root, mutated) = MerkleRoot::calculate_checked(&transactions);
+
+        assert!(!mutated);
+
+        // CVE-2012-2459: the same root for a different list
+
+        assert_eq!(MerkleRoot::calculate_checked(&duplicated), (root, true));
+
+        // so a block claiming that root with the duplicated list is refused before its transactions are looked at
+
+        let key = PrivateKey::new_key();
+
+        let (mut blockchain, _) = funded_chain(&key);
+
+        let timestamp = blockchain.header(&blockchain.tip_hash()).unwrap().timestamp + chrono::Duration::seconds(1);
+
+        let header = BlockHeader::new(timestamp, 0, blockchain.tip_hash(), root, blockchain.params().min_target);
+
+        let block = mine(Block::new(header, duplicated));
+
+        assert!(matches!(blockchain.add_block(block), Err(BtcError::InvalidMerkleRoot)));
+    }
+}
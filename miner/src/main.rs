@@ -1,11 +1,10 @@
-use std::env;
-use std::process::exit;
 use btc_lib::crypto::PublicKey;
+use btc_lib::params::{ChainParams, Network};
 use btc_lib::types::Block;
 use btc_lib::util::Saveable;
-use btc_lib::network::Message;
+use btc_lib::connection::Connection;
+use btc_lib::network::{handshake_outbound, Message, Services, Version};
 use std::thread;
-use tokio::sync::Mutex;
 use tokio::time::{interval, Duration};
 
 use tokio::net::TcpStream;
@@ -18,12 +17,14 @@ use std::sync:: {
     atomic::{AtomicBool, Ordering}, Arc,
 };
 
+
+const USER_AGENT: &str = concat!("miner/", env!("CARGO_PKG_VERSION"));
+
 // parser is a component responsible for interpreting 
 // and processing the input provided by the user via the command lin
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
-
 struct  Cli {
 
     #[arg(short, long)]
@@ -31,13 +32,21 @@ struct  Cli {
 
     #[arg(short, long)]
     public_key_file: String,
+
+    // network the node runs on: main, testnet or regtest
+    #[arg(short, long, default_value = "testnet")]
+    network: Network,
+
+    // file with the custom chain params of the node, overrides --network
+    #[arg(long, value_name = "FILE")]
+    chain_params: Option<String>,
 }
 
 
 // Mutex<T> is a synchronization primitive 
 // that provides safe access to shared data in a concurrent context. 
-// It ensures that only one thread at a time can access the TcpStream. T
-// his is important because multiple threads may need to perform read/write operations on the stream, and the Mutex ensures thread-safety by locking access to it
+// It ensures that only one thread at a time can access the current template.
+// This is important because the mining thread and the async tasks both read and replace it, and the Mutex ensures thread-safety by locking access to it
 
 // Arc<T>: This is a thread-safe reference-counted smart pointer. 
 // It allows multiple threads to own the same data (Option<Block> in this case),
@@ -56,7 +65,9 @@ struct Miner {
 
     public_key: PublicKey,
 
-    stream: Mutex<TcpStream>,
+    params: ChainParams,
+
+    connection: Connection,
 
     current_template: Arc<std::sync::Mutex<Option<Block>>>,
 
@@ -71,17 +82,31 @@ struct Miner {
 impl Miner {
 
 
-    async fn new(address: String, public_key: PublicKey) -> Result<Self> {
+    async fn new(address: String, public_key: PublicKey, params: ChainParams) -> Result<Self> {
+
+        let mut stream = TcpStream::connect(&address).await?;
 
-        let stream = TcpStream::connect(&address).await?;
+        // the node refuses miners for another chain before handing out any template
+
+        let version = Version::new(&params, USER_AGENT, 0, Services::NONE);
+
+        let node_version = handshake_outbound(&mut stream, &version).await?;
+
+        println!("connected to {} at height {}", node_version.user_agent, node_version.best_height);
+
+        // the node has nothing to tell a miner on its own, only the replies are read
+
+        let (connection, _) = Connection::new(stream, params.magic());
 
         let (mined_block_sender, mined_block_receiver) = flume::unbounded();
 
         Ok(Self {
 
             public_key,
+
+            params,
             
-            stream: Mutex::new(stream),
+            connection,
 
             current_template: Arc::new(std::sync::Mutex::new(None,)),
 
@@ -98,11 +123,11 @@ impl Miner {
 
     async fn run(&self) -> Result<()> {
 
-        /// create new thread 
+        // create new thread 
         self.spawn_mining_thread();
 
 
-        /// this line creates a periodic timer using tokio::time::interval. The template_interval will fire once every 5 seconds
+        // this line creates a periodic timer using tokio::time::interval. The template_interval will fire once every 5 seconds
 
         let mut template_interval = interval(Duration::from_secs(5));
 
@@ -236,22 +261,22 @@ impl Miner {
 
         let message = Message::FetchTemplate(self.public_key.clone());
 
-        let mut stream_lock = self.stream.lock().await;
+        match self.connection.request(message).await? {
 
-        message.send_async(&mut *stream_lock).await?;
-       
-       // dropping the lock so that to prevent deadlock
-        drop(stream_lock);
+            Message::Template(template) => {
 
-        let mut stream_lock = self.stream.lock().await;
+                println!("Received new template with target: {}, {} transactions paying {} in fees",
+                    template.block.header.target, template.transaction_fees.len(), template.fees);
 
-        match Message::receive_async(&mut *stream_lock).await? {
+                let template = template.block;
 
-            Message::Template(template) => {
+                // a node on another network (or a broken one) could hand us a target
+                // that no node of our network would accept
 
-                drop(stream_lock);
+                if template.header.target > self.params.min_target {
 
-                println!("Received new template with target: {}", template.header.target);
+                    return Err(anyhow!("template target is easier than the {} minimum target", self.params.network));
+                }
 
                 *self.current_template.lock().unwrap() = Some(template);
 
@@ -271,25 +296,19 @@ impl Miner {
 
     async fn validate_template(&self) -> Result<()> {
 
-        if let Some(template) = self.current_template.lock().unwrap().clone() {
+        // clone the template out first so the std mutex guard is not held across an await
 
-            let message = Message::ValidateTemplate(template);
-
-            let mut stream_lock = self.stream.lock().await;
-            
-            message.send_async(&mut *stream_lock).await?;
+        let template = self.current_template.lock().unwrap().clone();
 
-            drop(stream_lock);
+        if let Some(template) = template {
 
-            let mut stream_lock = self.stream.lock().await;
+            let message = Message::ValidateTemplate(template);
 
-            match Message::receive_async(&mut *stream_lock).await? {
+            match self.connection.request(message).await? {
 
 
                 Message::TemplateValidity(valid) => {
 
-                    drop(stream_lock);
-
                     if !valid {
 
                         println!("Current template is no longer valid");
@@ -324,9 +343,7 @@ impl Miner {
 
         let message = Message::SubmitTemplate(block);
 
-        let mut stream_lock = self.stream.lock().await;
-
-        message.send_async(&mut *stream_lock).await?;
+        self.connection.notify(&message).await?;
 
         self.mining.store(false, Ordering::Relaxed);
 
@@ -355,7 +372,14 @@ async fn main() -> Result<()> {
 
         })?;
 
-    let miner = Miner::new(cli.address, public_key).await?;
+    let params = match &cli.chain_params {
+
+        Some(path) => ChainParams::load_from_file(path)?,
+
+        None => ChainParams::for_network(cli.network),
+    };
+
+    let miner = Miner::new(cli.address, public_key, params).await?;
 
     miner.run().await
 
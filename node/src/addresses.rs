@@ -0,0 +1,913 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rand::seq::{IteratorRandom, SliceRandom};
+use serde::{Deserialize, Serialize};
+use static_init::dynamic;
+use btc_lib::network::{Message, PeerAddress, Version, MAX_ADDRESSES};
+use btc_lib::sha256::Hash;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+
+// the addresses of other nodes, and how they get around.
+//
+// addresses are kept in two tables: new ones that others told us about, and tried ones we
+// connected to ourselves. both tables are split into buckets of BUCKET_SIZE addresses. the bucket
+// of an address depends on its network group (the /16 of an ipv4 address), and for a new address
+// also on the group of the peer that told us, hashed with a secret key so nobody can aim for a
+// bucket. a peer flooding us with addresses, or someone owning many addresses in a few networks,
+// can only push out the addresses of a few buckets, and the tried ones are not touched by gossip at all.
+//
+// nodes tell the peers they connect to on which port they listen, those pass the address on,
+// and every node keeps passing on the addresses of the peers it is connected to
+
+const NEW_BUCKETS: u64 = 256;
+
+const TRIED_BUCKETS: u64 = 64;
+
+const BUCKET_SIZE: usize = 64;
+
+// how many buckets the addresses from one group of peers, or the tried addresses of one group, spread over
+const BUCKETS_PER_GROUP: u64 = 8;
+
+// addresses not seen for longer are not passed on, and forgotten unless they are tried ones
+const ADDRESS_HORIZON: chrono::Duration = chrono::Duration::days(30);
+
+// a later sighting only counts when it is this much newer. this is also what stops
+// gossip from going around in circles, only addresses that changed are passed on
+const UPDATE_INTERVAL: chrono::Duration = chrono::Duration::minutes(20);
+
+// addresses a peer sent on its own are passed on to this many other peers, if there are at most MAX_RELAYED of them.
+// a longer list is somebody's whole table, not news
+const RELAY_FANOUT: usize = 2;
+
+const MAX_RELAYED: usize = 10;
+
+// the first retry of a failed address, doubled with every failure since the last success
+const RETRY_DELAY: chrono::Duration = chrono::Duration::seconds(5);
+
+const MAX_RETRY_DELAY: chrono::Duration = chrono::Duration::hours(1);
+
+// a new address that failed this often without ever working is forgotten
+const MAX_FAILURES: u32 = 10;
+
+const ADDRESS_BOOK_FILE: &str = "peers.dat";
+
+
+#[dynamic]
+static BOOK: Mutex<AddressBook> = Mutex::new(AddressBook {
+    path: None,
+    key: rand::random(),
+    addresses: HashMap::new(),
+    buckets: HashMap::new(),
+    ours: HashSet::new(),
+    dirty: false,
+});
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+enum Table {
+
+    New,
+
+    Tried,
+}
+
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct KnownAddress {
+
+    last_seen: DateTime<Utc>,
+
+    last_connected: Option<DateTime<Utc>>,
+
+    // failed attempts since the last successful one
+    failures: u32,
+
+    // not dialed before this time
+    retry_at: DateTime<Utc>,
+
+    table: Table,
+
+    bucket: u64,
+}
+
+
+struct AddressBook {
+
+    // None until open is called, nothing is saved before that
+    path: Option<PathBuf>,
+
+    // picks the buckets, kept with the addresses so they stay in their buckets after a restart
+    key: [u8; 32],
+
+    addresses: HashMap<String, KnownAddress>,
+
+    // the addresses in every bucket, follows from the addresses
+    buckets: HashMap<(Table, u64), Vec<String>>,
+
+    // addresses that turned out to be our own, gossip brings them back to us
+    ours: HashSet<String>,
+
+    dirty: bool,
+}
+
+
+impl AddressBook {
+
+    fn keyed_hash(&self, parts: &[&[u8]]) -> u64 {
+
+        let mut data = self.key.to_vec();
+
+        for part in parts {
+
+            data.extend_from_slice(&(part.len() as u32).to_le_bytes());
+
+            data.extend_from_slice(part);
+        }
+
+        u64::from_le_bytes(Hash::hash_bytes(&data).as_bytes()[..8].try_into().unwrap())
+    }
+
+
+    fn new_bucket(&self, address: &str, source: &str) -> u64 {
+
+        let source_group = group(source);
+
+        let slot = self.keyed_hash(&[group(address).as_bytes(), source_group.as_bytes()]) % BUCKETS_PER_GROUP;
+
+        self.keyed_hash(&[source_group.as_bytes(), &slot.to_le_bytes()]) % NEW_BUCKETS
+    }
+
+
+    fn tried_bucket(&self, address: &str) -> u64 {
+
+        let slot = self.keyed_hash(&[address.as_bytes()]) % BUCKETS_PER_GROUP;
+
+        self.keyed_hash(&[group(address).as_bytes(), &slot.to_le_bytes()]) % TRIED_BUCKETS
+    }
+
+
+    // put an address into its bucket. a full bucket of new addresses makes room by forgetting the
+    // one seen longest ago, unless that is this one. a full bucket of tried addresses moves the one
+    // connected longest ago back to the new ones
+
+    fn insert(&mut self, address: String, known: KnownAddress) -> bool {
+
+        let bucket = (known.table, known.bucket);
+
+        let members = self.buckets.get(&bucket).map(Vec::as_slice).unwrap_or_default();
+
+        if members.len() >= BUCKET_SIZE {
+
+            let oldest = members.iter()
+                .min_by_key(|member| {
+
+                    let member = &self.addresses[*member];
+
+                    match known.table {
+
+                        Table::New => Some(member.last_seen),
+
+                        Table::Tried => member.last_connected,
+                    }
+                })
+                .expect("bug: full bucket without addresses")
+                .clone();
+
+            if known.table == Table::New && self.addresses[&oldest].last_seen > known.last_seen {
+
+                return false;
+            }
+
+            let evicted = self.remove(&oldest).expect("bug: bucket member without an entry");
+
+            if known.table == Table::Tried {
+
+                let bucket = self.new_bucket(&oldest, &oldest);
+
+                self.insert(oldest, KnownAddress { table: Table::New, bucket, ..evicted });
+            }
+        }
+
+        self.buckets.entry(bucket).or_default().push(address.clone());
+
+        self.addresses.insert(address, known);
+
+        self.dirty = true;
+
+        true
+    }
+
+
+    fn remove(&mut self, address: &str) -> Option<KnownAddress> {
+
+        let known = self.addresses.remove(address)?;
+
+        if let Some(members) = self.buckets.get_mut(&(known.table, known.bucket)) {
+
+            members.retain(|member| member != address);
+        }
+
+        self.dirty = true;
+
+        Some(known)
+    }
+
+
+    // true if the address is new to us or was seen again since we last heard of it
+
+    fn add(&mut self, address: String, last_seen: DateTime<Utc>, source: &str) -> bool {
+
+        let now = Utc::now();
+
+        let last_seen = last_seen.min(now);
+
+        if now - last_seen > ADDRESS_HORIZON || self.ours.contains(&address) {
+
+            return false;
+        }
+
+        if let Some(known) = self.addresses.get_mut(&address) {
+
+            if last_seen - known.last_seen < UPDATE_INTERVAL {
+
+                return false;
+            }
+
+            known.last_seen = last_seen;
+
+            self.dirty = true;
+
+            return true;
+        }
+
+        let bucket = self.new_bucket(&address, source);
+
+        self.insert(address, KnownAddress {
+            last_seen,
+            last_connected: None,
+            failures: 0,
+            retry_at: now,
+            table: Table::New,
+            bucket,
+        })
+    }
+
+
+    // up to count addresses that may be dialed now, from different groups than the peers we
+    // dialed already, so no single network can take all of our outbound connections.
+    // tried and new addresses are picked about equally often
+
+    fn candidates(&self, count: usize) -> Vec<String> {
+
+        let now = Utc::now();
+
+        let mut groups = HashSet::new();
+
+        let mut connected = HashSet::new();
+
+        for peer in crate::NODES.iter() {
+
+            if !peer.value().inbound {
+
+                groups.insert(group(peer.key()));
+            }
+
+            connected.insert(peer.key().clone());
+
+            connected.extend(peer.value().address.clone());
+        }
+
+        let mut rng = rand::thread_rng();
+
+        let mut tables = [Table::New, Table::Tried].map(|table| {
+
+            let mut addresses = self.addresses.iter()
+                .filter(|(address, known)| known.table == table && known.retry_at <= now && !connected.contains(*address))
+                .map(|(address, _)| address)
+                .collect::<Vec<_>>();
+
+            addresses.shuffle(&mut rng);
+
+            addresses
+        });
+
+        let mut candidates = vec![];
+
+        while candidates.len() < count {
+
+            let table = match tables.iter().filter(|addresses| !addresses.is_empty()).count() {
+
+                0 => break,
+
+                1 => tables.iter_mut().find(|addresses| !addresses.is_empty()).unwrap(),
+
+                _ => &mut tables[rand::random::<bool>() as usize],
+            };
+
+            let address = table.pop().unwrap();
+
+            if crate::bans::is_banned(address) || !groups.insert(group(address)) {
+
+                continue;
+            }
+
+            candidates.push(address.clone());
+        }
+
+        candidates
+    }
+
+
+    fn connected(&mut self, address: &str) {
+
+        if self.remove(address).is_none() {
+
+            return;
+        }
+
+        let now = Utc::now();
+
+        let bucket = self.tried_bucket(address);
+
+        self.insert(address.to_owned(), KnownAddress {
+            last_seen: now,
+            last_connected: Some(now),
+            failures: 0,
+            retry_at: now + retry_delay(0),
+            table: Table::Tried,
+            bucket,
+        });
+    }
+
+
+    fn failed(&mut self, address: &str) {
+
+        let Some(known) = self.addresses.get_mut(address) else {
+
+            return;
+        };
+
+        known.failures = known.failures.saturating_add(1);
+
+        known.retry_at = Utc::now() + retry_delay(known.failures);
+
+        let hopeless = known.table == Table::New && known.last_connected.is_none() && known.failures >= MAX_FAILURES;
+
+        self.dirty = true;
+
+        if hopeless {
+
+            self.remove(address);
+        }
+    }
+
+
+    // new addresses nobody has seen for a long time. tried ones stay, they are our way back into the network
+
+    fn remove_stale(&mut self) {
+
+        let now = Utc::now();
+
+        let stale = self.addresses.iter()
+            .filter(|(_, known)| known.table == Table::New && now - known.last_seen > ADDRESS_HORIZON)
+            .map(|(address, _)| address.clone())
+            .collect::<Vec<_>>();
+
+        for address in stale {
+
+            self.remove(&address);
+        }
+    }
+
+
+    // written to a temporary file first, so a crash never leaves half an address book behind
+
+    fn save(&mut self) -> Result<()> {
+
+        let Some(path) = &self.path else {
+
+            return Ok(());
+        };
+
+        if !self.dirty {
+
+            return Ok(());
+        }
+
+        let tmp_path = path.with_extension("tmp");
+
+        let file = File::create(&tmp_path)?;
+
+        ciborium::into_writer(&(&self.key, &self.addresses), &file)?;
+
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, path)?;
+
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+
+fn retry_delay(failures: u32) -> chrono::Duration {
+
+    (RETRY_DELAY * (1 << failures.min(16))).min(MAX_RETRY_DELAY)
+}
+
+
+// whether an address can be reached from anywhere, rather than only from the same machine or local network
+
+fn is_routable(ip: IpAddr) -> bool {
+
+    match ip.to_canonical() {
+
+        IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+            || ip.is_broadcast() || ip.is_documentation()),
+
+        IpAddr::V6(ip) => !(ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_unicast_link_local()),
+    }
+}
+
+
+// the network an address belongs to, the /16 of an ipv4 address and the /32 of an ipv6 one.
+// addresses that are not routable are a group of their own, so a test network on one machine still works
+
+fn group(address: &str) -> String {
+
+    let Ok(socket) = address.parse::<SocketAddr>() else {
+
+        return address.to_owned();
+    };
+
+    let ip = socket.ip().to_canonical();
+
+    if !is_routable(ip) {
+
+        return socket.to_string();
+    }
+
+    match ip {
+
+        IpAddr::V4(ip) => {
+
+            let [a, b, _, _] = ip.octets();
+
+            format!("{a}.{b}")
+        }
+
+        IpAddr::V6(ip) => {
+
+            let segments = ip.segments();
+
+            format!("{:x}:{:x}", segments[0], segments[1])
+        }
+    }
+}
+
+
+// whether an address from the gossip of source is worth keeping
+
+fn acceptable(address: &str, source: &str) -> bool {
+
+    let Ok(socket) = address.parse::<SocketAddr>() else {
+
+        return false;
+    };
+
+    if socket.port() == 0 || socket.ip().is_unspecified() {
+
+        return false;
+    }
+
+    // the addresses of a local network only mean something to nodes on it. a node we dialed
+    // by name was given to us by whoever runs this one, its addresses are trusted as well
+
+    let source_routable = source.parse::<SocketAddr>().is_ok_and(|source| is_routable(source.ip()));
+
+    is_routable(socket.ip()) || !source_routable
+}
+
+
+// load the addresses of earlier runs
+
+pub fn open<P: AsRef<Path>>(data_dir: P) {
+
+    let path = data_dir.as_ref().join(ADDRESS_BOOK_FILE);
+
+    let stored: Option<([u8; 32], HashMap<String, KnownAddress>)> = match File::open(&path) {
+
+        Ok(file) => match ciborium::from_reader(file) {
+
+            Ok(stored) => Some(stored),
+
+            Err(e) => {
+
+                println!("ignoring unreadable address book {}: {e}", path.display());
+
+                None
+            }
+        },
+
+        Err(_) => None,
+    };
+
+    let mut book = BOOK.lock().unwrap();
+
+    book.path = Some(path);
+
+    if let Some((key, addresses)) = stored {
+
+        book.key = key;
+
+        book.buckets = HashMap::new();
+
+        for (address, known) in &addresses {
+
+            book.buckets.entry((known.table, known.bucket)).or_default().push(address.clone());
+        }
+
+        book.addresses = addresses;
+    }
+
+    let tried = book.addresses.values()
+        .filter(|known| known.table == Table::Tried)
+        .count();
+
+    println!("{} known peer addresses, {} of them tried", book.addresses.len(), tried);
+}
+
+
+// an address given on the command line, it vouches for itself
+
+pub fn add_seed(address: &str) {
+
+    BOOK.lock().unwrap().add(address.to_owned(), Utc::now(), address);
+}
+
+
+pub fn candidates(count: usize) -> Vec<String> {
+
+    BOOK.lock().unwrap().candidates(count)
+}
+
+
+// we got through to the address, it moves to the tried ones
+
+pub fn connected(address: &str) {
+
+    BOOK.lock().unwrap().connected(address);
+}
+
+
+pub fn failed(address: &str) {
+
+    BOOK.lock().unwrap().failed(address);
+}
+
+
+// one of our own addresses, it is never dialed again
+
+pub fn ours(address: &str) {
+
+    let mut book = BOOK.lock().unwrap();
+
+    book.remove(address);
+
+    book.ours.insert(address.to_owned());
+}
+
+
+pub fn save() -> Result<()> {
+
+    let mut book = BOOK.lock().unwrap();
+
+    book.remove_stale();
+
+    book.save()
+}
+
+
+// the answer to GetAddresses, a random sample of everything recent we know
+
+pub fn sample() -> Vec<PeerAddress> {
+
+    let book = BOOK.lock().unwrap();
+
+    let now = Utc::now();
+
+    book.addresses.iter()
+        .filter(|(_, known)| now - known.last_seen <= ADDRESS_HORIZON)
+        .map(|(address, known)| PeerAddress { address: address.clone(), last_seen: known.last_seen })
+        .choose_multiple(&mut rand::thread_rng(), MAX_ADDRESSES)
+}
+
+
+// where a node that connected to us can be dialed, the address it came from with the port it listens on
+
+pub fn listen_address(remote: SocketAddr, version: &Version) -> Option<String> {
+
+    version.listen_port.map(|port| SocketAddr::new(remote.ip(), port).to_string())
+}
+
+
+// addresses a peer sent us. the ones that are news are passed on if relay is set and there are only a few,
+// the answer to our own GetAddresses is not
+
+pub fn received(source: &str, addresses: Vec<PeerAddress>, relay: bool) {
+
+    let relay = relay && addresses.len() <= MAX_RELAYED;
+
+    let now = Utc::now();
+
+    let mut news = vec![];
+
+    {
+        let mut book = BOOK.lock().unwrap();
+
+        for mut peer_address in addresses {
+
+            if !acceptable(&peer_address.address, source) {
+
+                continue;
+            }
+
+            // nobody has seen anything in the future
+            peer_address.last_seen = peer_address.last_seen.min(now);
+
+            if book.add(peer_address.address.clone(), peer_address.last_seen, source) && relay {
+
+                news.push(peer_address);
+            }
+        }
+    }
+
+    // only recent sightings are news, the others were known long enough to have spread already
+
+    news.retain(|peer_address| now - peer_address.last_seen < UPDATE_INTERVAL);
+
+    let peers = crate::NODES.iter()
+        .filter(|x| x.key() != source)
+        .map(|x| (x.key().clone(), x.value().connection.clone()))
+        .choose_multiple(&mut rand::thread_rng(), RELAY_FANOUT);
+
+    if news.is_empty() || peers.is_empty() {
+
+        return;
+    }
+
+    let message = Message::Addresses(news);
+
+    tokio::spawn(async move {
+
+        for (name, connection) in peers {
+
+            if let Err(e) = connection.notify(&message).await {
+
+                println!("failed to pass addresses on to {}: {e}", name);
+            }
+        }
+    });
+}
+
+
+// tell every peer that the other peers we are connected to are still around, a few of them at a time
+
+pub async fn advertise() {
+
+    let now = Utc::now();
+
+    let peers = crate::NODES.iter()
+        .map(|x| (x.key().clone(), x.value().clone()))
+        .collect::<Vec<_>>();
+
+    let listening = peers.iter()
+        .filter_map(|(_, peer)| peer.address.clone())
+        .collect::<Vec<_>>();
+
+    let messages = {
+
+        let mut rng = rand::thread_rng();
+
+        peers.into_iter()
+            .map(|(name, peer)| {
+
+                let addresses = listening.iter()
+                    .filter(|address| Some(*address) != peer.address.as_ref())
+                    .map(|address| PeerAddress { address: address.clone(), last_seen: now })
+                    .choose_multiple(&mut rng, MAX_RELAYED);
+
+                (name, peer, addresses)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for (name, peer, addresses) in messages {
+
+        if addresses.is_empty() {
+
+            continue;
+        }
+
+        if let Err(e) = peer.connection.notify(&Message::Addresses(addresses)).await {
+
+            println!("failed to send addresses to {}: {e}", name);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    fn book() -> AddressBook {
+
+        AddressBook {
+            path: None,
+            key: [7; 32],
+            addresses: HashMap::new(),
+            buckets: HashMap::new(),
+            ours: HashSet::new(),
+            dirty: false,
+        }
+    }
+
+
+    fn tried(last_connected: DateTime<Utc>, bucket: u64) -> KnownAddress {
+
+        KnownAddress {
+            last_seen: last_connected,
+            last_connected: Some(last_connected),
+            failures: 0,
+            retry_at: last_connected,
+            table: Table::Tried,
+            bucket,
+        }
+    }
+
+
+    #[test]
+    fn buckets_of_a_group() {
+
+        let mut book = book();
+
+        let source = "5.6.7.8:9000";
+
+        // one source can only fill a few new buckets, however many networks it names
+
+        for a in 1..=100 {
+
+            for b in 0..4 {
+
+                assert!(book.add(format!("{a}.{b}.1.1:9000"), Utc::now(), source));
+            }
+        }
+
+        let buckets = book.addresses.values().map(|known| known.bucket).collect::<HashSet<_>>();
+
+        assert!(buckets.len() <= BUCKETS_PER_GROUP as usize);
+
+        // and the same network from the same source always lands in the same bucket
+
+        assert_eq!(book.new_bucket("1.0.9.9:9000", source), book.addresses["1.0.1.1:9000"].bucket);
+
+        // the tried addresses of one network spread over a few buckets as well
+
+        let mut buckets = HashSet::new();
+
+        for c in 0..=255 {
+
+            let address = format!("1.0.{c}.1:9000");
+
+            book.add(address.clone(), Utc::now(), source);
+
+            book.connected(&address);
+
+            let known = &book.addresses[&address];
+
+            assert_eq!((known.table, known.bucket), (Table::Tried, book.tried_bucket(&address)));
+
+            buckets.insert(known.bucket);
+        }
+
+        assert!(buckets.len() <= BUCKETS_PER_GROUP as usize);
+    }
+
+
+    #[test]
+    fn failures_back_off() {
+
+        assert_eq!(retry_delay(0), RETRY_DELAY);
+
+        assert_eq!(retry_delay(3), RETRY_DELAY * 8);
+
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+
+        let mut book = book();
+
+        book.add("1.2.3.4:9000".to_owned(), Utc::now(), "5.6.7.8:9000");
+
+        book.add("1.2.3.5:9000".to_owned(), Utc::now(), "5.6.7.8:9000");
+
+        book.connected("1.2.3.5:9000");
+
+        for failures in 1..MAX_FAILURES {
+
+            let before = Utc::now();
+
+            book.failed("1.2.3.4:9000");
+
+            let known = &book.addresses["1.2.3.4:9000"];
+
+            assert_eq!(known.failures, failures);
+
+            assert!(known.retry_at >= before + retry_delay(failures));
+        }
+
+        // a new address that never worked is given up on, a tried one is kept
+
+        for _ in 0..MAX_FAILURES {
+
+            book.failed("1.2.3.5:9000");
+        }
+
+        book.failed("1.2.3.4:9000");
+
+        assert!(!book.addresses.contains_key("1.2.3.4:9000"));
+
+        assert_eq!(book.addresses["1.2.3.5:9000"].failures, MAX_FAILURES);
+
+        // working again resets the count
+
+        book.connected("1.2.3.5:9000");
+
+        assert_eq!(book.addresses["1.2.3.5:9000"].failures, 0);
+    }
+
+
+    #[test]
+    fn full_new_bucket_forgets_the_oldest() {
+
+        let mut book = book();
+
+        let now = Utc::now();
+
+        let source = "5.6.7.8:9000";
+
+        // one network from one source, all in the same bucket
+
+        for index in 0..BUCKET_SIZE {
+
+            let last_seen = now - chrono::Duration::hours(index as i64 + 1);
+
+            assert!(book.add(format!("1.2.3.{index}:9000"), last_seen, source));
+        }
+
+        let oldest = format!("1.2.3.{}:9000", BUCKET_SIZE - 1);
+
+        // older than everything in it, it is not let in
+
+        assert!(!book.add("1.2.4.1:9000".to_owned(), now - chrono::Duration::days(3), source));
+
+        assert!(book.add("1.2.4.2:9000".to_owned(), now, source));
+
+        assert!(!book.addresses.contains_key(&oldest));
+
+        assert_eq!(book.addresses.len(), BUCKET_SIZE);
+    }
+
+
+    #[test]
+    fn full_tried_bucket_moves_the_oldest_back() {
+
+        let mut book = book();
+
+        let now = Utc::now();
+
+        for index in 0..BUCKET_SIZE {
+
+            let last_connected = now - chrono::Duration::hours(index as i64 + 1);
+
+            assert!(book.insert(format!("1.2.3.{index}:9000"), tried(last_connected, 0)));
+        }
+
+        let oldest = format!("1.2.3.{}:9000", BUCKET_SIZE - 1);
+
+        assert!(book.insert("1.2.4.1:9000".to_owned(), tried(now, 0)));
+
+        assert_eq!(book.buckets[&(Table::Tried, 0)].len(), BUCKET_SIZE);
+
+        assert_eq!(book.addresses[&oldest].table, Table::New);
+
+        assert_eq!(book.addresses.len(), BUCKET_SIZE + 1);
+    }
+}
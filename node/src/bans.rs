@@ -0,0 +1,412 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use static_init::dynamic;
+use btc_lib::error::{BtcError, FrameError, RequestError};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+
+// misbehaving peers.
+//
+// every invalid thing a peer sends adds to its score, with a weight for how sure we are that an
+// honest peer would never have sent it. once the score reaches BAN_THRESHOLD the peer is
+// disconnected and its host banned for a while. scores are kept per host, since the port of a
+// connection someone else opened changes every time
+
+const BAN_THRESHOLD: u32 = 100;
+
+// unexpected or malformed messages, which a peer with a bug can send as well
+pub const PROTOCOL_VIOLATION: u32 = 20;
+
+const BANS_FILE: &str = "bans.dat";
+
+
+#[dynamic]
+static SCORES: DashMap<String, u32> = DashMap::new();
+
+#[dynamic]
+static BANS: Mutex<BanList> = Mutex::new(BanList {
+    path: None,
+    ban_time: chrono::Duration::days(1),
+    bans: HashMap::new(),
+});
+
+
+struct BanList {
+
+    // None until open is called, nothing is saved before that
+    path: Option<PathBuf>,
+
+    ban_time: chrono::Duration,
+
+    // host and when its ban ends
+    bans: HashMap<String, DateTime<Utc>>,
+}
+
+
+impl BanList {
+
+    fn remove_expired(&mut self) {
+
+        let now = Utc::now();
+
+        self.bans.retain(|_, until| *until > now);
+    }
+
+
+    // written to a temporary file first, like the address book
+
+    fn save(&self) -> Result<()> {
+
+        let Some(path) = &self.path else {
+
+            return Ok(());
+        };
+
+        let tmp_path = path.with_extension("tmp");
+
+        let file = File::create(&tmp_path)?;
+
+        ciborium::into_writer(&self.bans, &file)?;
+
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+
+// load the bans of earlier runs, later bans last ban_time seconds
+
+pub fn open<P: AsRef<Path>>(data_dir: P, ban_time: u64) {
+
+    let path = data_dir.as_ref().join(BANS_FILE);
+
+    let bans = match File::open(&path) {
+
+        Ok(file) => ciborium::from_reader(file).unwrap_or_else(|e| {
+
+            println!("ignoring unreadable ban list {}: {e}", path.display());
+
+            HashMap::new()
+        }),
+
+        Err(_) => HashMap::new(),
+    };
+
+    let mut list = BANS.lock().unwrap();
+
+    list.path = Some(path);
+
+    list.ban_time = chrono::Duration::seconds(ban_time.min(i64::MAX as u64) as i64);
+
+    list.bans = bans;
+
+    list.remove_expired();
+
+    println!("{} banned hosts", list.bans.len());
+}
+
+
+// the host part of an address, what scores and bans are kept for. connections are named by
+// their socket address, the admin tool names a bare ip. anything else, like the user agent of
+// a connection without an address, is kept as it is
+
+pub fn host(address: &str) -> String {
+
+    if let Ok(address) = address.parse::<SocketAddr>() {
+
+        return address.ip().to_string();
+    }
+
+    match address.parse::<IpAddr>() {
+
+        Ok(ip) => ip.to_string(),
+
+        Err(_) => address.to_owned(),
+    }
+}
+
+
+pub fn is_banned(address: &str) -> bool {
+
+    let mut list = BANS.lock().unwrap();
+
+    list.remove_expired();
+
+    list.bans.contains_key(&host(address))
+}
+
+
+// hosts that are banned, and until when
+
+pub fn list() -> Vec<(String, DateTime<Utc>)> {
+
+    let mut list = BANS.lock().unwrap();
+
+    list.remove_expired();
+
+    list.bans.iter()
+        .map(|(host, until)| (host.clone(), *until))
+        .collect()
+}
+
+
+// ban a host for this many seconds, or for the configured time if None, and drop its connections
+
+pub fn ban(address: &str, seconds: Option<u64>) {
+
+    let banned = host(address);
+
+    {
+        let mut list = BANS.lock().unwrap();
+
+        let duration = match seconds {
+
+            Some(seconds) => chrono::Duration::seconds(seconds.min(i64::MAX as u64) as i64),
+
+            None => list.ban_time,
+        };
+
+        let until = Utc::now().checked_add_signed(duration).unwrap_or(DateTime::<Utc>::MAX_UTC);
+
+        list.bans.insert(banned.clone(), until);
+
+        if let Err(e) = list.save() {
+
+            println!("failed to save the ban list: {e}");
+        }
+    }
+
+    SCORES.remove(&banned);
+
+    let names = crate::NODES.iter()
+        .filter(|x| host(x.key()) == banned)
+        .map(|x| x.key().clone())
+        .collect::<Vec<_>>();
+
+    for name in names {
+
+        crate::util::disconnect(&name);
+    }
+}
+
+
+pub fn unban(address: &str) -> bool {
+
+    let mut list = BANS.lock().unwrap();
+
+    let removed = list.bans.remove(&host(address)).is_some();
+
+    if removed {
+
+        if let Err(e) = list.save() {
+
+            println!("failed to save the ban list: {e}");
+        }
+    }
+
+    removed
+}
+
+
+// add to the score of a peer, true if that got it banned and the connection should be closed
+
+pub fn misbehaved(address: &str, penalty: u32, reason: &str) -> bool {
+
+    if penalty == 0 {
+
+        return false;
+    }
+
+    let score = {
+
+        let mut score = SCORES.entry(host(address)).or_insert(0);
+
+        *score = score.saturating_add(penalty);
+
+        *score
+    };
+
+    println!("{} misbehaved ({reason}), score {score}", address);
+
+    if score < BAN_THRESHOLD {
+
+        return false;
+    }
+
+    println!("banning {}", host(address));
+
+    ban(address, None);
+
+    true
+}
+
+
+// a block that failed validation. blocks are checked the same way everywhere,
+// so only a block we could not place yet is not held against the sender
+
+pub fn block_penalty(error: &BtcError) -> u32 {
+
+    match error {
+
+        BtcError::DuplicateBlock | BtcError::OrphanBlock | BtcError::Storage(_) => 0,
+
+        // our clock may be the one that is off
+        BtcError::TimeTooNew => 0,
+
+        _ => BAN_THRESHOLD,
+    }
+}
+
+
+pub fn transaction_penalty(error: &BtcError) -> u32 {
+
+    match error {
+
+        // lost a race against a block or another transaction
+        BtcError::MissingInput => 0,
+
+        // policy of this node, not a rule of the chain
+        BtcError::DuplicateTransaction | BtcError::InsufficientFee | BtcError::MempoolChainTooLong => 0,
+
+        // lost against a transaction it conflicts with
+        BtcError::NotReplaceable
+        | BtcError::TooManyReplacements
+        | BtcError::ReplacementSpendsUnconfirmed
+        | BtcError::ReplacementFeeRateTooLow
+        | BtcError::ReplacementFeeTooLow => 0,
+
+        // nobody relays a signature that does not verify by accident
+        BtcError::InvalidSignature => BAN_THRESHOLD,
+
+        // spends an input twice or more than its inputs
+        BtcError::InvalidTransaction
+        | BtcError::InvalidTransactionInput
+        | BtcError::InvalidTransactionOutput => 50,
+
+        BtcError::Storage(_) => 0,
+
+        _ => PROTOCOL_VIOLATION,
+    }
+}
+
+
+pub fn frame_penalty(error: &FrameError) -> u32 {
+
+    match error {
+
+        // the connection just ended
+        FrameError::Io(_) => 0,
+
+        // could be a command of a newer protocol version
+        FrameError::UnknownCommand(_) => PROTOCOL_VIOLATION,
+
+        // limits and checksums are part of the protocol, and the network was agreed on in the handshake
+        _ => BAN_THRESHOLD,
+    }
+}
+
+
+// for what went wrong while we asked a peer for something. the only BtcErrors there come from
+// checking the headers and blocks it sent
+
+pub fn error_penalty(error: &anyhow::Error) -> u32 {
+
+    if let Some(error) = error.downcast_ref::<BtcError>() {
+
+        return block_penalty(error);
+    }
+
+    if let Some(error) = error.downcast_ref::<FrameError>() {
+
+        return frame_penalty(error);
+    }
+
+    match error.downcast_ref::<RequestError>() {
+
+        Some(RequestError::Frame(error)) => frame_penalty(error),
+
+        // timeouts and closed connections are not misbehavior
+        Some(_) => 0,
+
+        None => PROTOCOL_VIOLATION,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn host_of_address() {
+
+        assert_eq!(host("1.2.3.4:9000"), "1.2.3.4");
+
+        assert_eq!(host("1.2.3.4"), "1.2.3.4");
+
+        assert_eq!(host("[2001:db8::1]:9000"), "2001:db8::1");
+
+        // a bare ipv6 address is not cut at its last colon
+
+        assert_eq!(host("2001:db8::1"), "2001:db8::1");
+
+        assert_eq!(host("wallet/0.1.0"), "wallet/0.1.0");
+    }
+
+
+    #[test]
+    fn banned_once_the_score_reaches_the_threshold() {
+
+        for _ in 0..BAN_THRESHOLD / PROTOCOL_VIOLATION - 1 {
+
+            assert!(!misbehaved("10.0.0.1:9000", PROTOCOL_VIOLATION, "test"));
+        }
+
+        assert!(!is_banned("10.0.0.1"));
+
+        // nothing is held against a penalty of zero
+
+        assert!(!misbehaved("10.0.0.1:9000", 0, "test"));
+
+        // scores are per host, another port adds to the same one
+
+        assert!(misbehaved("10.0.0.1:9001", PROTOCOL_VIOLATION, "test"));
+
+        assert!(is_banned("10.0.0.1:9002"));
+
+        assert!(!is_banned("10.0.0.2"));
+
+        assert!(!SCORES.contains_key("10.0.0.1"));
+
+        assert!(unban("10.0.0.1:9000"));
+
+        assert!(!is_banned("10.0.0.1"));
+    }
+
+
+    #[test]
+    fn ban_expires() {
+
+        ban("10.0.1.1:9000", Some(1));
+
+        assert!(is_banned("10.0.1.1"));
+
+        assert!(list().iter().any(|(host, _)| host == "10.0.1.1"));
+
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+
+        assert!(!is_banned("10.0.1.1"));
+
+        assert!(!list().iter().any(|(host, _)| host == "10.0.1.1"));
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{bail, Result};
+use argh::FromArgs;
+use tokio::net::TcpStream;
+use btc_lib::connection::Connection;
+use btc_lib::network::{handshake_outbound, Message, Services, Version};
+use btc_lib::params::{ChainParams, Network};
+use btc_lib::util::Saveable;
+
+
+const USER_AGENT: &str = concat!("admin/", env!("CARGO_PKG_VERSION"));
+
+
+#[derive(FromArgs)]
+
+/// Manage the bans of a node running on this machine
+struct Args {
+
+    #[argh(option, default = "String::from(\"127.0.0.1:9000\")")]
+    /// address of the node
+    node: String,
+
+    #[argh(option, default = "Network::Testnet")]
+    /// network the node runs on: main, testnet or regtest
+    network: Network,
+
+    #[argh(option)]
+    /// file with the custom chain params of the node, overrides --network
+    chain_params: Option<String>,
+
+    #[argh(subcommand)]
+    command: Command,
+}
+
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+
+    List(List),
+
+    Ban(Ban),
+
+    Unban(Unban),
+}
+
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// show the banned hosts
+struct List {}
+
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "ban")]
+/// ban a host
+struct Ban {
+
+    #[argh(positional)]
+    /// host to ban, without the port
+    host: String,
+
+    #[argh(option)]
+    /// seconds the ban lasts, the node's ban time by default
+    seconds: Option<u64>,
+}
+
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "unban")]
+/// lift the ban of a host
+struct Unban {
+
+    #[argh(positional)]
+    /// host to unban
+    host: String,
+}
+
+
+#[tokio::main]
+async fn main() -> Result<()> {
+
+    let args: Args = argh::from_env();
+
+    let params = match &args.chain_params {
+
+        Some(path) => ChainParams::load_from_file(path)?,
+
+        None => ChainParams::for_network(args.network),
+    };
+
+    let mut stream = TcpStream::connect(&args.node).await?;
+
+    handshake_outbound(&mut stream, &Version::new(&params, USER_AGENT, 0, Services::NONE)).await?;
+
+    let (connection, _) = Connection::new(stream, params.magic());
+
+    let message = match args.command {
+
+        Command::List(_) => Message::FetchBans,
+
+        Command::Ban(ban) => Message::Ban(ban.host, ban.seconds),
+
+        Command::Unban(unban) => Message::Unban(unban.host),
+    };
+
+    let Message::Bans(bans) = connection.request(message).await? else {
+
+        bail!("unexpected answer from the node");
+    };
+
+    if bans.is_empty() {
+
+        println!("no banned hosts");
+    }
+
+    for (host, until) in bans {
+
+        println!("{host}\tbanned until {until}");
+    }
+
+    Ok(())
+}
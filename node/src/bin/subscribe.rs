@@ -0,0 +1,127 @@
+use anyhow::{bail, Result};
+use argh::FromArgs;
+use tokio::net::TcpStream;
+use btc_lib::connection::{Connection, Incoming};
+use btc_lib::network::{handshake_outbound, Event, Message, Services, Topic, Version};
+use btc_lib::params::{ChainParams, Network};
+use btc_lib::util::Saveable;
+
+
+const USER_AGENT: &str = concat!("subscribe/", env!("CARGO_PKG_VERSION"));
+
+
+#[derive(FromArgs)]
+
+/// Print new blocks and transactions of a node as they come
+struct Args {
+
+    #[argh(option, default = "String::from(\"127.0.0.1:9000\")")]
+    /// address of the node
+    node: String,
+
+    #[argh(option, default = "Network::Testnet")]
+    /// network the node runs on: main, testnet or regtest
+    network: Network,
+
+    #[argh(option)]
+    /// file with the custom chain params of the node, overrides --network
+    chain_params: Option<String>,
+
+    #[argh(positional, from_str_fn(parse_topic))]
+    /// topics to subscribe to: hashblock, rawblock, hashtx, rawtx or reorg
+    topics: Vec<Topic>,
+}
+
+
+fn parse_topic(topic: &str) -> Result<Topic, String> {
+
+    match topic {
+
+        "hashblock" => Ok(Topic::HashBlock),
+
+        "rawblock" => Ok(Topic::RawBlock),
+
+        "hashtx" => Ok(Topic::HashTx),
+
+        "rawtx" => Ok(Topic::RawTx),
+
+        "reorg" => Ok(Topic::Reorg),
+
+        _ => Err(format!("unknown topic {topic}")),
+    }
+}
+
+
+#[tokio::main]
+async fn main() -> Result<()> {
+
+    let args: Args = argh::from_env();
+
+    if args.topics.is_empty() {
+
+        bail!("no topics given");
+    }
+
+    let params = match &args.chain_params {
+
+        Some(path) => ChainParams::load_from_file(path)?,
+
+        None => ChainParams::for_network(args.network),
+    };
+
+    let mut stream = TcpStream::connect(&args.node).await?;
+
+    let node_version = handshake_outbound(&mut stream, &Version::new(&params, USER_AGENT, 0, Services::NONE)).await?;
+
+    if !node_version.services.contains(Services::EVENTS) {
+
+        bail!("the node does not offer events");
+    }
+
+    let (connection, mut incoming) = Connection::new(stream, params.magic());
+
+    connection.notify(&Message::Subscribe(args.topics)).await?;
+
+    while let Some(received) = incoming.recv().await {
+
+        let Incoming { message, .. } = received?;
+
+        let Message::Event(event) = message else {
+
+            bail!("unexpected {} from the node", message.command());
+        };
+
+        match event {
+
+            Event::HashBlock(hash) => println!("hashblock {hash}"),
+
+            Event::RawBlock(block) => println!("rawblock {} with {} transactions", block.hash(), block.transactions.len()),
+
+            Event::HashTx(txid) => println!("hashtx {txid}"),
+
+            Event::RawTx(transaction) => {
+
+                println!("rawtx {} with {} inputs and {} outputs", transaction.hash(), transaction.inputs.len(), transaction.outputs.len());
+            }
+
+            Event::Reorg { disconnected, connected } => {
+
+                println!("reorg: {} blocks disconnected, {} connected", disconnected.len(), connected.len());
+
+                for hash in disconnected {
+
+                    println!("  - {hash}");
+                }
+
+                for hash in connected {
+
+                    println!("  + {hash}");
+                }
+            }
+        }
+    }
+
+    println!("the node closed the connection");
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use chrono::{Duration, Utc};
+use dashmap::DashMap;
+use static_init::dynamic;
+use btc_lib::network::{Services, Version};
+
+
+// network-adjusted time, like bitcoin: our clock plus the median of how far other nodes' clocks are off.
+//
+// every node tells its time in its version. one sample is kept per host, so reconnecting or opening more
+// connections does not count twice, and the median only moves once enough nodes agree. a median beyond
+// MAX_ADJUSTMENT means our own clock is more likely wrong, that is warned about instead of followed
+
+// hosts sampled at most, later ones are ignored
+const MAX_SAMPLES: usize = 200;
+
+// samples needed before the time is adjusted at all
+const MIN_SAMPLES: usize = 5;
+
+// the most the time is adjusted by, in seconds
+const MAX_ADJUSTMENT: i64 = 70 * 60;
+
+
+// host -> seconds its clock is ahead of ours
+#[dynamic]
+static SAMPLES: DashMap<String, i64> = DashMap::new();
+
+
+// record the clock of a node we just shook hands with, and adjust the time of the chain
+
+pub async fn add_sample(name: &str, version: &Version) {
+
+    // wallets and miners are no judges of time
+
+    if version.services == Services::NONE {
+
+        return;
+    }
+
+    let host = crate::bans::host(name);
+
+    if SAMPLES.len() >= MAX_SAMPLES && !SAMPLES.contains_key(&host) {
+
+        return;
+    }
+
+    SAMPLES.insert(host, (version.timestamp - Utc::now()).num_seconds());
+
+    if SAMPLES.len() < MIN_SAMPLES {
+
+        return;
+    }
+
+    let mut offsets = SAMPLES.iter().map(|x| *x.value()).collect::<Vec<_>>();
+
+    offsets.sort();
+
+    let median = offsets[offsets.len() / 2];
+
+    let offset = if median.abs() <= MAX_ADJUSTMENT {
+
+        median
+
+    } else {
+
+        println!("the other nodes are {median} seconds off our clock, please check the time of this machine");
+
+        0
+    };
+
+    crate::BLOCKCHAIN.write().await.set_time_offset(Duration::seconds(offset));
+}
@@ -0,0 +1,264 @@
+use dashmap::DashMap;
+use static_init::dynamic;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use btc_lib::connection::Connection;
+use btc_lib::network::{Event, Message, Topic};
+use btc_lib::sha256::Hash;
+use btc_lib::types::Blockchain;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+
+// telling subscribers what happens to the chain and the mempool.
+//
+// a client sends Subscribe with the topics it wants, and gets an Event for each of them from then on.
+// events are published where a block or transaction was accepted, and every subscriber has a queue of
+// its own, so a slow one never holds up the node. one that falls too far behind is disconnected,
+// it missed events and has to catch up by asking
+
+// events waiting to be sent to one subscriber
+const SUBSCRIBER_QUEUE: usize = 1000;
+
+
+#[dynamic]
+static SUBSCRIBERS: DashMap<String, Subscriber> = DashMap::new();
+
+// tells apart the subscriptions of a connection that subscribed more than once
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+
+struct Subscriber {
+
+    id: u64,
+
+    topics: HashSet<Topic>,
+
+    sender: Sender<Message>,
+
+    connection: Arc<Connection>,
+}
+
+
+// replace the topics of a connection, no topics end its subscription
+
+pub fn subscribe(name: &str, connection: &Arc<Connection>, topics: Vec<Topic>) {
+
+    if topics.is_empty() {
+
+        unsubscribe(name, connection);
+
+        return;
+    }
+
+    let topics = topics.into_iter().collect::<HashSet<_>>();
+
+    if let Some(mut subscriber) = SUBSCRIBERS.get_mut(name) {
+
+        if Arc::ptr_eq(&subscriber.connection, connection) {
+
+            subscriber.topics = topics;
+
+            return;
+        }
+    }
+
+    println!("{} subscribed to {:?}", name, topics);
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let (sender, receiver) = mpsc::channel(SUBSCRIBER_QUEUE);
+
+    SUBSCRIBERS.insert(name.to_owned(), Subscriber { id, topics, sender, connection: connection.clone() });
+
+    tokio::spawn(forward(name.to_owned(), id, connection.clone(), receiver));
+}
+
+
+pub fn unsubscribe(name: &str, connection: &Arc<Connection>) {
+
+    SUBSCRIBERS.remove_if(name, |_, subscriber| Arc::ptr_eq(&subscriber.connection, connection));
+}
+
+
+// send the queued events of one subscriber, until its subscription ends or the connection fails
+
+async fn forward(name: String, id: u64, connection: Arc<Connection>, mut receiver: Receiver<Message>) {
+
+    while let Some(message) = receiver.recv().await {
+
+        if let Err(e) = connection.notify(&message).await {
+
+            println!("dropping subscriber {}: {e}", name);
+
+            connection.close();
+
+            break;
+        }
+    }
+
+    SUBSCRIBERS.remove_if(&name, |_, subscriber| subscriber.id == id);
+}
+
+
+fn wanted(topic: Topic) -> bool {
+
+    SUBSCRIBERS.iter().any(|x| x.value().topics.contains(&topic))
+}
+
+
+fn publish(event: Event) {
+
+    let topic = event.topic();
+
+    let mut behind = vec![];
+
+    for subscriber in SUBSCRIBERS.iter().filter(|x| x.value().topics.contains(&topic)) {
+
+        match subscriber.sender.try_send(Message::Event(event.clone())) {
+
+            Ok(()) => {}
+
+            Err(TrySendError::Full(_)) => behind.push(subscriber.key().clone()),
+
+            // the subscription is ending already
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    for name in behind {
+
+        if let Some((_, subscriber)) = SUBSCRIBERS.remove(&name) {
+
+            println!("dropping subscriber {}: too far behind", name);
+
+            subscriber.connection.close();
+        }
+    }
+}
+
+
+// publish how the active chain moved since the last call. called wherever a block was added,
+// a rejected block leaves nothing to publish
+
+pub fn chain_updated(blockchain: &mut Blockchain) {
+
+    let update = blockchain.take_update();
+
+    if SUBSCRIBERS.is_empty() {
+
+        return;
+    }
+
+    if !update.disconnected.is_empty() {
+
+        publish(Event::Reorg { disconnected: update.disconnected, connected: update.connected.clone() });
+    }
+
+    let raw = wanted(Topic::RawBlock);
+
+    for hash in update.connected {
+
+        publish(Event::HashBlock(hash));
+
+        if !raw {
+
+            continue;
+        }
+
+        match blockchain.block_by_hash(&hash) {
+
+            Ok(Some(block)) => publish(Event::RawBlock(block)),
+
+            Ok(None) => println!("bug: connected block {hash} not in the store"),
+
+            Err(e) => println!("failed to read block {hash} for the subscribers: {e}"),
+        }
+    }
+}
+
+
+// publish a transaction that was just accepted into the mempool
+
+pub fn transaction_added(blockchain: &Blockchain, txid: Hash) {
+
+    if SUBSCRIBERS.is_empty() {
+
+        return;
+    }
+
+    publish(Event::HashTx(txid));
+
+    if wanted(Topic::RawTx) {
+
+        if let Some(transaction) = blockchain.mempool_transaction(&txid) {
+
+            publish(Event::RawTx(transaction.clone()));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use btc_lib::params::ChainParams;
+    use tokio::net::{TcpListener, TcpStream};
+
+
+    // our end of a connection to a client that never reads anything
+
+    async fn connection() -> (Arc<Connection>, TcpStream) {
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let (connection, _incoming) = Connection::new(socket, ChainParams::regtest().magic());
+
+        (Arc::new(connection), client)
+    }
+
+
+    // the test runs on one thread, nothing is forwarded while it publishes
+
+    #[tokio::test]
+    async fn subscriber_falling_behind_is_dropped() {
+
+        let (slow, _slow_client) = connection().await;
+
+        let (other, _other_client) = connection().await;
+
+        subscribe("slow", &slow, vec![Topic::HashTx]);
+
+        subscribe("other", &other, vec![Topic::HashBlock]);
+
+        for _ in 0..SUBSCRIBER_QUEUE {
+
+            publish(Event::HashTx(Hash::zero()));
+        }
+
+        assert!(SUBSCRIBERS.contains_key("slow"));
+
+        assert!(!slow.is_closed());
+
+        publish(Event::HashTx(Hash::zero()));
+
+        assert!(!SUBSCRIBERS.contains_key("slow"));
+
+        assert!(slow.is_closed());
+
+        // a subscriber of other topics is left alone
+
+        assert!(SUBSCRIBERS.contains_key("other"));
+
+        assert!(!other.is_closed());
+
+        unsubscribe("other", &other);
+
+        assert!(SUBSCRIBERS.is_empty());
+    }
+}
@@ -1,34 +1,192 @@
 // 
 
-use btc_lib::sha256::Hash;
+use anyhow::{bail, Result};
 use chrono::Utc;
-use uuid::Uuid;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use crate::bans::PROTOCOL_VIOLATION;
+use crate::peers::InboundSlot;
+use crate::Peer;
 use tokio::net::TcpStream;
-use btc_lib::network::Message;
-use btc_lib::types::{
-Block, BlockHeader, Transaction, TransactionOutput,
+use tokio::time;
+use btc_lib::connection::{Connection, Incoming, IncomingReceiver};
+use btc_lib::network::{
+handshake_inbound, InventoryItem, Message, PeerAddress, Services, Version, MAX_ADDRESSES, MAX_HEADERS, MAX_HISTORY,
+MAX_INVENTORY,
 };
-use btc_lib::util::MerkleRoot;
+use btc_lib::assembler::assemble_block;
+use btc_lib::error::BtcError;
 
 
-pub async fn handle_connection(mut socket: TcpStream) {
+// the slot is held until the connection ends
 
-    loop {
+pub async fn handle_connection(mut socket: TcpStream, _slot: InboundSlot) {
 
-        // read the message from socket
-        let message = match Message::receive_async(&mut socket).await {
+    // nothing is served before both sides agreed on the chain and the protocol version
 
+    let our_version = crate::util::our_version().await;
 
-            Ok(message) => message,
+    // a peer that never finishes the handshake would hold on to its inbound slot forever
+
+    let handshake = time::timeout(crate::util::CONNECT_TIMEOUT, handshake_inbound(&mut socket, &our_version));
+
+    let peer_version = match handshake.await {
+
+        Ok(Ok(peer_version)) => peer_version,
+
+        Ok(Err(e)) => {
+
+            println!("handshake failed: {e}, closing that connection");
+
+            return;
+        }
+
+        Err(_) => {
+
+            println!("handshake timed out, closing that connection");
+
+            return;
+        }
+    };
+
+    println!("{} connected, height {}", peer_version.user_agent, peer_version.best_height);
+
+    let remote = socket.peer_addr().ok();
+
+    let name = match remote {
+
+        Some(address) => address.to_string(),
+
+        None => peer_version.user_agent.clone(),
+    };
+
+    let address = remote.and_then(|remote| crate::addresses::listen_address(remote, &peer_version));
+
+    let (connection, incoming) = Connection::new(socket, our_version.magic);
+
+    let connection = Arc::new(connection);
+
+    // other nodes join the node pool, so they get our announcements too. wallets and miners offer nothing
+
+    if peer_version.services != Services::NONE {
+
+        if crate::bans::is_banned(&name) {
+
+            println!("refusing banned {}", name);
+
+            return;
+        }
+
+        crate::clock::add_sample(&name, &peer_version).await;
+
+        // a node that listens vouches for its own address, and we pass it on
+
+        if let Some(address) = &address {
+
+            let peer_address = PeerAddress { address: address.clone(), last_seen: Utc::now() };
+
+            crate::addresses::received(&name, vec![peer_address], true);
+        }
+
+        let peer = Peer { connection: connection.clone(), version: peer_version, inbound: true, address };
+
+        crate::peers::register(name.clone(), peer);
+    }
+
+    serve(name, connection, incoming, our_version).await;
+}
+
+
+// handle what a peer sends us on its own, on connections it opened as well as on ours.
+// replies to our own requests never show up here, the connection hands them to the request
+
+pub async fn serve(name: String, connection: Arc<Connection>, incoming: IncomingReceiver, our_version: Version) {
+
+    if let Err(e) = serve_messages(&name, &connection, incoming, &our_version).await {
+
+        println!("closing connection to {}: {e}", name);
+    }
+
+    crate::events::unsubscribe(&name, &connection);
+
+    // if it is one of our peers, it is gone now
+
+    crate::NODES.remove_if(&name, |_, peer| Arc::ptr_eq(&peer.connection, &connection));
+}
+
+
+// answer a request. a message that wants an answer but was not sent as a request is a protocol error
+
+async fn answer(connection: &Connection, request: Option<u32>, message: Message) -> Result<()> {
+
+    let Some(request) = request else {
+
+        bail!("{} sent as a request without a request id", message.command());
+    };
+
+    connection.reply(request, &message).await?;
+
+    Ok(())
+}
+
+
+// count something invalid against the peer, and stop serving it once that got it banned
+
+fn penalize(name: &str, penalty: u32, reason: String) -> Result<()> {
+
+    if crate::bans::misbehaved(name, penalty, &reason) {
+
+        bail!("banned for {reason}");
+    }
+
+    Ok(())
+}
+
+
+// whether the other side runs on this machine, only then it may manage the bans
+
+fn is_local(name: &str) -> bool {
+
+    name.parse::<SocketAddr>().is_ok_and(|address| address.ip().is_loopback())
+}
+
+
+async fn serve_messages(name: &str, connection: &Arc<Connection>, mut incoming: IncomingReceiver, our_version: &Version) -> Result<()> {
+
+    let local = is_local(name);
+
+    while let Some(received) = incoming.recv().await {
+
+        let Incoming { request, message } = match received {
+
+            Ok(incoming) => incoming,
 
             Err(e) => {
 
-                println!("Invalid message from peer: {e}, closing that connection");
+                penalize(name, crate::bans::frame_penalty(&e), e.to_string())?;
 
-                return ;
+                return Err(e.into());
             }
         };
 
+        // only answer what we announced in the handshake
+
+        if !our_version.supports(&message) {
+
+            let reject = Message::Reject("service not offered".to_owned());
+
+            let _ = match request {
+
+                Some(request) => connection.reply(request, &reject).await,
+
+                None => connection.notify(&reject).await,
+            };
+
+            penalize(name, PROTOCOL_VIOLATION, format!("asked for {}, a service we do not offer", message.command()))?;
+
+            bail!("asked for {}, a service we do not offer", message.command());
+        }
+
         //         These are messages that the node sends as a response to either a miner or the wallet.
         // We should never receive them as the node, so we can just safely ignore them and
         // terminate the connection by returning from the function
@@ -36,328 +194,456 @@ pub async fn handle_connection(mut socket: TcpStream) {
         use btc_lib::network::Message::*;
         match message  {
 
-            UTXOs(_) | Template(_) | Difference(_) | TemplateValidity(_) | NodeList(_) => {
+            UTXOs(_) | Template(_) | Difference(_) | TemplateValidity(_) | Proof(_)
+            | Headers(_) | BlockNotFound(_) | Version(_) | VerAck | Reject(_) | GetData(_) | Pong(_) | Bans(_)
+            | TransactionInfo(_) | History(_) | Event(_) | SubmitResult(_) => {
 
-                println!("I am neither a miner nor a wallet ! goodbye");
+                penalize(name, PROTOCOL_VIOLATION, format!("sent us {}", message.command()))?;
 
-                return ;
+                bail!("I am neither a miner nor a wallet ! goodbye");
+            }
 
 
+            // ban management, for the admin tool on this machine
 
+            FetchBans | Ban(..) | Unban(_) if !local => {
+
+                penalize(name, PROTOCOL_VIOLATION, format!("sent {} from another machine", message.command()))?;
+
+                bail!("ban management is only allowed from this machine");
+            }
+
+            // clients that want to hear about new blocks and transactions as they come
+
+            Subscribe(topics) => crate::events::subscribe(name, connection, topics),
+
+            FetchBans => {
+
+                answer(connection, request, Bans(crate::bans::list())).await?;
+            }
+
+            Ban(host, seconds) => {
+
+                println!("banning {host} on request");
+
+                crate::bans::ban(&host, seconds);
+
+                answer(connection, request, Bans(crate::bans::list())).await?;
+            }
+
+            Unban(host) => {
+
+                if crate::bans::unban(&host) {
+
+                    println!("lifted the ban of {host}");
+                }
+
+                answer(connection, request, Bans(crate::bans::list())).await?;
             }
 
             FetchBlock(height) => {
 
                 let blockchain = crate::BLOCKCHAIN.read().await;
 
-                let Some(block) = blockchain.blocks().nth(height as usize).cloned()
+                // blocks are read from the store, a failed read is treated like an unknown height
+
+                let Ok(Some(block)) = blockchain.block(height)
 
                     else {
 
-                        return ;
+                        bail!("no block at height {height}");
                     };
 
                 let message = NewBlock(block);
 
-                message.send_async(&mut socket).await.unwrap();
+                answer(connection, request, message).await?;
             }
 
-            // DiscoverNodes { no filtering going on - we just send all nodes we know};
-           
+            // syncing nodes download the header chain first, then the blocks by hash
 
-            DiscoverNodes =>  {
+            FetchHeaders(locator) => {
 
-                let nodes = crate::NODES.iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
+                let blockchain = crate::BLOCKCHAIN.read().await;
 
-                let message = NodeList(nodes);
+                let message = Headers(blockchain.headers_after(&locator, MAX_HEADERS));
 
-                message.send_async(&mut socket).await.unwrap();
+                answer(connection, request, message).await?;
             }
 
+            FetchBlockByHash(hash) => {
 
-            // AskDifference( read and subtract)
+                let blockchain = crate::BLOCKCHAIN.read().await;
 
-            AskDifference(height) => {
+                let message = match blockchain.block_by_hash(&hash) {
 
-                let blockchain = crate::BLOCKCHAIN.read().await;
+                    Ok(Some(block)) => NewBlock(block),
 
-                let count = blockchain.blocks_height() as i32 - height as i32;
+                    Ok(None) => BlockNotFound(hash),
 
-                let message = Difference(count);
+                    Err(e) => {
+
+                        println!("failed to read block {hash}: {e}");
+
+                        BlockNotFound(hash)
+                    }
+                };
 
-                message.send_async(&mut socket).await.unwrap();
+                answer(connection, request, message).await?;
             }
 
+            // lightweight clients prove a payment with the header and the merkle branch only
 
-            //Returning UTXOs for a particular public key is a bit more involved process, as we
-            // need to filter them out, and separate them from the tags marking them
+            FetchProof(txid) => {
 
-           FetchUTXOs(key) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
 
-                println!("received request to fetch Utxos");
+                let proof = match blockchain.merkle_proof(txid) {
+
+                    Ok(proof) => proof,
+
+                    Err(e) => {
+
+                        println!("failed to build proof: {e}");
+
+                        None
+                    }
+                };
+
+                let message = Proof(proof);
+
+                answer(connection, request, message).await?;
+            }
+
+            // only asked of nodes that keep an index, the version check above turned away the rest
+
+            FetchTransaction(txid) => {
 
                 let blockchain = crate::BLOCKCHAIN.read().await;
 
-                let utxos = blockchain.utxos().iter()
-                    
-                    .filter(|( _,  ( _, txout))| {
-                       
-                        txout.pubkey == key
+                let info = match blockchain.mempool_transaction(&txid) {
 
-                    })
-                    .map(|(_, (marked, txout))| {
+                    Some(transaction) => Some((None, transaction.clone())),
 
-                        (txout.clone(), *marked)
+                    None => match blockchain.find_transaction(&txid) {
 
+                        Ok(found) => found.map(|(header, transaction)| (Some(header.hash()), transaction)),
 
-                    })
-                    .collect::<Vec<_>>();
+                        Err(e) => {
 
-                let message = UTXOs(utxos);
+                            println!("failed to read transaction {txid}: {e}");
 
-                message.send_async(&mut socket).await.unwrap();
+                            None
+                        }
+                    },
+                };
 
-            
+                answer(connection, request, TransactionInfo(info)).await?;
             }
 
+            FetchHistory(key) => {
 
-            NewBlock(block) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
 
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
+                let history = blockchain.history(&key).unwrap_or_default();
 
-                println!("received new block");
+                let recent = history[history.len().saturating_sub(MAX_HISTORY)..].to_vec();
 
-                if blockchain.add_block(block).is_err() {
+                answer(connection, request, History(recent)).await?;
+            }
 
-                    println!("block rejected");
-                }
+            Ping(nonce) => {
+
+                answer(connection, request, Pong(nonce)).await?;
             }
 
 
-            //  We are making a simplification here in that we just add it to the mempool. It would
-            // be a nice idea to send it back to other nodes that may not have it. However, we would
-            // have to add a mechanism for preventing the network from creating notification
-            // loops. You can try implementing one, if you wan
+            // GetAddresses { a sample of the address book, not just the nodes we are connected to }
 
-            NewTransaction(tx) => {
+            GetAddresses => {
 
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
+                let message = Addresses(crate::addresses::sample());
+
+                answer(connection, request, message).await?;
+            }
 
-                println!("recieved transactionfrom friend");
 
-                if blockchain.add_to_mempool(tx).is_err() {
+            // addresses other nodes pass around. a few at a time are news and go on to some of our peers
 
-                    println!("transaction rejected, closing connection");
+            Addresses(addresses) => {
 
-                    return
+                if addresses.len() > MAX_ADDRESSES {
+
+                    penalize(name, PROTOCOL_VIOLATION, format!("{} addresses", addresses.len()))?;
+
+                    bail!("{} addresses, more than {}", addresses.len(), MAX_ADDRESSES);
                 }
+
+                crate::addresses::received(name, addresses, true);
             }
 
 
-            ValidateTemplate(block_template) => {
+            // AskDifference( read and subtract)
+
+            AskDifference(height) => {
 
                 let blockchain = crate::BLOCKCHAIN.read().await;
 
-                let status = block_template.header.prev_block_hash == blockchain.blocks()
-                    .last()
-                    .map(|last_block| last_block.hash())
-                    .unwrap_or(Hash::zero());
+                let count = blockchain.blocks_height() as i32 - height as i32;
 
-                let message = TemplateValidity(status);
+                let message = Difference(count);
 
-                message.send_async(&mut socket).await.unwrap();
+                answer(connection, request, message).await?;
             }
 
 
-            // if miner send us a correctly mined block, we want to broadcast it to other nodes
+            //Returning UTXOs for a particular public key is a bit more involved process, as we
+            // need to filter them out, and separate them from the tags marking them
 
-            SubmitTemplate(block) => {
+           FetchUTXOs(key) => {
 
-                println!("received allegedly mined template");
+                println!("received request to fetch Utxos");
+
+                let blockchain = crate::BLOCKCHAIN.read().await;
 
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
+                // only the outputs of this key if the node keeps an index, the whole utxo set otherwise
 
-                if let Err(e) = blockchain.add_block(block.clone()) {
+                let utxos = blockchain.utxos_of(&key);
 
-                    println!("block rejected {e} closing connection");
+                let message = UTXOs(utxos);
+
+                answer(connection, request, message).await?;
 
-                    return;
+            
+            }
 
 
+            // another node announced something we did not have yet, ask for it.
+            // the items follow as NewTransaction and NewBlock messages on this connection
+
+            Inventory(items) => {
+
+                if items.len() > MAX_INVENTORY {
+
+                    penalize(name, PROTOCOL_VIOLATION, format!("inventory of {} items", items.len()))?;
+
+                    bail!("inventory of {} items, more than {}", items.len(), MAX_INVENTORY);
                 }
 
-                blockchain.rebuild_utxos();
+                let wanted = {
 
-                println!("blocks looks good, broadcasting");
+                    let blockchain = crate::BLOCKCHAIN.read().await;
 
-                // send all blocks to all friend nodes
+                    items.into_iter()
+                        .filter(|item| !crate::relay::seen(item))
+                        .filter(|item| match item {
 
-                let nodes = crate::NODES.iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
+                            InventoryItem::Transaction(txid) => blockchain.mempool_transaction(txid).is_none(),
 
-                for node in nodes {
+                            InventoryItem::Block(hash) => !blockchain.contains_block(hash),
+                        })
+                        .collect::<Vec<_>>()
+                };
 
-                    if let Some(mut stream) = crate::NODES.get_mut(&node) {
+                let message = GetData(wanted);
 
+                answer(connection, request, message).await?;
+            }
 
-                        let message = Message::NewBlock(block.clone());
 
-                        if message.send_async(&mut *stream).await.is_err() {
+            // blocks and transactions we accept are announced to our own peers in turn
 
-                            println!("failed to send block to {}", node);
-                        }
+            NewBlock(block) => {
+
+                println!("received new block");
+
+                let item = InventoryItem::Block(block.hash());
+
+                let added = {
+
+                    let mut blockchain = crate::BLOCKCHAIN.write().await;
+
+                    let added = blockchain.add_block(block);
+
+                    crate::events::chain_updated(&mut blockchain);
+
+                    added
+                };
+
+                // a rejected block is not marked as seen, it may only be missing its parent so far
+
+                match added {
+
+                    Ok(()) => crate::relay::announce(item),
+
+                    // we are behind the peer, its headers lead to the parent
+
+                    Err(BtcError::OrphanBlock) => crate::sync::sync_from_peer(name),
+
+                    Err(e) => {
+
+                        println!("block rejected: {e}");
+
+                        penalize(name, crate::bans::block_penalty(&e), format!("invalid block: {e}"))?;
                     }
                 }
             }
 
 
+            // a relayed transaction can be rejected without the peer doing anything wrong,
+            // it may have been mined or double spent in the meantime. the penalty depends on why it was rejected
 
-            SubmitTransaction(tx) => {
+            NewTransaction(tx) => {
 
-                println!("submit tx");
+                println!("received transaction from {name}");
 
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
+                let txid = tx.hash();
 
-                if let Err(e) = blockchain.add_to_mempool(tx.clone()) {
+                let item = InventoryItem::Transaction(txid);
 
-                    println!("transaction rejected, closing connection: {e}");
+                let added = {
 
-                    return ;
+                    let mut blockchain = crate::BLOCKCHAIN.write().await;
 
-                }
+                    let added = blockchain.add_to_mempool(tx);
 
-                println!("added transaction to mempool");
+                    if added.is_ok() {
 
-                // send  transaction to all friend nodes
+                        crate::events::transaction_added(&blockchain, txid);
+                    }
+
+                    added
+                };
+
+                match added {
 
-                let nodes = crate::NODES.iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
+                    Ok(()) => crate::relay::announce(item),
 
-                for node in nodes {
+                    Err(e) => {
+
+                        println!("transaction rejected: {e}");
 
-                    println!("sending to friend : {node }");
+                        let penalty = crate::bans::transaction_penalty(&e);
 
-                    if let Some(mut stream) = crate::NODES.get_mut(&node) {
-                    
-                        let message = Message::NewTransaction(tx.clone());
+                        // only a transaction that breaks the rules of the chain is never asked for again,
+                        // one missing an input may be a child that arrived before its parent
 
-                        if message.send_async(&mut *stream).await.is_err() {
+                        if penalty > 0 {
 
-                            println!("failed to send transaction to {}", node);
-                        
-                        
+                            crate::relay::mark_seen(item);
                         }
-                    
+
+                        penalize(name, penalty, format!("invalid transaction: {e}"))?;
                     }
                 }
             }
 
 
+            ValidateTemplate(block_template) => {
 
-            // fetching template
+                let blockchain = crate::BLOCKCHAIN.read().await;
 
-            FetchTemplate(pubkey) => {
+                let status = block_template.header.prev_block_hash == blockchain.tip_hash();
 
-                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = TemplateValidity(status);
 
-                let mut transactions = vec![];
+                answer(connection, request, message).await?;
+            }
 
-                // insert transaction from mempool
 
-                transactions.extend(
-                    
-                    blockchain
-                        .mempool()
-                        .iter()
-                        .take(btc_lib::BLOCK_TRANSACTION_CAP)
-                        .map(|(_, tx)| tx)
-                        .clone()
-                        .collect::<Vec<_>>(),
-                
-                
-                );
+            // if miner send us a correctly mined block, we want to broadcast it to other nodes
 
-                // insert coinbase tx with pubkey
-                let t = Transaction {
+            SubmitTemplate(block) => {
 
-                                inputs: vec![],
+                println!("received allegedly mined template");
 
-                                outputs: vec![ TransactionOutput {
-                                    
-                                    pubkey,
-                                    
-                                    unique_id: Uuid::new_v4(),
+                let hash = block.hash();
 
-                                    value: 0,
-                                
-                                
-                                }],
-                            };
-                transactions.insert(0, &t);
+                let added = {
 
+                    let mut blockchain = crate::BLOCKCHAIN.write().await;
 
-                let merkle_root = MerkleRoot::calculate(&transactions.iter().map(|&t| t.clone()).collect::<Vec<_>>());
+                    let added = blockchain.add_block(block);
 
-                let mut block  = Block::new(
-                    
-                    BlockHeader {
+                    crate::events::chain_updated(&mut blockchain);
 
-                                timestamp: Utc::now(),
+                    added
+                };
 
-                                prev_block_hash: blockchain.blocks()
-                                    .last()
-                                    .map(| last_block|  {
-                                    
-                                        last_block.hash()
-                                        
-                                })
-                                .unwrap_or(Hash::zero()),
+                if let Err(e) = added {
 
-                                nonce: 0,
+                    penalize(name, crate::bans::block_penalty(&e), format!("invalid block: {e}"))?;
 
-                                target: blockchain.target(),
+                    bail!("block rejected {e}");
+                }
 
-                                merkle_root,
-                                
-                            },
+                println!("blocks looks good, announcing");
 
-                       transactions.into_iter().cloned().collect(),
+                crate::relay::announce(InventoryItem::Block(hash));
+            }
 
 
-                );
 
-                let miner_fees = match block.calculate_miner_fees(blockchain.utxos()) {
-                
-                    Ok(fees) => fees,
+            SubmitTransaction(tx) => {
 
-                    Err(e) => {
-                    
-                        eprintln!("{e}");
+                println!("submit tx");
+
+                let txid = tx.hash();
 
-                        return;
+                let added = {
 
+                    let mut blockchain = crate::BLOCKCHAIN.write().await;
+
+                    let added = blockchain.add_to_mempool(tx);
+
+                    if added.is_ok() {
+
+                        crate::events::transaction_added(&blockchain, txid);
                     }
-                
+
+                    added
                 };
 
-                let reward = blockchain.calculate_block_reward();
+                match added {
+
+                    Ok(()) => {
+
+                        println!("added transaction to mempool, announcing");
+
+                        crate::relay::announce(InventoryItem::Transaction(txid));
+
+                        answer(connection, request, SubmitResult(Ok(txid))).await?;
+                    }
+
+                    // the submitter is told why, a replacement paying too little is worth another try
 
-                // update coinbase tx with reward
+                    Err(e) => {
+
+                        penalize(name, crate::bans::transaction_penalty(&e), format!("invalid transaction: {e}"))?;
 
-                block.transactions[0].outputs[0].value = reward + miner_fees;
+                        println!("transaction rejected: {e}");
 
+                        answer(connection, request, SubmitResult(Err(e))).await?;
+                    }
+                }
+            }
 
-                // recalculate merkle root
 
-                block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
 
-                let message = Template(block);
+            // fetching template
 
-                message.send_async(&mut socket).await.unwrap();
+            FetchTemplate(pubkey) => {
+
+                let blockchain = crate::BLOCKCHAIN.read().await;
+
+                // the best paying packages of mempool transactions that fit in a block
+
+                let template = assemble_block(&blockchain, pubkey);
+
+                println!("built template with {} transactions paying {} in fees", template.transaction_fees.len(), template.fees);
+
+                let message = Template(template);
+
+                answer(connection, request, message).await?;
 
                 
 
@@ -378,4 +664,6 @@ pub async fn handle_connection(mut socket: TcpStream) {
 
 
     }
-}
\ No newline at end of file
+
+    Ok(())
+}
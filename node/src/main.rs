@@ -2,18 +2,29 @@ use anyhow::Result;
 use argh::FromArgs; // handling command line arguments
 use dashmap::DashMap; // Provides a fast HashMap that is thread-safe and has interior mutability
 use static_init::dynamic; // creating global variable 
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::sync::RwLock;
 use btc_lib::types::Blockchain;
+use btc_lib::connection::Connection;
+use btc_lib::network::Version;
+use btc_lib::params::{ChainParams, Network};
+use btc_lib::util::Saveable;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 
 
 
 
 
-use std::path::Path;  // its a struct used for handling filesystem paths in a platform-independent way (different os)
-
-
+mod addresses;
+mod bans;
+mod clock;
+mod events;
 mod handler;
+mod peers;
+mod relay;
+mod rpc;
+mod sync;
 mod util;
 
 
@@ -26,13 +37,33 @@ mod util;
 //     Readers can access the data concurrently, meaning if multiple threads are only reading the data, they can do so without blocking each other.
 //     Writers have exclusive access to the data, meaning while a thread is writing, no other thread can read or write the data
 
+// starts out as a testnet chain in memory, main() replaces it with the chain stored in the data directory
+
 #[dynamic]
-pub static BLOCKCHAIN:RwLock<Blockchain> =  RwLock::new(Blockchain::new());  // Rwlock provide interior mutability
+pub static BLOCKCHAIN:RwLock<Blockchain> =  RwLock::new(Blockchain::new(ChainParams::testnet()));  // Rwlock provide interior mutability
 
 // Node pool
 
 #[dynamic]
-pub static NODES: DashMap<String, TcpStream> = DashMap::new();
+pub static NODES: DashMap<String, Peer> = DashMap::new();
+
+
+// a node we are connected to, with what it told us about itself in the handshake.
+// the connection is shared, requests from several tasks can be in flight on it at once
+
+#[derive(Clone)]
+pub struct Peer {
+
+    pub connection: Arc<Connection>,
+
+    pub version: Version,
+
+    // whether the peer opened the connection
+    pub inbound: bool,
+
+    // where the peer accepts connections, None if it does not
+    pub address: Option<String>,
+}
 
 
 
@@ -46,9 +77,49 @@ struct Args {
     /// port number
     port: u16,
 
-   #[argh(option, default = "String::from(\"./blockchain.cbor\")" )]
-    /// blockchain file locatioin  --> /// doc comment
-    blockchain_file: String,    
+   #[argh(option, default = "String::from(\"./chain_data\")" )]
+    /// directory of the block store and the utxo database
+    data_dir: String,
+
+    #[argh(option, default = "Network::Testnet")]
+    /// network to join: main, testnet or regtest
+    network: Network,
+
+    #[argh(option)]
+    /// file with custom chain params, overrides --network
+    chain_params: Option<String>,
+
+    #[argh(switch)]
+    /// keep an index of the transactions and public keys of the chain, for lookups and wallet history
+    index: bool,
+
+    #[argh(option, default = "300")]
+    /// most memory the mempool may take, in megabytes. the lowest fee rates are evicted past it
+    max_mempool: usize,
+
+    #[argh(option, default = "btc_lib::mempool::DEFAULT_MIN_RELAY_FEE")]
+    /// lowest fee rate accepted into the mempool, in satoshis per 1000 bytes
+    min_relay_fee: u64,
+
+    #[argh(option, default = "24 * 60 * 60")]
+    /// how long a misbehaving peer stays banned, in seconds
+    ban_time: u64,
+
+    #[argh(option)]
+    /// port of the JSON-RPC server, 1000 above --port if not given
+    rpc_port: Option<u16>,
+
+    #[argh(option, default = "IpAddr::from([127, 0, 0, 1])")]
+    /// address the JSON-RPC server listens on, only this machine by default
+    rpc_bind: IpAddr,
+
+    #[argh(option)]
+    /// user for the JSON-RPC server, without it a cookie file in the data directory holds the credentials
+    rpc_user: Option<String>,
+
+    #[argh(option)]
+    /// password for the JSON-RPC server
+    rpc_password: Option<String>,
 
     #[argh(positional)]
     // address of initial nodes
@@ -71,66 +142,63 @@ async fn main() -> Result<()> {
     // Access the parsed arguments
     let port = args.port;
 
-    let blockchain_file = args.blockchain_file;
+    let data_dir = args.data_dir;
 
     let nodes = args.nodes;
 
-    if Path::new(&blockchain_file).exists() {
-
+    let params = match &args.chain_params {
 
-        util::load_blockchain(&blockchain_file).await?;
+        Some(path) => ChainParams::load_from_file(path)?,
 
-        println!("total amount of known nodes: {}", NODES.len());
+        None => ChainParams::for_network(args.network),
+    };
 
-    } else {
-
-        println!("blockchain file does not exist");
-
-        util::populate_connections(&nodes).await?;
+    println!("running on {} (genesis {})", params.network, params.genesis_hash());
 
-        println!("total amount of known nodes: {}",NODES.len());
+    // blocks are written to the data directory as they are connected, there is nothing to save periodically
 
-        if nodes.is_empty() {
+    util::load_blockchain(&data_dir, params).await?;
 
-            println!("no initial nodes provided, starting as a seed node");
+    BLOCKCHAIN.write().await.configure_mempool(args.max_mempool.saturating_mul(1024 * 1024), args.min_relay_fee);
 
+    if args.index {
 
-        } else {
-
-            let (longest_name, longest_count) = util::find_longest_chain_node().await?;
-
-            // request the blockchain from the node with the longest blockchain
+        BLOCKCHAIN.write().await.enable_index(&data_dir)?;
+    }
 
-            util::download_blockchain(&longest_name, longest_count).await?;
+    // the initial nodes join the addresses known from earlier runs, none of them has to be reachable
 
-            println!("blockchain download from {}", longest_name);
+    bans::open(&data_dir, args.ban_time);
 
-            {   // recalculate the utxo
+    addresses::open(&data_dir);
 
-                let mut blockchain = BLOCKCHAIN.write().await;
-                
-                blockchain.rebuild_utxos();
+    // the port goes into our Version, so the nodes we connect to can pass our address on
 
-            }
+    let _ = util::LISTEN_PORT.set(port);
 
+    peers::start(&nodes).await;
 
-            {    // try to adjust difficulty
+    println!("total amount of known nodes: {}",NODES.len());
 
-                let mut blockchain = BLOCKCHAIN.write().await;
+    if NODES.is_empty() {
 
-                blockchain.try_adjust_target();
+        println!("no nodes reachable, starting as a seed node");
 
-            }
 
+    } else {
 
+        // headers first, then the blocks of the best chain from all peers at once.
+        // a failed sync is not fatal, the node can still serve what it has
 
+        if let Err(e) = sync::sync_from_peers().await {
 
+            println!("initial sync failed: {e}");
         }
+    }
 
+    // and again now and then, a seed node as well once others joined
 
-
-        
-    }
+    sync::start();
 
 
     // Handling the requests
@@ -148,15 +216,40 @@ async fn main() -> Result<()> {
 
     tokio::spawn(util::cleanup());
 
-    // and a task to periodically save the blockchain
+    // accepted transactions and blocks are announced to the peers from a single task
+
+    relay::start();
 
-     tokio::spawn(util::save(blockchain_file.clone()));
+    // scripts and dashboards talk JSON to the node over http
+
+    let rpc_port = args.rpc_port.unwrap_or(port.saturating_add(1000));
+
+    let credentials = rpc::credentials(args.rpc_user, args.rpc_password, &data_dir)?;
+
+    rpc::start(SocketAddr::new(args.rpc_bind, rpc_port), credentials).await?;
 
     loop {
 
-        let (socket, _) = listener.accept().await?;
+        let (socket, address) = listener.accept().await?;
+
+        // connections from this machine are checked after the handshake,
+        // the admin tool has to get through to lift a ban
+
+        if !address.ip().is_loopback() && bans::is_banned(&address.to_string()) {
+
+            println!("refusing banned {}", address);
+
+            continue;
+        }
+
+        let Some(slot) = peers::inbound_slot() else {
+
+            println!("too many inbound connections, refusing {}", address);
+
+            continue;
+        };
 
-        tokio::spawn(handler::handle_connection(socket));
+        tokio::spawn(handler::handle_connection(socket, slot));
 
 
     }
@@ -0,0 +1,244 @@
+use rand::seq::IteratorRandom;
+use tokio::time::{self, Duration};
+use btc_lib::connection::Connection;
+use btc_lib::error::HandshakeError;
+use btc_lib::network::{Message, MAX_ADDRESSES};
+use crate::bans::PROTOCOL_VIOLATION;
+use crate::Peer;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+
+// the peer manager keeps the node connected.
+//
+// it dials known addresses until TARGET_OUTBOUND of our own connections are up, accepts at most
+// MAX_INBOUND connections from others, and pings every peer so dead ones are noticed and replaced.
+// where to connect to is up to the address book
+
+const TARGET_OUTBOUND: usize = 8;
+
+const MAX_INBOUND: usize = 32;
+
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10);
+
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// how often one of our peers is asked for addresses, to hear of nodes the gossip did not bring
+const ADDRESS_REQUEST_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// how often the peers are told which of the others are still around
+const ADVERTISE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+
+// connect to the initial nodes and whatever else we know, then keep the connections up in the background
+
+pub async fn start(nodes: &[String]) {
+
+    for node in nodes {
+
+        crate::addresses::add_seed(node);
+    }
+
+    maintain(false).await;
+
+    tokio::spawn(async {
+
+        let mut interval = time::interval(MAINTENANCE_INTERVAL);
+
+        loop {
+
+            interval.tick().await;
+
+            maintain(true).await;
+        }
+    });
+
+    // the peers we just connected to were asked already, and have nobody to tell about yet
+
+    tokio::spawn(async {
+
+        let mut interval = time::interval_at(time::Instant::now() + ADDRESS_REQUEST_INTERVAL, ADDRESS_REQUEST_INTERVAL);
+
+        loop {
+
+            interval.tick().await;
+
+            let peer = crate::NODES.iter()
+                .filter(|x| !x.value().inbound)
+                .map(|x| (x.key().clone(), x.value().clone()))
+                .choose(&mut rand::thread_rng());
+
+            if let Some((name, peer)) = peer {
+
+                request_addresses(&name, &peer).await;
+            }
+        }
+    });
+
+    tokio::spawn(async {
+
+        let mut interval = time::interval_at(time::Instant::now() + ADVERTISE_INTERVAL, ADVERTISE_INTERVAL);
+
+        loop {
+
+            interval.tick().await;
+
+            crate::addresses::advertise().await;
+        }
+    });
+}
+
+
+// sync with the peers that got connected, the first time the initial sync takes care of that
+
+async fn maintain(sync: bool) {
+
+    let outbound = crate::NODES.iter()
+        .filter(|x| !x.value().inbound)
+        .count();
+
+    let missing = TARGET_OUTBOUND.saturating_sub(outbound);
+
+    for address in crate::addresses::candidates(missing) {
+
+        match crate::util::connect_peer(&address).await {
+
+            Ok(peer) => {
+
+                crate::addresses::connected(&address);
+
+                register(address.clone(), peer.clone());
+
+                // every peer we dial tells us about the nodes it knows
+
+                request_addresses(&address, &peer).await;
+
+                if sync {
+
+                    crate::sync::sync_from_peer(&address);
+                }
+            }
+
+            // one of our own addresses is never dialed again
+
+            Err(e) if matches!(e.downcast_ref(), Some(HandshakeError::SelfConnection)) => {
+
+                crate::addresses::ours(&address);
+            }
+
+            Err(e) => {
+
+                println!("not connecting to {}: {e}", address);
+
+                crate::addresses::failed(&address);
+            }
+        }
+    }
+
+    if let Err(e) = crate::addresses::save() {
+
+        println!("failed to save the address book: {e}");
+    }
+}
+
+
+async fn request_addresses(name: &str, peer: &Peer) {
+
+    match peer.connection.request(Message::GetAddresses).await {
+
+        Ok(Message::Addresses(addresses)) if addresses.len() <= MAX_ADDRESSES => {
+
+            crate::addresses::received(name, addresses, false);
+        }
+
+        Ok(message) => {
+
+            println!("unexpected answer to GetAddresses from {}", name);
+
+            if crate::bans::misbehaved(name, PROTOCOL_VIOLATION, &format!("answered GetAddresses with {}", message.command())) {
+
+                crate::util::disconnect(name);
+            }
+        }
+
+        Err(e) => println!("no addresses from {}: {e}", name),
+    }
+}
+
+
+// add a peer to the node pool and keep checking that it is alive
+
+pub fn register(name: String, peer: Peer) {
+
+    tokio::spawn(keep_alive(name.clone(), peer.connection.clone()));
+
+    crate::NODES.insert(name, peer);
+}
+
+
+async fn keep_alive(name: String, connection: Arc<Connection>) {
+
+    let mut interval = time::interval(PING_INTERVAL);
+
+    // the first tick is immediate, the peer just answered the handshake
+    interval.tick().await;
+
+    for nonce in 0u64.. {
+
+        interval.tick().await;
+
+        // a peer that went away is removed by whoever noticed it, unless it was not in the pool yet
+
+        if connection.is_closed() {
+
+            crate::NODES.remove_if(&name, |_, peer| Arc::ptr_eq(&peer.connection, &connection));
+
+            return;
+        }
+
+        let error = match connection.request(Message::Ping(nonce)).await {
+
+            Ok(Message::Pong(pong)) if pong == nonce => continue,
+
+            Ok(_) => "unexpected answer to Ping".to_owned(),
+
+            Err(e) => e.to_string(),
+        };
+
+        println!("dropping {}: {error}", name);
+
+        connection.close();
+
+        crate::NODES.remove_if(&name, |_, peer| Arc::ptr_eq(&peer.connection, &connection));
+
+        return;
+    }
+}
+
+
+// a place for a connection someone else opened, None if all are taken
+
+pub struct InboundSlot(());
+
+
+static INBOUND: AtomicUsize = AtomicUsize::new(0);
+
+
+pub fn inbound_slot() -> Option<InboundSlot> {
+
+    INBOUND.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |inbound| {
+
+        (inbound < MAX_INBOUND).then_some(inbound + 1)
+    })
+    .ok()
+    .map(|_| InboundSlot(()))
+}
+
+
+impl Drop for InboundSlot {
+
+    fn drop(&mut self) {
+
+        INBOUND.fetch_sub(1, Ordering::SeqCst);
+    }
+}
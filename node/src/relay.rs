@@ -0,0 +1,191 @@
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use anyhow::{anyhow, bail, Result};
+use static_init::dynamic;
+use btc_lib::network::{InventoryItem, Message, MAX_INVENTORY};
+use crate::Peer;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Mutex, OnceLock};
+
+
+// spreading new transactions and blocks over the network.
+//
+// items are announced by hash (Inventory) and only sent to the peers that ask for them (GetData).
+// every node announces what it accepted in turn, so an item floods the whole network.
+// a node never asks for an item it has seen before, which is what stops the flood from going in circles
+
+// how many items are remembered, the oldest are forgotten first
+const SEEN_CAPACITY: usize = 50_000;
+
+
+static QUEUE: OnceLock<UnboundedSender<InventoryItem>> = OnceLock::new();
+
+#[dynamic]
+static SEEN: Mutex<SeenItems> = Mutex::new(SeenItems {
+    items: HashSet::new(),
+    order: VecDeque::new(),
+});
+
+
+struct SeenItems {
+
+    items: HashSet<InventoryItem>,
+
+    order: VecDeque<InventoryItem>,
+}
+
+
+impl SeenItems {
+
+    // false if the item was seen before
+
+    fn insert(&mut self, item: InventoryItem) -> bool {
+
+        if !self.items.insert(item) {
+
+            return false;
+        }
+
+        self.order.push_back(item);
+
+        if self.order.len() > SEEN_CAPACITY {
+
+            let oldest = self.order.pop_front().expect("bug: empty seen queue");
+
+            self.items.remove(&oldest);
+        }
+
+        true
+    }
+
+    fn contains(&self, item: &InventoryItem) -> bool {
+
+        self.items.contains(item)
+    }
+}
+
+
+pub fn seen(item: &InventoryItem) -> bool {
+
+    SEEN.lock().unwrap().contains(item)
+}
+
+
+// remember an item without announcing it, e.g. one that turned out to be invalid
+
+pub fn mark_seen(item: InventoryItem) -> bool {
+
+    SEEN.lock().unwrap().insert(item)
+}
+
+
+// queue an item we accepted for announcement to all peers, once
+
+pub fn announce(item: InventoryItem) {
+
+    mark_seen(item);
+
+    // before the relay is started there is nobody to tell
+
+    if let Some(queue) = QUEUE.get() {
+
+        let _ = queue.send(item);
+    }
+}
+
+
+// start the task that announces queued items to the peers
+
+pub fn start() {
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    if QUEUE.set(sender).is_ok() {
+
+        tokio::spawn(run(receiver));
+    }
+}
+
+
+async fn run(mut receiver: UnboundedReceiver<InventoryItem>) {
+
+    while let Some(item) = receiver.recv().await {
+
+        // everything that piled up in the meantime goes out in the same Inventory
+
+        let mut items = vec![item];
+
+        while items.len() < MAX_INVENTORY {
+
+            match receiver.try_recv() {
+
+                Ok(item) => items.push(item),
+
+                Err(_) => break,
+            }
+        }
+
+        // only to nodes that asked for relayed items in the handshake
+
+        let peers = crate::NODES.iter()
+            .filter(|x| x.value().version.supports(&Message::Inventory(vec![])))
+            .map(|x| (x.key().clone(), x.value().clone()))
+            .collect::<Vec<_>>();
+
+        for (name, peer) in peers {
+
+            if let Err(e) = send_inventory(&peer, &items).await {
+
+                println!("dropping {}: {e}", name);
+
+                crate::bans::misbehaved(&name, crate::bans::error_penalty(&e), &e.to_string());
+
+                crate::util::disconnect(&name);
+            }
+        }
+    }
+}
+
+
+// announce the items and send the ones the peer asks for
+
+async fn send_inventory(peer: &Peer, items: &[InventoryItem]) -> Result<()> {
+
+    let wanted = match peer.connection.request(Message::Inventory(items.to_vec())).await? {
+
+        Message::GetData(wanted) => wanted,
+
+        _ => bail!("unexpected answer to Inventory"),
+    };
+
+    for item in wanted {
+
+        if !items.contains(&item) {
+
+            bail!("asked for {:?}, which was not announced", item);
+        }
+
+        let message = {
+
+            let blockchain = crate::BLOCKCHAIN.read().await;
+
+            match item {
+
+                InventoryItem::Transaction(txid) => blockchain.mempool_transaction(&txid)
+                    .map(|transaction| Message::NewTransaction(transaction.clone())),
+
+                InventoryItem::Block(hash) => blockchain.block_by_hash(&hash)
+                    .map_err(|e| anyhow!("failed to read block {hash}: {e}"))?
+                    .map(Message::NewBlock),
+            }
+        };
+
+        // the transaction may have been mined or evicted since it was announced, then there is nothing to send
+
+        if let Some(message) = message {
+
+            peer.connection.notify(&message).await?;
+        }
+    }
+
+    Ok(())
+}
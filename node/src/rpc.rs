@@ -0,0 +1,971 @@
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+use btc_lib::crypto::PublicKey;
+use btc_lib::network::InventoryItem;
+use btc_lib::sha256::Hash;
+use btc_lib::types::{Block, BlockHeader, Blockchain, Transaction};
+use btc_lib::util::Saveable;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+
+// a JSON-RPC server for scripts and dashboards.
+//
+// every request is an HTTP POST of a JSON-RPC 2.0 call, or a batch of them, and the connection is
+// closed after the answer. requests need basic auth, with the user and password from the command
+// line or, without those, the user __cookie__ and the random password that is written to the cookie
+// file in the data directory at startup. tools on the same machine read it from there
+
+const COOKIE_FILE: &str = ".cookie";
+
+const COOKIE_USER: &str = "__cookie__";
+
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+// a transaction at the size limit of the protocol, in hex, with room to spare
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+// for reading the request and writing the answer, slow clients do not keep a task around forever
+const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+// error codes of JSON-RPC, and the ones bitcoind uses for the same problems
+
+const PARSE_ERROR: i64 = -32700;
+
+const INVALID_REQUEST: i64 = -32600;
+
+const METHOD_NOT_FOUND: i64 = -32601;
+
+const INVALID_PARAMS: i64 = -32602;
+
+const INTERNAL_ERROR: i64 = -32603;
+
+const NOT_FOUND: i64 = -5;
+
+const REJECTED: i64 = -26;
+
+// asked for something the node was not started to keep
+const NOT_ENABLED: i64 = -1;
+
+
+struct RpcError {
+
+    code: i64,
+
+    message: String,
+}
+
+
+impl RpcError {
+
+    fn new(code: i64, message: impl Into<String>) -> Self {
+
+        RpcError { code, message: message.into() }
+    }
+}
+
+
+impl From<btc_lib::error::BtcError> for RpcError {
+
+    fn from(error: btc_lib::error::BtcError) -> Self {
+
+        RpcError::new(INTERNAL_ERROR, error.to_string())
+    }
+}
+
+
+// the user:password every request has to carry. with neither given, a new password
+// is written to the cookie file
+
+pub fn credentials(user: Option<String>, password: Option<String>, data_dir: &str) -> Result<String> {
+
+    match (user, password) {
+
+        (Some(user), Some(password)) => Ok(format!("{user}:{password}")),
+
+        (None, None) => {
+
+            let credentials = format!("{COOKIE_USER}:{}", hex::encode(rand::random::<[u8; 32]>()));
+
+            let path = Path::new(data_dir).join(COOKIE_FILE);
+
+            write_cookie(&path, &credentials)
+                .with_context(|| format!("failed to write the rpc cookie {}", path.display()))?;
+
+            Ok(credentials)
+        }
+
+        _ => bail!("--rpc-user and --rpc-password have to be given together"),
+    }
+}
+
+
+// only readable by the user running the node, the cookie is as good as the password
+
+fn write_cookie(path: &Path, credentials: &str) -> std::io::Result<()> {
+
+    let tmp_path = path.with_extension("tmp");
+
+    fs::write(&tmp_path, credentials)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+    }
+
+    fs::rename(&tmp_path, path)
+}
+
+
+pub async fn start(address: SocketAddr, credentials: String) -> Result<()> {
+
+    let listener = TcpListener::bind(address).await
+        .with_context(|| format!("failed to bind the rpc server to {address}"))?;
+
+    println!("rpc server listening on {}", address);
+
+    let credentials = Arc::new(credentials);
+
+    tokio::spawn(async move {
+
+        loop {
+
+            let socket = match listener.accept().await {
+
+                Ok((socket, _)) => socket,
+
+                Err(e) => {
+
+                    println!("rpc server failed to accept: {e}");
+
+                    continue;
+                }
+            };
+
+            let credentials = credentials.clone();
+
+            tokio::spawn(async move {
+
+                if time::timeout(RPC_TIMEOUT, handle(socket, &credentials)).await.is_err() {
+
+                    println!("rpc client timed out");
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+
+async fn handle(socket: TcpStream, credentials: &str) {
+
+    let mut reader = BufReader::new(socket);
+
+    let (status, reason, body) = match read_request(&mut reader, credentials).await {
+
+        Ok(body) => (200, "OK", answer(&body).await.to_string()),
+
+        Err((status, reason)) => (status, reason, String::new()),
+    };
+
+    let _ = respond(reader.get_mut(), status, reason, &body).await;
+}
+
+
+// the body of an authorized POST. the request line and the headers are checked first,
+// nothing of the body is read for a client that is not allowed to send one
+
+async fn read_request(reader: &mut BufReader<TcpStream>, credentials: &str) -> Result<Vec<u8>, (u16, &'static str)> {
+
+    const BAD_REQUEST: (u16, &str) = (400, "Bad Request");
+
+    let mut header_size = 0;
+
+    let mut lines = vec![];
+
+    loop {
+
+        let mut line = String::new();
+
+        let limit = (MAX_HEADER_SIZE - header_size) as u64;
+
+        let read = (&mut *reader).take(limit).read_line(&mut line).await.map_err(|_| BAD_REQUEST)?;
+
+        header_size += read;
+
+        if read == 0 || !line.ends_with('\n') {
+
+            return Err(if header_size >= MAX_HEADER_SIZE { (431, "Request Header Fields Too Large") } else { BAD_REQUEST });
+        }
+
+        let line = line.trim_end().to_owned();
+
+        if line.is_empty() {
+
+            break;
+        }
+
+        lines.push(line);
+    }
+
+    let mut lines = lines.into_iter();
+
+    let method = lines.next()
+        .and_then(|line| line.split_whitespace().next().map(str::to_owned))
+        .ok_or(BAD_REQUEST)?;
+
+    let mut content_length = 0;
+
+    let mut authorization = None;
+
+    let mut expect_continue = false;
+
+    for line in lines {
+
+        let Some((name, value)) = line.split_once(':') else {
+
+            return Err(BAD_REQUEST);
+        };
+
+        let value = value.trim();
+
+        match name.to_ascii_lowercase().as_str() {
+
+            "content-length" => content_length = value.parse().map_err(|_| BAD_REQUEST)?,
+
+            "authorization" => authorization = Some(value.to_owned()),
+
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+
+            _ => (),
+        }
+    }
+
+    if !authorized(authorization.as_deref(), credentials) {
+
+        return Err((401, "Unauthorized"));
+    }
+
+    if method != "POST" {
+
+        return Err((405, "Method Not Allowed"));
+    }
+
+    if content_length > MAX_BODY_SIZE {
+
+        return Err((413, "Payload Too Large"));
+    }
+
+    // curl waits for this before it sends a larger body
+
+    if expect_continue {
+
+        reader.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.map_err(|_| BAD_REQUEST)?;
+    }
+
+    let mut body = vec![0u8; content_length];
+
+    reader.read_exact(&mut body).await.map_err(|_| BAD_REQUEST)?;
+
+    Ok(body)
+}
+
+
+fn authorized(authorization: Option<&str>, credentials: &str) -> bool {
+
+    let Some(encoded) = authorization.and_then(|value| value.strip_prefix("Basic ")) else {
+
+        return false;
+    };
+
+    let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(encoded.trim()) else {
+
+        return false;
+    };
+
+    // compared in full every time, how long it takes says nothing about the password
+
+    decoded.len() == credentials.len()
+        && decoded.iter().zip(credentials.as_bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+
+async fn respond(socket: &mut TcpStream, status: u16, reason: &str, body: &str) -> std::io::Result<()> {
+
+    let mut response = format!("HTTP/1.1 {status} {reason}\r\n");
+
+    if status == 401 {
+
+        response.push_str("WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n");
+    }
+
+    response.push_str(&format!(
+        "Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    ));
+
+    socket.write_all(response.as_bytes()).await?;
+
+    socket.shutdown().await
+}
+
+
+// the answer to a single call or a batch
+
+async fn answer(body: &[u8]) -> Value {
+
+    let request = match serde_json::from_slice::<Value>(body) {
+
+        Ok(request) => request,
+
+        Err(e) => return reply(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+    };
+
+    match request {
+
+        Value::Array(calls) if !calls.is_empty() => {
+
+            let mut replies = vec![];
+
+            for call in calls {
+
+                replies.push(answer_call(call).await);
+            }
+
+            Value::Array(replies)
+        }
+
+        call => answer_call(call).await,
+    }
+}
+
+
+async fn answer_call(call: Value) -> Value {
+
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+
+    let Some(method) = call.get("method").and_then(Value::as_str) else {
+
+        return reply(id, Err(RpcError::new(INVALID_REQUEST, "no method")));
+    };
+
+    let params = call.get("params").cloned().unwrap_or(Value::Array(vec![]));
+
+    reply(id, dispatch(method, &params).await)
+}
+
+
+fn reply(id: Value, result: Result<Value, RpcError>) -> Value {
+
+    match result {
+
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+
+        Err(e) => json!({ "jsonrpc": "2.0", "error": { "code": e.code, "message": e.message }, "id": id }),
+    }
+}
+
+
+async fn dispatch(method: &str, params: &Value) -> Result<Value, RpcError> {
+
+    match method {
+
+        "getblockcount" => get_block_count().await,
+
+        "getbestblockhash" => get_best_block_hash().await,
+
+        "getblockhash" => get_block_hash(params).await,
+
+        "getblock" => get_block(params).await,
+
+        "getblockheader" => get_block_header(params).await,
+
+        "gettransaction" => get_transaction(params).await,
+
+        "getmempoolinfo" => get_mempool_info().await,
+
+        "getrawmempool" => get_raw_mempool(params).await,
+
+        "sendrawtransaction" => send_raw_transaction(params).await,
+
+        "getutxos" => get_utxos(params).await,
+
+        "gethistory" => get_history(params).await,
+
+        "getpeerinfo" => get_peer_info(),
+
+        "getmininginfo" => get_mining_info().await,
+
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("no method {method}"))),
+    }
+}
+
+
+// parameters can be given by position or by name
+
+fn param<'a>(params: &'a Value, index: usize, name: &str) -> Option<&'a Value> {
+
+    let value = match params {
+
+        Value::Array(values) => values.get(index),
+
+        Value::Object(values) => values.get(name),
+
+        _ => None,
+    };
+
+    value.filter(|value| !value.is_null())
+}
+
+
+fn required<'a>(params: &'a Value, index: usize, name: &str) -> Result<&'a Value, RpcError> {
+
+    param(params, index, name).ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing {name}")))
+}
+
+
+fn hash_param(params: &Value, index: usize, name: &str) -> Result<Hash, RpcError> {
+
+    required(params, index, name)?
+        .as_str()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{name} has to be a hex string")))?
+        .parse()
+        .map_err(|e: String| RpcError::new(INVALID_PARAMS, e))
+}
+
+
+fn bool_param(params: &Value, index: usize, name: &str, default: bool) -> Result<bool, RpcError> {
+
+    match param(params, index, name) {
+
+        None => Ok(default),
+
+        // bitcoind takes 0 and 1 for these as well
+
+        Some(value) => value.as_bool()
+            .or_else(|| value.as_u64().map(|value| value != 0))
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{name} has to be a boolean"))),
+    }
+}
+
+
+fn bytes_param(params: &Value, index: usize, name: &str) -> Result<Vec<u8>, RpcError> {
+
+    required(params, index, name)?
+        .as_str()
+        .and_then(|value| hex::decode(value).ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{name} has to be a hex string")))
+}
+
+
+// a public key in SEC1 hex, compressed or not
+
+fn pubkey_param(params: &Value, index: usize) -> Result<PublicKey, RpcError> {
+
+    PublicKey::from_sec1_bytes(&bytes_param(params, index, "pubkey")?)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "pubkey is not a public key"))
+}
+
+
+fn encode(value: &impl Saveable) -> Result<String, RpcError> {
+
+    let mut bytes = vec![];
+
+    value.save(&mut bytes).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+
+    Ok(hex::encode(bytes))
+}
+
+
+// the height of the tip, the genesis block is at 0
+
+fn tip_height(blockchain: &Blockchain) -> u64 {
+
+    blockchain.blocks_height().saturating_sub(1)
+}
+
+
+fn header_json(blockchain: &Blockchain, header: &BlockHeader) -> Value {
+
+    let hash = header.hash();
+
+    // blocks on side branches have no height, like bitcoind they have -1 confirmations
+
+    let height = blockchain.block_height(&hash);
+
+    let confirmations = height.map_or(-1, |height| (tip_height(blockchain) - height as u64 + 1) as i64);
+
+    // the genesis block has none
+
+    let previous = (header.prev_block_hash != Hash::zero()).then(|| header.prev_block_hash.to_string());
+
+    json!({
+        "hash": hash.to_string(),
+        "height": height,
+        "confirmations": confirmations,
+        "time": header.timestamp.timestamp(),
+        "nonce": header.nonce,
+        "target": format!("{:x}", header.target),
+        "merkleroot": header.merkle_root.as_hash().to_string(),
+        "previousblockhash": previous,
+    })
+}
+
+
+fn transaction_json(transaction: &Transaction) -> Value {
+
+    let inputs = transaction.inputs.iter()
+        .map(|input| json!({
+            "txid": input.prev_output.txid.to_string(),
+            "vout": input.prev_output.vout,
+        }))
+        .collect::<Vec<_>>();
+
+    let outputs = transaction.outputs.iter()
+        .map(|output| json!({
+            "value": output.value,
+            "pubkey": hex::encode(output.pubkey.to_sec1_bytes()),
+            "unique_id": output.unique_id.to_string(),
+        }))
+        .collect::<Vec<_>>();
+
+    json!({
+        "txid": transaction.hash().to_string(),
+        "inputs": inputs,
+        "outputs": outputs,
+    })
+}
+
+
+async fn get_block_count() -> Result<Value, RpcError> {
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    Ok(json!(tip_height(&blockchain)))
+}
+
+
+async fn get_best_block_hash() -> Result<Value, RpcError> {
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    Ok(json!(blockchain.tip_hash().to_string()))
+}
+
+
+async fn get_block_hash(params: &Value) -> Result<Value, RpcError> {
+
+    let height = required(params, 0, "height")?
+        .as_u64()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "height has to be a number"))?;
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    let header = blockchain.headers()
+        .nth(height as usize)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("no block at height {height}")))?;
+
+    Ok(json!(header.hash().to_string()))
+}
+
+
+// verbosity 0 is the block as hex, 1 adds the txids to the header, 2 the whole transactions
+
+async fn get_block(params: &Value) -> Result<Value, RpcError> {
+
+    let hash = hash_param(params, 0, "blockhash")?;
+
+    let verbosity = match param(params, 1, "verbosity") {
+
+        None => 1,
+
+        Some(value) => value.as_u64()
+            .or_else(|| value.as_bool().map(u64::from))
+            .filter(|verbosity| *verbosity <= 2)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "verbosity has to be 0, 1 or 2"))?,
+    };
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    let block: Block = blockchain.block_by_hash(&hash)?
+        .ok_or_else(|| RpcError::new(NOT_FOUND, format!("no block {hash}")))?;
+
+    if verbosity == 0 {
+
+        return Ok(json!(encode(&block)?));
+    }
+
+    let transactions = block.transactions.iter()
+        .map(|transaction| match verbosity {
+
+            1 => json!(transaction.hash().to_string()),
+
+            _ => transaction_json(transaction),
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = header_json(&blockchain, &block.header);
+
+    result["tx"] = Value::Array(transactions);
+
+    Ok(result)
+}
+
+
+// verbose false is the header as hex, in the layout that is hashed
+
+async fn get_block_header(params: &Value) -> Result<Value, RpcError> {
+
+    let hash = hash_param(params, 0, "blockhash")?;
+
+    let verbose = bool_param(params, 1, "verbose", true)?;
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    let header = blockchain.header(&hash)
+        .ok_or_else(|| RpcError::new(NOT_FOUND, format!("no block {hash}")))?;
+
+    if !verbose {
+
+        return Ok(json!(hex::encode(header.to_bytes())));
+    }
+
+    Ok(header_json(&blockchain, header))
+}
+
+
+// a transaction from the mempool or the active chain. found through the transaction index
+// when the node keeps one, otherwise the chain is searched from the tip, block by block
+
+async fn get_transaction(params: &Value) -> Result<Value, RpcError> {
+
+    let txid = hash_param(params, 0, "txid")?;
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    if let Some(transaction) = blockchain.mempool_transaction(&txid) {
+
+        let mut result = transaction_json(transaction);
+
+        result["hex"] = json!(encode(transaction)?);
+
+        result["confirmations"] = json!(0);
+
+        return Ok(result);
+    }
+
+    let (header, transaction) = blockchain.find_transaction(&txid)?
+        .ok_or_else(|| RpcError::new(NOT_FOUND, format!("no transaction {txid} in the mempool or the active chain")))?;
+
+    let block = header_json(&blockchain, &header);
+
+    let mut result = transaction_json(&transaction);
+
+    result["hex"] = json!(encode(&transaction)?);
+
+    result["blockhash"] = block["hash"].clone();
+
+    result["confirmations"] = block["confirmations"].clone();
+
+    Ok(result)
+}
+
+
+async fn get_mempool_info() -> Result<Value, RpcError> {
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    let mempool = blockchain.mempool();
+
+    let bytes = mempool.iter().map(|entry| entry.size).sum::<usize>();
+
+    // fee rates in satoshis per 1000 bytes
+
+    Ok(json!({
+        "size": mempool.len(),
+        "bytes": bytes,
+        "usage": mempool.usage(),
+        "maxmempool": mempool.max_usage(),
+        "mempoolminfee": mempool.min_fee_rate(),
+        "minrelaytxfee": mempool.min_relay_fee(),
+        "maxage": blockchain.params().max_mempool_transaction_age,
+    }))
+}
+
+
+// the txids, highest fee rate first, or with verbose set the time, size and fee of every transaction
+
+async fn get_raw_mempool(params: &Value) -> Result<Value, RpcError> {
+
+    let verbose = bool_param(params, 0, "verbose", false)?;
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    if !verbose {
+
+        let txids = blockchain.mempool().iter()
+            .map(|entry| json!(entry.txid.to_string()))
+            .collect::<Vec<_>>();
+
+        return Ok(Value::Array(txids));
+    }
+
+    let mut transactions = Map::new();
+
+    for entry in blockchain.mempool().iter() {
+
+        transactions.insert(entry.txid.to_string(), json!({
+            "time": entry.time.timestamp(),
+            "size": entry.size,
+            "fee": entry.fee,
+            "feerate": entry.fee_rate,
+            "bip125-replaceable": blockchain.mempool().replaceable(&entry.txid),
+        }));
+    }
+
+    Ok(Value::Object(transactions))
+}
+
+
+// a transaction encoded like in the wallet's files, as hex. it is announced to the peers like a submitted one
+
+async fn send_raw_transaction(params: &Value) -> Result<Value, RpcError> {
+
+    let bytes = bytes_param(params, 0, "hexstring")?;
+
+    let transaction = Transaction::load(bytes.as_slice())
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("not a transaction: {e}")))?;
+
+    let txid = transaction.hash();
+
+    {
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+
+        blockchain.add_to_mempool(transaction)
+            .map_err(|e| RpcError::new(REJECTED, format!("transaction rejected: {e}")))?;
+
+        crate::events::transaction_added(&blockchain, txid);
+    }
+
+    println!("added transaction {txid} from rpc to mempool, announcing");
+
+    crate::relay::announce(InventoryItem::Transaction(txid));
+
+    Ok(json!(txid.to_string()))
+}
+
+
+// the unspent outputs of a public key, given as SEC1 hex. reserved ones are spent by a mempool transaction,
+// unconfirmed ones belong to one
+
+async fn get_utxos(params: &Value) -> Result<Value, RpcError> {
+
+    let pubkey = pubkey_param(params, 0)?;
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    let utxos = blockchain.utxos_of(&pubkey).into_iter()
+        .map(|(outpoint, output, reserved)| json!({
+            "txid": outpoint.txid.to_string(),
+            "vout": outpoint.vout,
+            "value": output.value,
+            "reserved": reserved,
+            "confirmed": blockchain.utxos().contains_key(&outpoint),
+        }))
+        .collect::<Vec<_>>();
+
+    Ok(Value::Array(utxos))
+}
+
+
+// everything paid to and spent from a public key, oldest first
+
+async fn get_history(params: &Value) -> Result<Value, RpcError> {
+
+    let pubkey = pubkey_param(params, 0)?;
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    let history = blockchain.history(&pubkey)
+        .ok_or_else(|| RpcError::new(NOT_ENABLED, "no index, start the node with --index"))?;
+
+    let entries = history.iter()
+        .map(|entry| json!({
+            "txid": entry.txid.to_string(),
+            "blockhash": entry.block.to_string(),
+            "height": entry.height,
+            "outpoint": {
+                "txid": entry.outpoint.txid.to_string(),
+                "vout": entry.outpoint.vout,
+            },
+            "value": entry.value,
+            "spent": entry.spent,
+        }))
+        .collect::<Vec<_>>();
+
+    Ok(Value::Array(entries))
+}
+
+
+fn get_peer_info() -> Result<Value, RpcError> {
+
+    let peers = crate::NODES.iter()
+        .map(|x| {
+
+            let peer = x.value();
+
+            json!({
+                "name": x.key(),
+                "address": peer.address,
+                "inbound": peer.inbound,
+                "version": peer.version.version,
+                "user_agent": peer.version.user_agent,
+                "services": peer.version.services.names(),
+                "best_height": peer.version.best_height,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Value::Array(peers))
+}
+
+
+async fn get_mining_info() -> Result<Value, RpcError> {
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    Ok(json!({
+        "network": blockchain.params().network.to_string(),
+        "blocks": tip_height(&blockchain),
+        "target": format!("{:x}", blockchain.target()),
+        "reward": blockchain.calculate_block_reward(),
+        "mempool": blockchain.mempool().len(),
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use uuid::Uuid;
+
+
+    fn basic(credentials: &str) -> String {
+
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+    }
+
+
+    // what read_request makes of what a client sent, the client stays connected throughout
+
+    async fn request(sent: &[u8], credentials: &str) -> Result<Vec<u8>, (u16, &'static str)> {
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+        client.write_all(sent).await.unwrap();
+
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let mut reader = BufReader::new(socket);
+
+        time::timeout(Duration::from_secs(5), read_request(&mut reader, credentials)).await
+            .expect("read_request waited for more than was sent")
+    }
+
+
+    #[test]
+    fn basic_auth() {
+
+        assert!(authorized(Some(&basic("user:password")), "user:password"));
+
+        assert!(!authorized(Some(&basic("user:passwore")), "user:password"));
+
+        assert!(!authorized(Some(&basic("user:password2")), "user:password"));
+
+        assert!(!authorized(Some("Bearer dXNlcjpwYXNzd29yZA=="), "user:password"));
+
+        assert!(!authorized(Some("Basic not base64"), "user:password"));
+
+        assert!(!authorized(None, "user:password"));
+    }
+
+
+    #[tokio::test]
+    async fn unauthorized_before_the_body() {
+
+        // the body that was announced never comes, the answer does not wait for it
+
+        let head = format!("POST / HTTP/1.1\r\nAuthorization: {}\r\nContent-Length: 1000\r\n\r\n", basic("user:wrong"));
+
+        assert_eq!(request(head.as_bytes(), "user:password").await, Err((401, "Unauthorized")));
+
+        let head = "POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\n";
+
+        assert_eq!(request(head.as_bytes(), "user:password").await, Err((401, "Unauthorized")));
+
+        // nor is the size of a body checked before the client is known
+
+        let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+
+        assert_eq!(request(head.as_bytes(), "user:password").await, Err((401, "Unauthorized")));
+
+        let head = format!("POST / HTTP/1.1\r\nauthorization: {}\r\nContent-Length: 2\r\n\r\n{{}}", basic("user:password"));
+
+        assert_eq!(request(head.as_bytes(), "user:password").await, Ok(b"{}".to_vec()));
+    }
+
+
+    #[test]
+    fn cookie_credentials() {
+
+        let dir = std::env::temp_dir().join(format!("rpc-{}", Uuid::new_v4()));
+
+        fs::create_dir_all(&dir).unwrap();
+
+        let data_dir = dir.to_str().unwrap();
+
+        assert_eq!(credentials(Some("user".into()), Some("password".into()), data_dir).unwrap(), "user:password");
+
+        assert!(credentials(Some("user".into()), None, data_dir).is_err());
+
+        assert!(!dir.join(COOKIE_FILE).exists());
+
+        // without a user and password a new random one is written for tools on this machine
+
+        let cookie = credentials(None, None, data_dir).unwrap();
+
+        assert!(cookie.starts_with(&format!("{COOKIE_USER}:")));
+
+        assert_eq!(fs::read_to_string(dir.join(COOKIE_FILE)).unwrap(), cookie);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            assert_eq!(fs::metadata(dir.join(COOKIE_FILE)).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let next = credentials(None, None, data_dir).unwrap();
+
+        assert_ne!(next, cookie);
+
+        assert_eq!(fs::read_to_string(dir.join(COOKIE_FILE)).unwrap(), next);
+
+        assert!(authorized(Some(&basic(&next)), &next));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,494 @@
+use anyhow::{anyhow, bail, Result};
+
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration};
+use btc_lib::connection::Connection;
+use btc_lib::error::BtcError;
+use btc_lib::network::{Message, Services, MAX_HEADERS};
+use btc_lib::sha256::Hash;
+use btc_lib::types::{Block, BlockHeader};
+use btc_lib::util::MerkleRoot;
+use btc_lib::U256;
+use crate::Peer;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+
+// headers-first initial block download.
+//
+// every peer is asked for its header chain first, which is checked (links, proof of work, timestamps)
+// before a single block is downloaded. the blocks of the chain with the most work are then fetched
+// by hash from all peers at once, from the peer that sent the headers whenever it is free. a peer that
+// times out or sends something that does not match the headers is dropped, and the block is asked from
+// another peer. a peer that does not have a block is fine, it is only not asked for that block again
+
+// after the initial sync it runs again every so often, in case announcements were missed, and against single
+// peers: one that sent a block whose parent we lack, and every new outbound peer. one sync runs at a time
+
+// how often asking for a block may fail before the sync gives up
+const MAX_ATTEMPTS: usize = 3;
+
+// how often the headers of a peer may fail to connect before it is left out of the sync
+const MAX_RESTARTS: usize = 3;
+
+// how far downloads may run ahead of the block that is connected next
+const DOWNLOAD_WINDOW: usize = 64;
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+
+// held while a sync runs
+static SYNCING: Mutex<()> = Mutex::const_new(());
+
+
+// sync again with all peers every SYNC_INTERVAL
+
+pub fn start() {
+
+    tokio::spawn(async {
+
+        let mut interval = time::interval_at(time::Instant::now() + SYNC_INTERVAL, SYNC_INTERVAL);
+
+        loop {
+
+            interval.tick().await;
+
+            if let Err(e) = sync_from_peers().await {
+
+                println!("sync failed: {e}");
+            }
+        }
+    });
+}
+
+
+// sync with one peer in the background, unless a sync is running already
+
+pub fn sync_from_peer(name: &str) {
+
+    let name = name.to_owned();
+
+    tokio::spawn(async move {
+
+        let Ok(_syncing) = SYNCING.try_lock() else {
+
+            return;
+        };
+
+        println!("syncing with {name}");
+
+        if let Err(e) = sync(Some(&name)).await {
+
+            println!("sync with {name} failed: {e}");
+        }
+    });
+}
+
+
+pub async fn sync_from_peers() -> Result<()> {
+
+    let _syncing = SYNCING.lock().await;
+
+    sync(None).await
+}
+
+
+// sync with all peers, or only the named one
+
+async fn sync(only: Option<&str>) -> Result<()> {
+
+    // only peers that announced both headers and blocks in the handshake take part
+
+    let peers = crate::NODES.iter()
+        .map(|x| (x.key().clone(), x.value().clone()))
+        .filter(|(name, _)| only.is_none_or(|only| name == only))
+        .filter(|(_, peer)| peer.version.services.contains(Services::HEADERS | Services::BLOCKS))
+        .collect::<Vec<_>>();
+
+    let names = peers.iter()
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+
+    let (best_headers, mut peers) = fetch_best_headers(peers).await;
+
+    let result = match best_headers {
+
+        Some((source, headers)) => download_blocks(&headers, &source, &mut peers).await,
+
+        None => {
+
+            println!("no peer knows a chain with more work than ours");
+
+            Ok(())
+        }
+    };
+
+    // peers that did not behave are dropped from the node pool
+
+    for name in names {
+
+        if !peers.iter().any(|(good, _)| *good == name) {
+
+            crate::util::disconnect(&name);
+        }
+    }
+
+    result
+}
+
+
+// ask every peer for its header chain, and keep the valid one with the most work and the peer that sent it,
+// if it has more work than our own chain
+
+async fn fetch_best_headers(peers: Vec<(String, Peer)>) -> (Option<(String, Vec<BlockHeader>)>, VecDeque<(String, Peer)>) {
+
+    let mut tasks = JoinSet::new();
+
+    for (name, peer) in peers {
+
+        tasks.spawn(async move {
+
+            let result = fetch_headers(&peer.connection).await;
+
+            (name, peer, result)
+        });
+    }
+
+    let our_work = {
+
+        let blockchain = crate::BLOCKCHAIN.read().await;
+
+        blockchain.chain_work(&blockchain.tip_hash()).unwrap_or(U256::zero())
+    };
+
+    let mut best: Option<(U256, String, Vec<BlockHeader>)> = None;
+
+    let mut good_peers = VecDeque::new();
+
+    while let Some(joined) = tasks.join_next().await {
+
+        let (name, peer, result) = joined.expect("bug: header task panicked");
+
+        match result {
+
+            Ok((work, headers)) => {
+
+                println!("{} sent {} headers", name, headers.len());
+
+                let best_work = best.as_ref().map(|(work, _, _)| *work).unwrap_or(our_work);
+
+                if work > best_work {
+
+                    best = Some((work, name.clone(), headers));
+                }
+
+                good_peers.push_back((name, peer));
+            }
+
+            Err(e) => {
+
+                println!("dropping {}: {e}", name);
+
+                crate::bans::misbehaved(&name, crate::bans::error_penalty(&e), &e.to_string());
+            }
+        }
+    }
+
+    (best.map(|(_, name, headers)| (name, headers)), good_peers)
+}
+
+
+// fetch and check the header chain of one peer.
+// returns its headers from where it forks off our chain, and the total work of the chain they end in
+
+async fn fetch_headers(connection: &Connection) -> Result<(U256, Vec<BlockHeader>)> {
+
+    let mut headers: Vec<BlockHeader> = vec![];
+
+    let mut restarts = 0;
+
+    loop {
+
+        let locator = crate::BLOCKCHAIN.read().await.block_locator_after(&headers);
+
+        let batch = match connection.request(Message::FetchHeaders(locator)).await? {
+
+            Message::Headers(batch) => batch,
+
+            _ => bail!("unexpected answer to FetchHeaders"),
+        };
+
+        if batch.len() > MAX_HEADERS {
+
+            bail!("sent {} headers, more than {}", batch.len(), MAX_HEADERS);
+        }
+
+        let more = batch.len() == MAX_HEADERS;
+
+        let blockchain = crate::BLOCKCHAIN.read().await;
+
+        // the peer continues after the newest locator hash on its active chain. that is an earlier
+        // header than our last one if it switched branches meanwhile, the headers after it are dropped
+
+        if let Some(first) = batch.first() {
+
+            match headers.iter().rposition(|header| header.hash() == first.prev_block_hash) {
+
+                Some(index) => headers.truncate(index + 1),
+
+                None if blockchain.header(&first.prev_block_hash).is_some() => headers.clear(),
+
+                // builds on nothing we know, our chain may have moved meanwhile. start over from it
+
+                None => {
+
+                    restarts += 1;
+
+                    if restarts > MAX_RESTARTS {
+
+                        println!("headers keep not connecting, giving up on this peer for now");
+
+                        return Ok((U256::zero(), vec![]));
+                    }
+
+                    headers.clear();
+
+                    continue;
+                }
+            }
+        }
+
+        for header in batch {
+
+            // every header but the first builds on the header before it
+
+            blockchain.check_header(&headers, &header)?;
+
+            headers.push(header);
+        }
+
+        if headers.is_empty() {
+
+            // nothing we do not have already
+            return Ok((U256::zero(), headers));
+        }
+
+        if !more {
+
+            let work = blockchain.chain_work(&headers[0].prev_block_hash).expect("bug: fork point not stored")
+                + headers.iter().fold(U256::zero(), |total, header| total + header.work());
+
+            return Ok((work, headers));
+        }
+    }
+}
+
+
+// a block still to be downloaded
+
+struct Download {
+
+    // into the wanted blocks
+    index: usize,
+
+    // requests that timed out or were answered wrongly
+    failures: usize,
+
+    // peers that answered they do not have it
+    missing: HashSet<String>,
+}
+
+
+// download the blocks of a checked header chain from all peers and connect them in order.
+// source is the peer the headers came from, it has all of them unless it switched branches since
+
+async fn download_blocks(headers: &[BlockHeader], source: &str, peers: &mut VecDeque<(String, Peer)>) -> Result<()> {
+
+    let wanted = {
+
+        let blockchain = crate::BLOCKCHAIN.read().await;
+
+        headers.iter()
+            .map(|header| header.hash())
+            .filter(|hash| !blockchain.contains_block(hash))
+            .collect::<Vec<_>>()
+    };
+
+    println!("downloading {} blocks from {} peers", wanted.len(), peers.len());
+
+    let mut queue: VecDeque<Download> = (0..wanted.len())
+        .map(|index| Download { index, failures: 0, missing: HashSet::new() })
+        .collect();
+
+    // the blocks that arrived out of order, with the peer that sent them
+
+    let mut downloaded: BTreeMap<usize, (String, Block)> = BTreeMap::new();
+
+    let mut next = 0;
+
+    let mut tasks = JoinSet::new();
+
+    let mut result = Ok(());
+
+    'download: while next < wanted.len() {
+
+        // hand out work to idle peers, without running too far ahead. the source first, then any
+        // peer that did not say already it does not have the block
+
+        while let Some(download) = queue.front() {
+
+            if download.index >= next + DOWNLOAD_WINDOW {
+
+                break;
+            }
+
+            let position = peers.iter()
+                .position(|(name, _)| name == source && !download.missing.contains(name))
+                .or_else(|| peers.iter().position(|(name, _)| !download.missing.contains(name)));
+
+            let Some(position) = position else {
+
+                break;
+            };
+
+            let (name, peer) = peers.remove(position).unwrap();
+
+            let download = queue.pop_front().unwrap();
+
+            let hash = wanted[download.index];
+
+            tasks.spawn(async move {
+
+                let result = fetch_block(&peer.connection, hash).await;
+
+                (name, peer, download, result)
+            });
+        }
+
+        let Some(joined) = tasks.join_next().await else {
+
+            result = match queue.front() {
+
+                Some(download) if !download.missing.is_empty() => Err(anyhow!("no peer has block {}", wanted[download.index])),
+
+                _ => Err(anyhow!("no peers left to download from")),
+            };
+
+            break;
+        };
+
+        let (name, peer, mut download, fetched) = joined.expect("bug: download task panicked");
+
+        match fetched {
+
+            Ok(Some(block)) => {
+
+                downloaded.insert(download.index, (name.clone(), block));
+
+                peers.push_back((name, peer));
+            }
+
+            // the peer is fine, it is just on another branch
+
+            Ok(None) => {
+
+                download.missing.insert(name.clone());
+
+                peers.push_back((name, peer));
+
+                queue.push_front(download);
+            }
+
+            Err(e) => {
+
+                println!("dropping {}: {e}", name);
+
+                crate::bans::misbehaved(&name, crate::bans::error_penalty(&e), &e.to_string());
+
+                download.failures += 1;
+
+                if download.failures >= MAX_ATTEMPTS {
+
+                    result = Err(anyhow!("gave up on block {}", wanted[download.index]));
+
+                    break;
+                }
+
+                queue.push_front(download);
+            }
+        }
+
+        // connect everything that is next in line
+
+        while let Some((sender, block)) = downloaded.remove(&next) {
+
+            let mut blockchain = crate::BLOCKCHAIN.write().await;
+
+            let added = blockchain.add_block(block);
+
+            crate::events::chain_updated(&mut blockchain);
+
+            match added {
+
+                Ok(()) | Err(BtcError::DuplicateBlock) => {}
+
+                Err(e) => {
+
+                    crate::bans::misbehaved(&sender, crate::bans::block_penalty(&e), &format!("invalid block: {e}"));
+
+                    result = Err(anyhow!("block {} rejected: {e}", wanted[next]));
+
+                    break 'download;
+                }
+            }
+
+            next += 1;
+        }
+    }
+
+    // wait for the requests still in flight, so their peers can be kept
+
+    while let Some(joined) = tasks.join_next().await {
+
+        if let Ok((name, peer, _, Ok(_))) = joined {
+
+            peers.push_back((name, peer));
+        }
+    }
+
+    println!("connected {} of {} blocks", next, wanted.len());
+
+    result
+}
+
+
+// fetch a block by hash and make sure it is the block the header promised.
+// None if the peer does not have it
+
+async fn fetch_block(connection: &Connection, hash: Hash) -> Result<Option<Block>> {
+
+    let block = match connection.request(Message::FetchBlockByHash(hash)).await? {
+
+        Message::NewBlock(block) => block,
+
+        Message::BlockNotFound(_) => return Ok(None),
+
+        _ => bail!("unexpected answer to FetchBlockByHash"),
+    };
+
+    if block.hash() != hash {
+
+        bail!("sent block {} instead of {}", block.hash(), hash);
+    }
+
+    // the header matches, the transactions have to match its merkle root as well
+
+    let (merkle_root, mutated) = MerkleRoot::calculate_checked(&block.transactions);
+
+    if merkle_root != block.header.merkle_root || mutated {
+
+        bail!("sent block {} with transactions that do not match its header", hash);
+    }
+
+    Ok(Some(block))
+}
@@ -1,41 +1,46 @@
 
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
 use tokio::net::TcpStream;
-use tokio::time;
-use btc_lib::network::Message;
+use tokio::time::{self, Duration};
+use btc_lib::connection::Connection;
+use btc_lib::network::{handshake_outbound, Services, Version};
+use btc_lib::params::ChainParams;
 use btc_lib::types::Blockchain;
-use btc_lib::util::Saveable;
+use crate::Peer;
+use std::sync::{Arc, OnceLock};
 
 
+pub const USER_AGENT: &str = concat!("node/", env!("CARGO_PKG_VERSION"));
 
-pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
+// how long dialing a node and the handshake may take, and the handshake of a node that dialed us
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
+static NONCE: OnceLock<u64> = OnceLock::new();
 
-    println!("blockchain file exists, loading...");
-    
-    let new_blockchain = Blockchain::load_from_file(blockchain_file)?;
+// the port we accept connections on, set once at startup
+pub static LISTEN_PORT: OnceLock<u16> = OnceLock::new();
 
-    println!("Blockchain loaded");
 
-    let mut blockchain = crate::BLOCKCHAIN.write().await;
 
-    *blockchain = new_blockchain;
+pub async fn load_blockchain(data_dir: &str, params: ChainParams) -> Result<()> {
 
-    println!("rebuilding utxos...");
 
-    blockchain.rebuild_utxos();
+    println!("opening blockchain in {}...", data_dir);
 
-    println!("utxos rebuilt");
+    // only the headers and the utxo set are read, and the stored chain has to belong to our network
 
-    println!("check if the target needs to be adjusted...");
+    let new_blockchain = Blockchain::open(params, data_dir)
+        .with_context(|| format!("failed to open the blockchain in {data_dir}"))?;
 
-    println!("current target :{}", blockchain.target());
+    println!("Blockchain loaded, height {}", new_blockchain.blocks_height());
 
-    blockchain.try_adjust_target();
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
 
-    println!("new target:{}", blockchain.target());
+    *blockchain = new_blockchain;
+
+    println!("current target :{}", blockchain.target());
 
     println!("initializing complete");
 
@@ -61,182 +66,70 @@ pub async fn cleanup() {
 
 
 
-pub async fn save(name: String) {
-
-    let mut interval =time::interval(time::Duration::from_secs(15));
-        
-        loop {
-
-            interval.tick().await;
-
-            println!("saving blockchain to drive...");
-
-            let blockchain = crate::BLOCKCHAIN.read().await;
-            
-            blockchain.save_to_file(name.clone()).unwrap();
-        }
-
-}
-
-
-pub async  fn download_blockchain(node: &str, count: u32) -> Result<()>{
-
-    let mut stream  = crate::NODES.get_mut(node).unwrap();
-
-    for i in 0..count as usize {
+// what we tell other nodes about ourselves
 
-        let message  = Message::FetchBlock(i);
+pub async fn our_version() -> Version {
 
-        message.send_async(&mut *stream).await?;
+    let blockchain = crate::BLOCKCHAIN.read().await;
 
-        let message = Message::receive_async(&mut *stream).await?;
+    let services = match blockchain.has_index() {
 
-        match message {
+        true => Services::NODE | Services::INDEX,
 
-            Message::NewBlock(block) => {
+        false => Services::NODE,
+    };
 
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-
-                blockchain.add_block(block)?;
-            }
-
-            _ =>  { 
-                
-                println!("unexpected message from {}", node);
-            }
-        }
-    }
+    let mut version = Version::new(blockchain.params(), USER_AGENT, blockchain.blocks_height(), services);
 
+    // the nonce has to stay the same for the whole process, the first random one is kept
 
+    version.nonce = *NONCE.get_or_init(|| version.nonce);
 
-   Ok(())
+    version.listen_port = LISTEN_PORT.get().copied();
 
+    version
 }
 
 
+// connect to a node and shake hands, refusing nodes on another chain or protocol version
 
+pub async fn connect_peer(address: &str) -> Result<Peer> {
 
+    let ours = our_version().await;
 
+    let (stream, version) = time::timeout(CONNECT_TIMEOUT, async {
 
+        let mut stream = TcpStream::connect(address).await?;
 
+        let version = handshake_outbound(&mut stream, &ours).await?;
 
+        anyhow::Ok((stream, version))
+    })
+    .await
+    .map_err(|_| anyhow!("timed out"))??;
 
-pub async fn find_longest_chain_node() -> Result<(String, u32)> {
-
-
-    println!("find nodes with highest blockchain length");
-
-    let mut longest_name = String::new();
-
-    let mut longest_count = 0;
-
-    let all_nodes = crate::NODES.iter()
-        .map( |x| x.key().clone() )
-        .collect::<Vec<_>>();
-
-    for node in all_nodes {
-
-        println!("asking {} for blockchain length", node);
-
-        let mut stream = crate::NODES.get_mut(&node).context("no node")?;
-
-        let message = Message::AskDifference(0);
-
-        message.send_async(&mut *stream).await.unwrap();
-
-        println!("sent AskDifference to {}", node);
-
-        let message =  Message::receive_async(&mut *stream).await?;
-
-        match message {
-
-            Message::Difference(count) => {
-
-                println!("received Difference from {}", node);
+    println!("connected to {} ({}, height {})", address, version.user_agent, version.best_height);
 
-                if count > longest_count {
+    crate::clock::add_sample(address, &version).await;
 
-                    println!("new longest blockchain:  {count} block from {node}");
+    let (connection, incoming) = Connection::new(stream, version.magic);
 
-                    longest_count = count;
-                    longest_name = node;
+    let connection = Arc::new(connection);
 
+    // the peer can send requests and announcements our way on this connection as well
 
-                }
-            }
-
-            e => {
-
-                println!("unexpeted message from {}: {:?}", node, e);
-            }
-        }
-
-
-    }
-
- 
-    Ok((longest_name, longest_count as u32))
-
-   
+    tokio::spawn(crate::handler::serve(address.to_owned(), connection.clone(), incoming, ours));
 
+    Ok(Peer { connection, version, inbound: false, address: Some(address.to_owned()) })
 }
 
 
-pub async  fn populate_connections (nodes: &[String],) -> Result<()>{
-
-    println!("trying to connect to other nodes...");
-
-    for node in nodes {
-
-        println!("connecting to {}", node);
-
-        let mut stream = TcpStream::connect(&node).await?;
-
-        let message = Message::DiscoverNodes;
-
-        message.send_async(&mut stream).await?;
-
-        println!("sent DiscoverNodes to {}", node);
-
-        let message = Message::receive_async(&mut stream).await?;
-
-        match message {
-
-            Message::NodeList(child_nodes) =>  {
-
-                println!("received NodeList from {}", node);
-
-                for child_node in child_nodes {
-
-                    println!("adding node {}", child_node);
-
-                    let new_stream = TcpStream::connect(&child_node).await?;
-
-                    crate::NODES.insert(child_node, new_stream);
-                }
-            }
-
-            _ => {
-
-                println!("unexpected message from {}", node);
-            }
-
-           
-
-
-        }
-
-        crate::NODES.insert(node.clone(), stream);
-
+// forget a peer and close its connection
 
+pub fn disconnect(name: &str) {
 
+    if let Some((_, peer)) = crate::NODES.remove(name) {
 
+        peer.connection.close();
     }
-
-    Ok(())
-
-
 }
-
-
-
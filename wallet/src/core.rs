@@ -9,20 +9,32 @@ use kanal::AsyncSender;
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpStream;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::sync::Arc;
 use btc_lib::crypto::{PrivateKey, PublicKey};
-use btc_lib::network::Message;
-use btc_lib::types::{Transaction, TransactionOutput};
+use btc_lib::connection::Connection;
+use btc_lib::network::{handshake_outbound, Message, Services, Version};
+use btc_lib::params::{ChainParams, Network};
+use btc_lib::sha256::Hash;
+use btc_lib::store::HistoryEntry;
+use btc_lib::types::{OutPoint, SigHashType, Transaction, TransactionInput, TransactionOutput, SEQUENCE_REPLACEABLE};
 use btc_lib::util::Saveable;
 
 
+const USER_AGENT: &str = concat!("wallet/", env!("CARGO_PKG_VERSION"));
+
+
+// an unspent output we own: (marked, outpoint, output)
+
+type OwnedUtxo = (bool, OutPoint, TransactionOutput);
+
+
 #[derive(Clone)]
 struct UtxoStore {
 
     my_keys: Vec<LoadedKey>,
 
-    utxos: Arc<SkipMap<PublicKey, Vec<(bool, TransactionOutput)>>>,
+    utxos: Arc<SkipMap<PublicKey, Vec<OwnedUtxo>>>,
 }
 
 
@@ -53,8 +65,14 @@ pub struct Core {
 
     pub config: Config,
 
+    // parameters of the network selected in the config
+    pub params: ChainParams,
+
     utxos: UtxoStore,
 
+    // what we sent this session by hash, so a transaction stuck on a low fee can be replaced
+    sent: Arc<SkipMap<Hash, Transaction>>,
+
     pub tx_sender: AsyncSender<Transaction>,
 }
 
@@ -65,12 +83,18 @@ impl Core {
 
         let (tx_sender, _) = kanal::bounded(10);
 
+        let params = ChainParams::for_network(config.network);
+
         Core {
 
             config,
 
+            params,
+
             utxos,
 
+            sent: Arc::new(SkipMap::new()),
+
             tx_sender: tx_sender.clone_async(),
         }
 
@@ -103,18 +127,34 @@ impl Core {
     }
 
 
-    pub async fn fetch_utxos(&self) -> Result<()> {
+    // connect to the default node and shake hands, the node refuses wallets for another chain
 
+    async fn connect(&self) -> Result<(Connection, Version)> {
 
         let mut stream = TcpStream::connect(&self.config.default_node).await?;
 
+        let version = Version::new(&self.params, USER_AGENT, 0, Services::NONE);
+
+        let node_version = handshake_outbound(&mut stream, &version).await?;
+
+        // only replies are expected from the node
+
+        let (connection, _) = Connection::new(stream, self.params.magic());
+
+        Ok((connection, node_version))
+    }
+
+
+    pub async fn fetch_utxos(&self) -> Result<()> {
+
+
+        let (connection, _) = self.connect().await?;
+
         for key in &self.utxos.my_keys {
 
             let message = Message::FetchUTXOs(key.public.clone());
 
-            message.send_async(&mut stream).await?;
-
-            if let Message::UTXOs(utxos) = Message::receive_async(&mut stream).await? {
+            if let Message::UTXOs(utxos) = connection.request(message).await? {
 
 
                 // replace the entire UTXO set for this key
@@ -124,7 +164,7 @@ impl Core {
                     key.public.clone(),
                     utxos
                         .into_iter()
-                        .map(|(output, marked)| (marked, output))
+                        .map(|(outpoint, output, marked)| (marked, outpoint, output))
                         .collect(),
                 );
 
@@ -140,31 +180,70 @@ impl Core {
     }
 
 
-    pub async  fn send_transaction(&self, tranaction: Transaction) -> Result<()> {
+    // hand the transaction to the node, an error if the node refused it and why
 
-        let mut stream  = TcpStream::connect(&self.config.default_node).await?;
+    pub async  fn send_transaction(&self, tranaction: Transaction) -> Result<Hash> {
 
-        let message = Message::SubmitTransaction(tranaction);
+        let (connection, _) = self.connect().await?;
 
-        message.send_async(&mut stream).await?;
+        let message = Message::SubmitTransaction(tranaction.clone());
 
-        Ok(())
+        match connection.request(message).await? {
+
+            Message::SubmitResult(result) => {
 
+                let txid = result?;
 
+                self.sent.insert(txid, tranaction);
+
+                Ok(txid)
+            }
+
+            _ => Err(anyhow::anyhow!("unexpected response from node")),
+        }
+    }
+
+
+    // what was paid to and spent from our keys, oldest first. only nodes that keep an index know
+
+    pub async fn fetch_history(&self) -> Result<Vec<HistoryEntry>> {
+
+        let (connection, node_version) = self.connect().await?;
+
+        if !node_version.services.contains(Services::INDEX) {
+
+            return Err(anyhow::anyhow!("the node keeps no index, it has to be started with --index"));
+        }
+
+        let mut history = vec![];
+
+        for key in &self.utxos.my_keys {
+
+            match connection.request(Message::FetchHistory(key.public.clone())).await? {
+
+                Message::History(entries) => history.extend(entries),
+
+                _ => return Err(anyhow::anyhow!("unexpected response from node")),
+            }
+        }
+
+        history.sort_by_key(|entry| entry.height);
+
+        Ok(history)
     }
 
 
     pub fn get_balance(&self) -> u64 {
 
 
-        /// First sum(): It computes the total value of UTXOs within a single entry.
-        /// Second sum(): It adds up the results of the first sum() across all the entries, giving you the total value of all UTXOs in the entire collection.
+        // First sum(): It computes the total value of UTXOs within a single entry.
+        // Second sum(): It adds up the results of the first sum() across all the entries, giving you the total value of all UTXOs in the entire collection.
 
         self.utxos.utxos.iter().
             map(|entry| {
 
                 entry.value().iter()
-                .map(|utxo| utxo.1.value)
+                .map(|utxo| utxo.2.value)
                 .sum::<u64>()
         }).sum()
 
@@ -180,7 +259,9 @@ impl Core {
 
         let total_amount = amount + fee;
 
-        let mut inputs = Vec::new();
+        // pick the utxos to spend first, the signatures can only be made once the outputs are known
+
+        let mut selected = Vec::new();
 
         let mut input_sum = 0;
 
@@ -189,7 +270,7 @@ impl Core {
             let pubkey = entry.key();
             let utxos = entry.value();
 
-            for (marked, utxo) in utxos.iter() {
+            for (marked, outpoint, utxo) in utxos.iter() {
 
                 if *marked {
 
@@ -201,20 +282,7 @@ impl Core {
                     break;
                 }
 
-                inputs.push(btc_lib::types::TransactionInput {
-
-                    prev_transaction_output_hash: utxo.hash(),
-                    
-                    signature: btc_lib::crypto::Signature::sign_output(
-                        &utxo.hash(), 
-                        
-                        &self.utxos.my_keys.iter()
-                            .find((|k| k.public == *pubkey))
-                            .unwrap()
-                            .private,
-                    
-                    )
-                });
+                selected.push((*outpoint, utxo.clone(), self.private_key(pubkey)));
 
                 input_sum  += utxo.value;
             }
@@ -252,13 +320,108 @@ impl Core {
 
              });
         }
-           
-           
-       Ok(Transaction::new(inputs, outputs))
 
+        Self::sign(&selected, outputs)
+    }
+
+
+    // a replacement for a transaction we sent that is still unconfirmed: the same inputs and payment,
+    // with the change lowered by extra_fee. the node only takes it if it pays enough more, see check_replacement
+
+    pub fn bump_fee(&self, txid: &Hash, extra_fee: u64) -> Result<Transaction> {
 
+        let stuck = self.sent.get(txid)
+            .ok_or_else(|| anyhow::anyhow!("no transaction {txid} was sent from this wallet"))?
+            .value()
+            .clone();
+
+        // the outputs it spends are still marked as spent by the mempool while it waits there
+
+        let mut selected = Vec::new();
+
+        for input in &stuck.inputs {
+
+            let owned = self.utxos.utxos.iter().find_map(|entry| {
+
+                entry.value().iter()
+                    .find(|(_, outpoint, _)| *outpoint == input.prev_output)
+                    .map(|(_, _, utxo)| (utxo.clone(), self.private_key(entry.key())))
+            });
 
+            let Some((utxo, private_key)) = owned else {
 
+                return Err(anyhow::anyhow!("the outputs {txid} spends are gone, it may be confirmed already"));
+            };
+
+            selected.push((input.prev_output, utxo, private_key));
+        }
+
+        // the change is the output after the payment that goes back to our first key
+
+        let mut outputs = stuck.outputs.clone();
+
+        let change = outputs.iter()
+            .skip(1)
+            .position(|output| output.pubkey == self.utxos.my_keys[0].public)
+            .map(|index| index + 1)
+            .ok_or_else(|| anyhow::anyhow!("{txid} has no change to pay a higher fee from"))?;
+
+        outputs[change].value = outputs[change].value.checked_sub(extra_fee)
+            .ok_or_else(|| anyhow::anyhow!("the change of {txid} is less than {extra_fee} satoshis"))?;
+
+        outputs[change].unique_id = uuid::Uuid::new_v4();
+
+        if outputs[change].value == 0 {
+
+            outputs.remove(change);
+        }
+
+        Self::sign(&selected, outputs)
+    }
+
+
+    fn private_key(&self, pubkey: &PublicKey) -> PrivateKey {
+
+        self.utxos.my_keys.iter()
+            .find(|k| k.public == *pubkey)
+            .unwrap()
+            .private
+            .clone()
+    }
+
+
+    // sign every input over all inputs and outputs. the inputs opt in to replacement,
+    // so a transaction stuck on a low fee can be sent again paying more, see bump_fee
+
+    fn sign(selected: &[(OutPoint, TransactionOutput, PrivateKey)], outputs: Vec<TransactionOutput>) -> Result<Transaction> {
+
+        let signed_inputs: Vec<(OutPoint, u32)> = selected.iter().map(|(outpoint, _, _)| (*outpoint, SEQUENCE_REPLACEABLE)).collect();
+
+        let mut inputs = Vec::new();
+
+        for (index, (outpoint, utxo, private_key)) in selected.iter().enumerate() {
+
+            let sighash = Transaction::compute_signature_hash(
+                &signed_inputs,
+                &outputs,
+                index,
+                utxo,
+                SigHashType::ALL,
+            )?;
+
+            inputs.push(TransactionInput {
+
+                prev_output: *outpoint,
+
+                sequence: SEQUENCE_REPLACEABLE,
+                
+                signature: btc_lib::crypto::Signature::sign_hash(&sighash, private_key),
+
+                sighash_type: SigHashType::ALL,
+            });
+        }
+           
+       Ok(Transaction::new(inputs, outputs))
     }
 
 
@@ -338,6 +501,7 @@ impl Recipient {
 
 pub struct LoadedRecipient {
 
+    #[allow(dead_code)]
     pub name: String,
     
     pub key: PublicKey,
@@ -373,6 +537,16 @@ pub struct  Config {
 
     pub default_node: String, 
 
-    pub fee_config: FeeConfig
+    pub fee_config: FeeConfig,
+
+    // configs written before networks existed were meant for testnet
+    #[serde(default = "default_network")]
+    pub network: Network,
+}
+
+
+fn default_network() -> Network {
+
+    Network::Testnet
 }
 
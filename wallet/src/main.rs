@@ -3,11 +3,12 @@ mod core;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use kanal;
 use tokio::time::{self, Duration};
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 use std::path::PathBuf;
+use btc_lib::params::ChainParams;
 use btc_lib::types::Transaction;
+use btc_lib::util::Saveable;
 use std::sync::Arc;
 use core::Config;
 use core::Core;
@@ -50,8 +51,11 @@ struct Cli {
     config: Option<PathBuf>,
 
     #[arg(short, long, value_name = "ADDRESS")]
-    node: Option<String>
+    node: Option<String>,
 
+    // file with the custom chain params of the node, overrides the network of the config
+    #[arg(long, value_name = "FILE")]
+    chain_params: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -91,9 +95,11 @@ async fn handle_transaction(rx: kanal::AsyncReceiver<Transaction>, core: Arc<Cor
 
     while let Ok(transaction) = rx.recv().await {
 
-        if let Err(e) = core.send_transaction(transaction).await {
+        match core.send_transaction(transaction).await {
 
-            eprintln!("failed to send transaction : {}", e);
+            Ok(txid) => println!("sent transaction {txid}"),
+
+            Err(e) => eprintln!("failed to send transaction : {}", e),
         }
     }
 
@@ -125,7 +131,7 @@ async fn run_cli(core: Arc<Core>) -> Result<()> {
 
         io::stdin().read_line(&mut input)?;
 
-        let parts: Vec<&str> = input.trim().split_whitespace().collect();
+        let parts: Vec<&str> = input.split_whitespace().collect();
 
         if parts.is_empty() {
 
@@ -182,6 +188,57 @@ async fn run_cli(core: Arc<Core>) -> Result<()> {
                 core.fetch_utxos().await?;
             }
 
+            // replace a transaction stuck on a low fee with one paying fee more
+
+            "bump" => {
+
+                if parts.len() != 3 {
+
+                    println!("Usage: bump <txid> <fee>");
+
+                    continue;
+                }
+
+                let Ok(txid) = parts[1].parse() else {
+
+                    println!("not a transaction hash: {}", parts[1]);
+
+                    continue;
+                };
+
+                let fee: u64 = parts[2].parse()?;
+
+                if let Err(e) = core.fetch_utxos().await {
+
+                    println!("failed to fetch utxos: {e}");
+                };
+
+                match core.bump_fee(&txid, fee) {
+
+                    Ok(transaction) => core.tx_sender.send(transaction).await?,
+
+                    Err(e) => println!("failed to bump fee: {e}"),
+                }
+            }
+
+            "history" => {
+
+                match core.fetch_history().await {
+
+                    Ok(history) => {
+
+                        for entry in history {
+
+                            let sign = if entry.spent { "-" } else { "+" };
+
+                            println!("{:>8} {} {sign}{} satoshis", entry.height, entry.txid, entry.value);
+                        }
+                    }
+
+                    Err(e) => println!("failed to fetch history: {e}"),
+                }
+            }
+
             "exit" => break,
 
             _  => {
@@ -227,7 +284,9 @@ fn generate_dummy_config(path: &PathBuf) -> Result<()> {
             fee_type: FeeType::Percent,
 
             value: 0.1,
-        }
+        },
+
+        network: btc_lib::params::Network::Testnet,
 
 
     };
@@ -271,11 +330,18 @@ async fn main() -> Result<()> {
 
     }
 
+    if let Some(path) = cli.chain_params {
+
+        core.params = ChainParams::load_from_file(path)?;
+    }
+
 
     let (tx_sender, tx_receiver) = kanal::bounded(10);
 
     core.tx_sender = tx_sender.clone_async();
 
+    println!("wallet running on {} via node {}", core.params.network, core.config.default_node);
+
     let core = Arc::new(core);
 
     tokio::spawn(update_utxos(core.clone()));